@@ -1,4 +1,32 @@
 #include "epd_handler.h"
+#include <atomic>
+
+static std::atomic<int64_t> displayed_frame{-1};
+static std::atomic<int64_t> displayed_etag{-1};
+
+int64_t epd_displayed_frame()
+{
+    return displayed_frame.load();
+}
+
+int64_t epd_displayed_etag()
+{
+    return displayed_etag.load();
+}
+
+// The panel now shows exactly what job carried
+static void mark_displayed(const EpdJob& job)
+{
+    displayed_frame = job.getFrameId();
+    displayed_etag = job.getEtag();
+}
+
+// The panel shows something igen never sent as a whole
+static void mark_unknown()
+{
+    displayed_frame = EPD_NO_FRAME;
+    displayed_etag = -1;
+}
 
 void EpdHandler::start_worker()
 {
@@ -26,12 +54,14 @@ void EpdHandler::start_worker()
                             EPD_7IN5_V2_Init();
                             EPD_7IN5_V2_Clear();
                             EPD_7IN5_V2_Sleep();
+                            mark_unknown();
 
                             break;
                         case EpdJobKind::ClearBlack:
                             EPD_7IN5_V2_Init();
                             EPD_7IN5_V2_ClearBlack();
                             EPD_7IN5_V2_Sleep();
+                            mark_unknown();
                             break;
                         case EpdJobKind::Display: {
                             EPD_7IN5_V2_Init();
@@ -45,6 +75,7 @@ void EpdHandler::start_worker()
                                 delay(200);
                                 printf("size match, sending to epd\r\n");
                                 EPD_7IN5_V2_Display(msg.getData());
+                                mark_displayed(msg);
 
                                 // prevent mem leak
                             }
@@ -63,13 +94,14 @@ void EpdHandler::start_worker()
 
                             printf("x: %llu, y: %llu, w: %llu, h: %llu\r\n", x,y,w,h);
                             // ensure size match
-                            if (msg.getSize() != ((w / 8) * h)) {
+                            if (msg.getSize() != ((w + 7) / 8) * h) {
                                 printf("size mismatch\r\n");
                             }
                             else {
                                 // This delay is mandatory (!) to prevent artifacting
                                 delay(400);
                                 EPD_7IN5_V2_Display_Part(msg.getData(), x, y, x + w, y + h);
+                                mark_displayed(msg);
                             }
                             // prevent mem leak
                             delay(100);
@@ -78,6 +110,77 @@ void EpdHandler::start_worker()
 
                             break;
                         }
+                        case EpdJobKind::DisplayMultiPartial: {
+                            EPD_7IN5_V2_Init_Part();
+                            const auto data = msg.getData();
+                            const auto size = msg.getSize();
+                            const auto read_u32 = [data](const size_t at) {
+                                return static_cast<uint32_t>(data[at]) |
+                                       (static_cast<uint32_t>(data[at + 1]) << 8) |
+                                       (static_cast<uint32_t>(data[at + 2]) << 16) |
+                                       (static_cast<uint32_t>(data[at + 3]) << 24);
+                            };
+
+                            const auto count = size >= 4 ? read_u32(0) : 0;
+                            bool complete = size >= 4;
+                            printf("display multi partial task, %u rects\r\n", count);
+                            size_t at = 4;
+                            for (uint32_t i = 0; i < count; i++) {
+                                if (at + 16 > size) {
+                                    printf("size mismatch\r\n");
+                                    complete = false;
+                                    break;
+                                }
+                                const auto x = read_u32(at);
+                                const auto y = read_u32(at + 4);
+                                const auto w = read_u32(at + 8);
+                                const auto h = read_u32(at + 12);
+                                at += 16;
+
+                                // Rows are padded to whole bytes, rects at the right edge may end mid-byte
+                                const size_t len = ((w + 7) / 8) * h;
+                                if (at + len > size) {
+                                    printf("size mismatch\r\n");
+                                    complete = false;
+                                    break;
+                                }
+                                printf("x: %u, y: %u, w: %u, h: %u\r\n", x, y, w, h);
+                                // See DisplayPartial, same artifacting issue
+                                delay(400);
+                                EPD_7IN5_V2_Display_Part(data + at, x, y, x + w, y + h);
+                                at += len;
+                            }
+                            // Only some of the rects made it, the panel matches no frame now
+                            if (complete) {
+                                mark_displayed(msg);
+                            }
+                            else {
+                                mark_unknown();
+                            }
+                            delay(100);
+                            delete msg.getData();
+                            EPD_7IN5_V2_Sleep();
+
+                            break;
+                        }
+                        case EpdJobKind::Display4Gray: {
+                            EPD_7IN5_V2_Init_4Gray();
+                            printf("display 4-gray task, buf: %p, len: %d\r\n", msg.getData(), msg.getSize());
+
+                            if (msg.getSize() != ((EPD_7IN5_V2_WIDTH / 4) * EPD_7IN5_V2_HEIGHT)) {
+                                printf("size mismatch\r\n");
+                            }
+                            else {
+                                // See Display
+                                delay(200);
+                                EPD_7IN5_V2_Display_4Gray(msg.getData());
+                                mark_displayed(msg);
+                            }
+                            delay(20);
+                            delete msg.getData();
+                            EPD_7IN5_V2_Sleep();
+                            break;
+                        }
                         default:
                         case EpdJobKind::Undefined:
                             printf("Undefined job kind, ignoring\r\n");
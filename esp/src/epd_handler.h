@@ -11,6 +11,10 @@ enum class EpdJobKind
     ClearBlack,
     Display,
     DisplayPartial,
+    // data is the raw MultiPartial payload, see igen/src/wire/mod.rs
+    DisplayMultiPartial,
+    // data is a 2bpp frame, see FullGray4 in igen/src/wire/mod.rs
+    Display4Gray,
 
     Undefined,
 };
@@ -22,6 +26,10 @@ private:
     uint8_t* data;
     size_t size;
     uint64_t aux[16];
+    // Frame-Id igen sent this job with, -1 if none
+    int64_t frameId = -1;
+    // ETag igen sent this job with (crc32 in hex), -1 if none
+    int64_t etag = -1;
 
 public:
     explicit EpdJob(const EpdJobKind kind) : kind(kind), data(nullptr), size(0), aux{0}
@@ -57,8 +65,37 @@ public:
         return aux[index];
     }
 
+    int64_t getFrameId() const
+    {
+        return frameId;
+    }
+
+    void setFrameId(const int64_t id)
+    {
+        frameId = id;
+    }
+
+    int64_t getEtag() const
+    {
+        return etag;
+    }
+
+    void setEtag(const int64_t tag)
+    {
+        etag = tag;
+    }
+
 };
 
+// What igen calls NO_FRAME, reported once the panel shows nothing it sent
+constexpr int64_t EPD_NO_FRAME = 0xFFFFFFFF;
+
+// Frame-Id of what is on the panel right now, -1 until anything was shown.
+// Reported back to igen so it diffs against what is actually displayed.
+int64_t epd_displayed_frame();
+// ETag of what is on the panel right now, -1 if unknown. Sent as If-None-Match.
+int64_t epd_displayed_etag();
+
 class EpdHandler
 {
     QueueHandle_t queue;
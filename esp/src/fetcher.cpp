@@ -1,4 +1,40 @@
 #include "fetcher.h"
+#include <new>
+#include <rom/crc.h>
+
+constexpr uint8_t FRAME_MAGIC[4] = {'O', 'W', 'O', 'V'};
+constexpr uint8_t FRAME_PROTOCOL_VERSION = 2;
+constexpr size_t FRAME_HEADER_LEN = 24;
+
+constexpr uint8_t FRAME_ENCODING_RAW = 0x0;
+constexpr uint8_t FRAME_ENCODING_PACKBITS = 0x1;
+
+// igen never sends more, see MAX_RECTS in igen/src/wire/mod.rs
+constexpr size_t FRAME_MAX_RECTS = 64;
+constexpr size_t FRAME_RECT_LEN = 16;
+// One 1bpp frame of this panel
+constexpr size_t FRAME_PLANE_LEN = (EPD_7IN5_V2_WIDTH + 7) / 8 * EPD_7IN5_V2_HEIGHT;
+// Largest raw payload (two planes) plus what packbits may add to it
+constexpr size_t FRAME_MAX_LEN = FRAME_HEADER_LEN + 2 * FRAME_PLANE_LEN + 2 * FRAME_PLANE_LEN / 128 + 1;
+
+// Largest decoded payload of a command on this panel, 0 for unknown ones
+size_t max_raw_length(const uint8_t command)
+{
+    switch (command) {
+        case 0x0: return FRAME_PLANE_LEN;
+        case 0x1: return FRAME_RECT_LEN + FRAME_PLANE_LEN;
+        // Rects don't overlap, so their bits add up to a frame at most
+        case 0x2: return 4 + FRAME_MAX_RECTS * FRAME_RECT_LEN + FRAME_PLANE_LEN;
+        case 0x3: return 2 * FRAME_PLANE_LEN;
+        case 0x4: return 2 * FRAME_PLANE_LEN;
+        default: return 0;
+    }
+}
+
+uint16_t bytes_to_u16_le(uint8_t b0, uint8_t b1)
+{
+    return static_cast<uint16_t>(b0) | (static_cast<uint16_t>(b1) << 8);
+}
 
 uint32_t bytes_to_u32_le(uint8_t b0, uint8_t b1, uint8_t b2, uint8_t b3)
 {
@@ -8,7 +44,47 @@ uint32_t bytes_to_u32_le(uint8_t b0, uint8_t b1, uint8_t b2, uint8_t b3)
            (static_cast<uint32_t>(b3) << 24);
 }
 
+// Returns false if the input is malformed or doesn't decode to exactly outLength bytes
+bool unpack_bits(const uint8_t* in, const size_t inLength, uint8_t* out, const size_t outLength)
+{
+    size_t i = 0;
+    size_t o = 0;
+    while (i < inLength) {
+        const auto n = static_cast<int8_t>(in[i++]);
+        if (n >= 0) {
+            const size_t len = n + 1;
+            if (i + len > inLength || o + len > outLength) {
+                return false;
+            }
+            memcpy(out + o, in + i, len);
+            i += len;
+            o += len;
+        }
+        else if (n != -128) {
+            const size_t len = 1 - n;
+            if (i >= inLength || o + len > outLength) {
+                return false;
+            }
+            memset(out + o, in[i], len);
+            i += 1;
+            o += len;
+        }
+    }
+    return o == outLength;
+}
+
 EpdJob Fetcher::fetch()
+{
+    frameId = -1;
+    etag = -1;
+    refreshAfter = DEFAULT_REFRESH_AFTER;
+    auto job = fetch_frame();
+    job.setFrameId(frameId);
+    job.setEtag(etag);
+    return job;
+}
+
+EpdJob Fetcher::fetch_frame()
 {
     // Do it messy for now
     printf("start fetch\n");
@@ -18,11 +94,27 @@ EpdJob Fetcher::fetch()
 
     client.setHttpResponseTimeout(30000);
 
-    auto err = client.get("/image");
+    client.beginRequest();
+    // Lets igen diff against what we show instead of what it sent last
+    String path = "/image";
+    const auto displayed = epd_displayed_frame();
+    if (displayed >= 0) {
+        path += "?displayed=" + String(static_cast<uint32_t>(displayed));
+    }
+    auto err = client.get(path);
     if (err != 0) {
         printf("Error while trying to fetch image: %d\n", err);
         return EpdJob{EpdJobKind::Clear};
     }
+    client.sendHeader("Accept-Frame-Encoding", "packbits");
+    // Nothing to download (and no refresh) while the panel already shows the current frame
+    const auto displayedEtag = epd_displayed_etag();
+    if (displayedEtag >= 0) {
+        char tag[16];
+        snprintf(tag, sizeof(tag), "\"%08x\"", static_cast<uint32_t>(displayedEtag));
+        client.sendHeader("If-None-Match", tag);
+    }
+    client.endRequest();
 
     const auto statusCode = client.responseStatusCode();
     printf("Status Code: %d\n", statusCode);
@@ -35,16 +127,42 @@ EpdJob Fetcher::fetch()
         if (headerName.equalsIgnoreCase("Content-Length")) {
             contentLength = headerValue.toInt();
         }
+        else if (headerName.equalsIgnoreCase("Frame-Id")) {
+            frameId = strtoul(headerValue.c_str(), nullptr, 10);
+        }
+        else if (headerName.equalsIgnoreCase("ETag")) {
+            // "0badf00d", see content_hash in igen/src/render/epd.rs
+            String value = headerValue;
+            value.replace("\"", "");
+            etag = strtoul(value.c_str(), nullptr, 16);
+        }
+        else if (headerName.equalsIgnoreCase("Refresh-After")) {
+            refreshAfter = strtoul(headerValue.c_str(), nullptr, 10);
+        }
     }
     
     printf("Content Length: %ld\n", contentLength);
 
+    if (statusCode == 304) {
+        printf("Frame not modified\r\n");
+        return EpdJob{EpdJobKind::Undefined};
+    }
+
     if (contentLength == -1) {
         printf("No content length");
         return EpdJob{EpdJobKind::Clear};
     }
 
-    auto* buf = new uint8_t[contentLength];
+    if (contentLength < 0 || static_cast<size_t>(contentLength) > FRAME_MAX_LEN) {
+        printf("Frame of %ld bytes is too large\r\n", contentLength);
+        return EpdJob{EpdJobKind::Undefined};
+    }
+
+    auto* buf = new (std::nothrow) uint8_t[contentLength];
+    if (buf == nullptr) {
+        printf("Out of memory for %ld byte frame\r\n", contentLength);
+        return EpdJob{EpdJobKind::Undefined};
+    }
 
     size_t totalRead = 0;
     while (totalRead < contentLength) {
@@ -58,35 +176,147 @@ EpdJob Fetcher::fetch()
         printf("read %d bytes (total: %zu of %ld)\n", bytesRead, totalRead, contentLength);
     }
 
-    auto command = bytes_to_u32_le(buf[0], buf[1], buf[2], buf[3]);
+    // See igen/src/wire/mod.rs for the frame layout
+    // Broken frames are dropped (Undefined) so the panel keeps showing the last good one
+    if (totalRead < FRAME_HEADER_LEN) {
+        printf("Frame too short for header\r\n");
+        delete[] buf;
+        return EpdJob{EpdJobKind::Undefined};
+    }
+
+    if (memcmp(buf, FRAME_MAGIC, 4) != 0) {
+        printf("Bad frame magic\r\n");
+        delete[] buf;
+        return EpdJob{EpdJobKind::Undefined};
+    }
+
+    const uint8_t version = buf[4];
+    const uint8_t command = buf[5];
+    const uint8_t encoding = buf[6];
+    const auto width = bytes_to_u16_le(buf[8], buf[9]);
+    const auto height = bytes_to_u16_le(buf[10], buf[11]);
+    const auto payloadLength = bytes_to_u32_le(buf[12], buf[13], buf[14], buf[15]);
+    const auto rawLength = bytes_to_u32_le(buf[16], buf[17], buf[18], buf[19]);
+    const auto crc = bytes_to_u32_le(buf[20], buf[21], buf[22], buf[23]);
+
+    if (version != FRAME_PROTOCOL_VERSION) {
+        printf("Unsupported protocol version: %d\r\n", version);
+        delete[] buf;
+        return EpdJob{EpdJobKind::Undefined};
+    }
+
+    if (width != EPD_7IN5_V2_WIDTH || height != EPD_7IN5_V2_HEIGHT) {
+        printf("Frame is for a %dx%d panel\r\n", width, height);
+        delete[] buf;
+        return EpdJob{EpdJobKind::Undefined};
+    }
+
+    if (payloadLength != static_cast<size_t>(contentLength) - FRAME_HEADER_LEN) {
+        printf("Payload length mismatch: header %u, got %ld\r\n", payloadLength, contentLength - FRAME_HEADER_LEN);
+        delete[] buf;
+        return EpdJob{EpdJobKind::Undefined};
+    }
+
+    if (crc32_le(0, buf + FRAME_HEADER_LEN, payloadLength) != crc) {
+        printf("Frame checksum mismatch\r\n");
+        delete[] buf;
+        return EpdJob{EpdJobKind::Undefined};
+    }
+
+    // Checked before allocating anything for it, the header is not to be trusted
+    if (rawLength > max_raw_length(command)) {
+        printf("Payload of %u bytes is too large for command %d\r\n", rawLength, command);
+        delete[] buf;
+        return EpdJob{EpdJobKind::Undefined};
+    }
+
+    // From here on payload is the decoded payload, owned by us
+    uint8_t* payload;
+    if (encoding == FRAME_ENCODING_RAW) {
+        if (rawLength != payloadLength) {
+            printf("Raw payload length mismatch\r\n");
+            delete[] buf;
+            return EpdJob{EpdJobKind::Undefined};
+        }
+        payload = new (std::nothrow) uint8_t[payloadLength];
+        if (payload == nullptr) {
+            printf("Out of memory for %u byte payload\r\n", payloadLength);
+            delete[] buf;
+            return EpdJob{EpdJobKind::Undefined};
+        }
+        memcpy(payload, buf + FRAME_HEADER_LEN, payloadLength);
+    }
+    else if (encoding == FRAME_ENCODING_PACKBITS) {
+        payload = new (std::nothrow) uint8_t[rawLength];
+        if (payload == nullptr) {
+            printf("Out of memory for %u byte payload\r\n", rawLength);
+            delete[] buf;
+            return EpdJob{EpdJobKind::Undefined};
+        }
+        if (!unpack_bits(buf + FRAME_HEADER_LEN, payloadLength, payload, rawLength)) {
+            printf("Malformed packbits payload\r\n");
+            delete[] payload;
+            delete[] buf;
+            return EpdJob{EpdJobKind::Undefined};
+        }
+        printf("Unpacked %u bytes into %u\r\n", payloadLength, rawLength);
+    }
+    else {
+        printf("Unknown frame encoding: %d\r\n", encoding);
+        delete[] buf;
+        return EpdJob{EpdJobKind::Undefined};
+    }
+    delete[] buf;
+
     if (command == 0x0) {
         // Full update
         printf("Full update command received\r\n");
 
-        const auto fixed_data = new uint8_t[contentLength - 4];
-        memcpy(fixed_data, buf + 4, static_cast<size_t>(contentLength) - 4);
-        delete[] buf;
-
-        return EpdJob{EpdJobKind::Display, fixed_data, static_cast<size_t>(contentLength) - 4};
+        return EpdJob{EpdJobKind::Display, payload, rawLength};
     }
     else if (command == 0x1) {
         // Partial
         printf("Partial update command received\r\n");
+        if (rawLength < 16) {
+            printf("Partial payload too short\r\n");
+            delete[] payload;
+            return EpdJob{EpdJobKind::Undefined};
+        }
         // cba
-        uint64_t aux[4] = {0};
-        aux[0] = bytes_to_u32_le(buf[4], buf[5], buf[6], buf[7]);
-        aux[1] = bytes_to_u32_le(buf[8], buf[9], buf[10], buf[11]);
-        aux[2] = bytes_to_u32_le(buf[12], buf[13], buf[14], buf[15]);
-        aux[3] = bytes_to_u32_le(buf[16], buf[17], buf[18], buf[19]);
-
-        const auto fixed_data = new uint8_t[contentLength - 20];
-        memcpy(fixed_data, buf + 20, static_cast<size_t>(contentLength) - 20);
-        delete[] buf;
+        uint64_t aux[16] = {0};
+        aux[0] = bytes_to_u32_le(payload[0], payload[1], payload[2], payload[3]);
+        aux[1] = bytes_to_u32_le(payload[4], payload[5], payload[6], payload[7]);
+        aux[2] = bytes_to_u32_le(payload[8], payload[9], payload[10], payload[11]);
+        aux[3] = bytes_to_u32_le(payload[12], payload[13], payload[14], payload[15]);
+
+        const auto fixed_data = new (std::nothrow) uint8_t[rawLength - 16];
+        if (fixed_data == nullptr) {
+            printf("Out of memory for partial update\r\n");
+            delete[] payload;
+            return EpdJob{EpdJobKind::Undefined};
+        }
+        memcpy(fixed_data, payload + 16, rawLength - 16);
+        delete[] payload;
 
-        return EpdJob{EpdJobKind::DisplayPartial, fixed_data, static_cast<size_t>(contentLength) - 20, aux};
+        return EpdJob{EpdJobKind::DisplayPartial, fixed_data, rawLength - 16, aux};
+    }
+    else if (command == 0x2) {
+        printf("Multi partial update command received\r\n");
+        return EpdJob{EpdJobKind::DisplayMultiPartial, payload, rawLength};
+    }
+    else if (command == 0x3) {
+        printf("4-gray update command received\r\n");
+        return EpdJob{EpdJobKind::Display4Gray, payload, rawLength};
+    }
+    else if (command == 0x4) {
+        // Needs the 7in5b (tri-color) driver, which this firmware does not ship
+        printf("Tri-color update command received, unsupported by this panel\r\n");
+        delete[] payload;
+        return EpdJob{EpdJobKind::Undefined};
     }
     else {
         printf("Unknown image command\r\n");
-        return EpdJob{EpdJobKind::Clear};
+        delete[] payload;
+        return EpdJob{EpdJobKind::Undefined};
     }
 }
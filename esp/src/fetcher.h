@@ -5,6 +5,23 @@
 
 class Fetcher
 {
+    // Frame-Id header of the last response
+    int64_t frameId = -1;
+    // ETag header of the last response, -1 if none
+    int64_t etag = -1;
+    // Refresh-After header of the last response, in seconds
+    uint32_t refreshAfter = DEFAULT_REFRESH_AFTER;
+
+    EpdJob fetch_frame();
+
 public:
+    // Used when igen doesn't say how long to sleep
+    static constexpr uint32_t DEFAULT_REFRESH_AFTER = 60;
+
     EpdJob fetch();
+
+    uint32_t refresh_after() const
+    {
+        return refreshAfter;
+    }
 };
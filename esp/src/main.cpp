@@ -46,5 +46,8 @@ void loop()
     const auto job = fetcher.fetch();
     xQueueSendToBack(ipc_queue, &job, portMAX_DELAY);
 
-    delay(1000 * 60);
+    // igen knows when the dashboard changes next
+    const auto seconds = fetcher.refresh_after();
+    printf("Sleeping for %u s\r\n", seconds);
+    delay(1000 * seconds);
 }
@@ -1,25 +1,59 @@
 #![allow(dead_code)]
 #![allow(clippy::needless_range_loop)]
 
-use crate::render::dash::RenderAction;
 use axum::Router;
 use axum::body::Bytes;
-use axum::extract::State;
+use axum::extract::{Path, Query, State};
+use axum::http::HeaderMap;
+use axum::http::header::{ETAG, IF_NONE_MATCH};
 use axum::response::Response;
-use axum::routing::get;
+use axum::routing::{get, post};
 use log::debug;
-use render::dash::Dash;
+use render::dash::{Dash, Rendered};
+use serde::Deserialize;
+use std::collections::HashMap;
 use std::sync::Arc;
 use tokio::sync::Mutex;
+use widget::WidgetRegistry;
+use wire::Encoding;
 
 mod provider;
 mod render;
 mod settings;
+mod widget;
+mod wire;
+
+// Opt-in for compressed payloads, e.g. "Accept-Frame-Encoding: packbits"
+const ACCEPT_FRAME_ENCODING: &str = "accept-frame-encoding";
+// Alternative to /device/{id}/image for firmware that can't change its url
+const DEVICE_ID: &str = "device-id";
+// Seconds until the dashboard changes next, devices may sleep until then
+const REFRESH_AFTER: &str = "refresh-after";
+// Id of the frame in the response, to be reported back once it is on the panel
+const FRAME_ID: &str = "frame-id";
+
+// ?displayed=<frame id>, on the next image request or on its own via POST .../ack
+#[derive(Deserialize)]
+struct Displayed {
+    displayed: Option<u32>,
+}
 
 #[derive(Clone)]
 pub struct AppState {
     // ballin
+    // For everything that doesn't say which device it is
     dash: Arc<Mutex<Dash>>,
+    // [[devices]] by id
+    devices: Arc<HashMap<String, Arc<Mutex<Dash>>>>,
+}
+
+impl AppState {
+    fn device(&self, id: Option<&str>) -> Option<Arc<Mutex<Dash>>> {
+        match id {
+            None => Some(self.dash.clone()),
+            Some(id) => self.devices.get(id).cloned(),
+        }
+    }
 }
 
 #[tokio::main]
@@ -38,16 +72,33 @@ async fn main() {
     debug!("Config: {:?}", config);
 
     // well not a fun of awaiting a constructor
-    let dash = Dash::new(config).await;
+    let widgets = Arc::new(WidgetRegistry::with_defaults(&config).await);
+    widgets.spawn_refresh();
+    let mut devices = HashMap::new();
+    for device in &config.devices {
+        let dash = Dash::for_device(&config, device, widgets.clone());
+        if devices
+            .insert(device.id.clone(), Arc::new(Mutex::new(dash)))
+            .is_some()
+        {
+            panic!("Device id {} is used twice", device.id);
+        }
+    }
+    let dash = Dash::new(config, widgets);
 
     let state = AppState {
         dash: Arc::new(Mutex::new(dash)),
+        devices: Arc::new(devices),
     };
 
     let app = Router::new()
         .route("/", get(root))
         .route("/image", get(image))
         .route("/nice_image", get(nice_image))
+        .route("/ack", post(ack))
+        .route("/device/{id}/image", get(device_image))
+        .route("/device/{id}/ack", post(device_ack))
+        .route("/device/{id}/nice_image", get(device_nice_image))
         .with_state(state);
     let listener = tokio::net::TcpListener::bind("0.0.0.0:7676")
         .await
@@ -62,43 +113,126 @@ async fn root() -> &'static str {
     "🦕"
 }
 
-async fn image(State(state): State<AppState>) -> Response {
-    // probability of correct concurrency: 40%
-    let mut dash = { state.dash.lock().await };
+fn device_id(headers: &HeaderMap) -> Option<&str> {
+    headers.get(DEVICE_ID).and_then(|v| v.to_str().ok())
+}
 
-    // yolo protocol
+async fn image(
+    State(state): State<AppState>,
+    Query(displayed): Query<Displayed>,
+    headers: HeaderMap,
+) -> Response {
+    match state.device(device_id(&headers)) {
+        Some(dash) => send_frame(&dash, displayed, &headers).await,
+        None => unknown_device(),
+    }
+}
 
-    let img_data = match dash.render(false).await {
-        RenderAction::Full(data) => {
-            let mut bv = 0x00u32.to_le_bytes().to_vec();
-            bv.extend(data);
-            bv
-        }
-        RenderAction::Partial(bbox, data) => {
-            let mut bv = [
-                0x01u32,
-                bbox.x as u32,
-                bbox.y as u32,
-                bbox.width as u32,
-                bbox.height as u32,
-            ]
-            .iter()
-            .flat_map(|v| v.to_le_bytes())
-            .collect::<Vec<u8>>();
-            bv.extend(data);
-            bv
+async fn device_image(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Query(displayed): Query<Displayed>,
+    headers: HeaderMap,
+) -> Response {
+    match state.device(Some(&id)) {
+        Some(dash) => send_frame(&dash, displayed, &headers).await,
+        None => unknown_device(),
+    }
+}
+
+async fn ack(
+    State(state): State<AppState>,
+    Query(displayed): Query<Displayed>,
+    headers: HeaderMap,
+) -> Response {
+    match state.device(device_id(&headers)) {
+        Some(dash) => acknowledge(&dash, displayed).await,
+        None => unknown_device(),
+    }
+}
+
+async fn device_ack(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Query(displayed): Query<Displayed>,
+) -> Response {
+    match state.device(Some(&id)) {
+        Some(dash) => acknowledge(&dash, displayed).await,
+        None => unknown_device(),
+    }
+}
+
+async fn acknowledge(dash: &Mutex<Dash>, displayed: Displayed) -> Response {
+    let Some(frame_id) = displayed.displayed else {
+        return Response::builder()
+            .status(400)
+            .body("missing ?displayed=<frame id>".into())
+            .unwrap();
+    };
+    dash.lock().await.acknowledge(frame_id);
+    Response::builder().status(204).body(().into()).unwrap()
+}
+
+async fn send_frame(dash: &Mutex<Dash>, displayed: Displayed, headers: &HeaderMap) -> Response {
+    // probability of correct concurrency: 40%
+    let mut dash = { dash.lock().await };
+
+    if let Some(frame_id) = displayed.displayed {
+        dash.acknowledge(frame_id);
+    }
+    let if_none_match = headers.get(IF_NONE_MATCH).and_then(|v| v.to_str().ok());
+    let (frame_id, etag, action) = match dash.render(false, if_none_match) {
+        Rendered::Frame { id, etag, action } => (id, etag, action),
+        Rendered::Unchanged { etag } => {
+            return Response::builder()
+                .status(304)
+                .header(ETAG, etag)
+                .header(REFRESH_AFTER, dash.sleep_hint().as_secs().to_string())
+                .body(().into())
+                .unwrap();
         }
     };
+    let encoding = headers
+        .get(ACCEPT_FRAME_ENCODING)
+        .and_then(|v| v.to_str().ok())
+        .map(Encoding::negotiate)
+        .unwrap_or_default();
+    let (width, height) = dash.size();
+    let img_data = wire::encode(&action, width, height, encoding);
+    debug!("Sending {} bytes ({})", img_data.len(), encoding.name());
 
     let bytes = Bytes::from(img_data);
     Response::builder()
+        .header("Content-Type", "application/octet-stream")
         .header("Content-Length", bytes.len().to_string())
+        .header(ETAG, etag)
+        .header(FRAME_ID, frame_id.to_string())
+        .header(REFRESH_AFTER, dash.sleep_hint().as_secs().to_string())
         .body(bytes.into())
         .unwrap()
 }
 
-async fn nice_image() -> Response {
-    match tokio::fs::read("output.png").await {
+fn unknown_device() -> Response {
+    Response::builder()
+        .status(404)
+        .body("unknown device".into())
+        .unwrap()
+}
+
+async fn nice_image(State(state): State<AppState>) -> Response {
+    send_preview(&state.dash).await
+}
+
+async fn device_nice_image(State(state): State<AppState>, Path(id): Path<String>) -> Response {
+    match state.device(Some(&id)) {
+        Some(dash) => send_preview(&dash).await,
+        None => unknown_device(),
+    }
+}
+
+async fn send_preview(dash: &Mutex<Dash>) -> Response {
+    let path = dash.lock().await.preview_path();
+    match tokio::fs::read(path).await {
         Ok(img_data) => Response::builder()
             .header("Content-Type", "image/png")
             .body(Bytes::from(img_data).into())
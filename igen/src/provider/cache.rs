@@ -0,0 +1,69 @@
+// Provider results on disk, one json file per provider, so a restart of igen
+// picks up the last data instead of refetching (and reshuffling) everything.
+// The in-memory copy is what widgets render from.
+
+use chrono::{DateTime, Local};
+use log::warn;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+
+#[derive(Serialize, Deserialize)]
+pub struct CacheEntry<T> {
+    pub fetched_at: DateTime<Local>,
+    pub value: T,
+}
+
+pub struct DiskCache<T> {
+    path: PathBuf,
+    entry: Mutex<Option<CacheEntry<T>>>,
+}
+
+impl<T: Serialize + DeserializeOwned> DiskCache<T> {
+    // A missing or unreadable file just means starting empty
+    pub fn open(dir: &str, name: &str) -> Self {
+        let path = Path::new(dir).join(format!("{name}.json"));
+        let entry = match fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content)
+                .inspect_err(|e| warn!("Ignoring cache file {:?}: {}", path, e))
+                .ok(),
+            Err(_) => None,
+        };
+        DiskCache {
+            path,
+            entry: Mutex::new(entry),
+        }
+    }
+
+    pub fn get(&self) -> MutexGuard<'_, Option<CacheEntry<T>>> {
+        self.entry.lock().unwrap()
+    }
+
+    pub fn fetched_at(&self) -> Option<DateTime<Local>> {
+        self.get().as_ref().map(|e| e.fetched_at)
+    }
+
+    // Replaces the cached value, failing to persist it only costs a refetch later
+    pub fn store(&self, value: T) {
+        let entry = CacheEntry {
+            fetched_at: Local::now(),
+            value,
+        };
+        if let Err(e) = self.write(&entry) {
+            warn!("Could not write cache file {:?}: {}", self.path, e);
+        }
+        *self.get() = Some(entry);
+    }
+
+    fn write(&self, entry: &CacheEntry<T>) -> std::io::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        // Write and rename so a crash never leaves half a file behind
+        let tmp = self.path.with_extension("json.tmp");
+        fs::write(&tmp, serde_json::to_vec(entry)?)?;
+        fs::rename(tmp, &self.path)
+    }
+}
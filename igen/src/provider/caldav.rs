@@ -0,0 +1,190 @@
+// CalDAV (RFC 4791) calendar collections, e.g. Nextcloud, Radicale or iCloud.
+//
+// A single calendar-query REPORT returns every event touching the window as
+// iCalendar data, which is expanded the same way as .ics files are.
+
+use crate::provider::ProviderError;
+use crate::provider::calendar::Event;
+use crate::provider::ical;
+use chrono::{DateTime, Local};
+use reqwest::Method;
+use reqwest::header::CONTENT_TYPE;
+
+const CALDAV_NS: &str = "urn:ietf:params:xml:ns:caldav";
+
+pub struct CalDavProvider {
+    // The collection, not the server root
+    url: String,
+    username: Option<String>,
+    password: Option<String>,
+    http_client: reqwest::Client,
+}
+
+fn calendar_query(from: DateTime<Local>, to: DateTime<Local>) -> String {
+    const FORMAT: &str = "%Y%m%dT%H%M%SZ";
+    format!(
+        r#"<?xml version="1.0" encoding="utf-8"?>
+<c:calendar-query xmlns:d="DAV:" xmlns:c="{CALDAV_NS}">
+  <d:prop><c:calendar-data/></d:prop>
+  <c:filter>
+    <c:comp-filter name="VCALENDAR">
+      <c:comp-filter name="VEVENT">
+        <c:time-range start="{}" end="{}"/>
+      </c:comp-filter>
+    </c:comp-filter>
+  </c:filter>
+</c:calendar-query>"#,
+        from.to_utc().format(FORMAT),
+        to.to_utc().format(FORMAT)
+    )
+}
+
+// The iCalendar objects in a multistatus response
+fn calendar_data(multistatus: &str) -> Result<Vec<String>, ProviderError> {
+    let doc = roxmltree::Document::parse(multistatus)
+        .map_err(|e| ProviderError::InvalidData(format!("invalid multistatus: {e}")))?;
+    Ok(doc
+        .descendants()
+        .filter(|n| n.has_tag_name((CALDAV_NS, "calendar-data")))
+        .filter_map(|n| n.text())
+        .map(String::from)
+        .collect())
+}
+
+impl CalDavProvider {
+    pub fn new(url: String, username: Option<String>, password: Option<String>) -> CalDavProvider {
+        CalDavProvider {
+            url,
+            username,
+            password,
+            http_client: reqwest::Client::new(),
+        }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub async fn fetch(
+        &self,
+        from: DateTime<Local>,
+        to: DateTime<Local>,
+    ) -> Result<Vec<Event>, ProviderError> {
+        let report = Method::from_bytes(b"REPORT").expect("REPORT is a valid method");
+        let mut request = self
+            .http_client
+            .request(report, &self.url)
+            .header("Depth", "1")
+            .header(CONTENT_TYPE, "application/xml; charset=utf-8")
+            .body(calendar_query(from, to));
+        if let Some(username) = &self.username {
+            request = request.basic_auth(username, self.password.as_ref());
+        }
+        let multistatus = request.send().await?.error_for_status()?.text().await?;
+
+        let mut events = vec![];
+        for data in calendar_data(&multistatus)? {
+            events.extend(ical::events_between(&data, from, to));
+        }
+        Ok(events)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::provider::calendar::Time;
+    use axum::Router;
+    use axum::http::{HeaderMap, Method, StatusCode};
+    use axum::routing::any;
+    use chrono::{TimeZone, Utc};
+
+    // What Nextcloud answers, trimmed
+    const MULTISTATUS: &str = r#"<?xml version="1.0"?>
+<d:multistatus xmlns:d="DAV:" xmlns:cal="urn:ietf:params:xml:ns:caldav">
+  <d:response>
+    <d:href>/remote.php/dav/calendars/me/personal/a.ics</d:href>
+    <d:propstat>
+      <d:prop><cal:calendar-data>BEGIN:VCALENDAR
+BEGIN:VEVENT
+UID:a
+SUMMARY:Dentist
+DTSTART:20250314T080000Z
+DTEND:20250314T090000Z
+END:VEVENT
+END:VCALENDAR
+</cal:calendar-data></d:prop>
+      <d:status>HTTP/1.1 200 OK</d:status>
+    </d:propstat>
+  </d:response>
+  <d:response>
+    <d:href>/remote.php/dav/calendars/me/personal/b.ics</d:href>
+    <d:propstat>
+      <d:prop><cal:calendar-data><![CDATA[BEGIN:VCALENDAR
+BEGIN:VEVENT
+UID:b
+SUMMARY:Rent & bills
+DTSTART;VALUE=DATE:20250301
+RRULE:FREQ=MONTHLY
+END:VEVENT
+END:VCALENDAR
+]]></cal:calendar-data></d:prop>
+      <d:status>HTTP/1.1 200 OK</d:status>
+    </d:propstat>
+  </d:response>
+</d:multistatus>"#;
+
+    // Stand-in for the server, wants the REPORT and credentials a client sends
+    async fn report(
+        method: Method,
+        headers: HeaderMap,
+        body: String,
+    ) -> (StatusCode, &'static str) {
+        let authorized = headers
+            .get("authorization")
+            .is_some_and(|v| v == "Basic bWU6c2VjcmV0");
+        if method.as_str() != "REPORT" || headers.get("depth").is_none_or(|d| d != "1") {
+            (StatusCode::METHOD_NOT_ALLOWED, "")
+        } else if !authorized {
+            (StatusCode::UNAUTHORIZED, "")
+        } else if !body
+            .contains(r#"<c:time-range start="20250310T000000Z" end="20250410T000000Z"/>"#)
+        {
+            (StatusCode::BAD_REQUEST, "")
+        } else {
+            (StatusCode::MULTI_STATUS, MULTISTATUS)
+        }
+    }
+
+    #[tokio::test]
+    async fn queries_collection() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = Router::new().route("/dav/personal/", any(report));
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let url = format!("http://{addr}/dav/personal/");
+        let from = Utc.with_ymd_and_hms(2025, 3, 10, 0, 0, 0).unwrap().into();
+        let to = Utc.with_ymd_and_hms(2025, 4, 10, 0, 0, 0).unwrap().into();
+        let provider = CalDavProvider::new(url.clone(), Some("me".into()), Some("secret".into()));
+        let events = provider.fetch(from, to).await.unwrap();
+
+        let titles: Vec<_> = events.iter().map(|e| e.title.as_str()).collect();
+        assert_eq!(titles, ["Dentist", "Rent & bills"]);
+        assert_eq!(
+            events[0].time,
+            Time::Timed(
+                Utc.with_ymd_and_hms(2025, 3, 14, 8, 0, 0).unwrap().into(),
+                chrono::TimeDelta::hours(1)
+            )
+        );
+        // Only the occurrence within the window
+        assert_eq!(
+            events[1].time,
+            Time::AllDay(chrono::NaiveDate::from_ymd_opt(2025, 4, 1).unwrap())
+        );
+
+        let anonymous = CalDavProvider::new(url, None, None);
+        assert!(anonymous.fetch(from, to).await.is_err());
+    }
+}
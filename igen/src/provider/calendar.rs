@@ -0,0 +1,379 @@
+// Events from every configured calendar source, merged into one list.
+//
+// Google goes through its API, .ics files and CalDAV collections are expanded
+// locally (see ical and rrule). All of them end up as the same Event.
+
+use crate::provider::ProviderError;
+use crate::provider::caldav::CalDavProvider;
+use crate::provider::calendar::Time::{AllDay, Timed};
+use crate::provider::google::CalendarProvider;
+use crate::provider::ical::IcsProvider;
+use crate::render::graphics::Color;
+use crate::settings::{CalendarDisplay, CalendarSourceConfig, Config};
+use chrono::{Local, TimeDelta};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+
+// How far ahead .ics and CalDAV sources are expanded
+const LOOKAHEAD: TimeDelta = TimeDelta::days(31);
+// Per calendar, unless it sets max_events
+pub const MAX_EVENTS: usize = 10;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Time {
+    AllDay(chrono::NaiveDate),
+    Timed(
+        chrono::DateTime<chrono::Local>,
+        #[serde(with = "duration_secs")] chrono::TimeDelta,
+    ),
+}
+
+// TimeDelta has no serde support of its own
+mod duration_secs {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(d: &chrono::TimeDelta, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_i64(d.num_seconds())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<chrono::TimeDelta, D::Error> {
+        i64::deserialize(d).map(chrono::TimeDelta::seconds)
+    }
+}
+
+impl PartialEq for Time {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (AllDay(f), AllDay(s)) => f == s,
+            (Timed(f, df), Timed(s, ds)) => f == s && df == ds,
+            (_, _) => false,
+        }
+    }
+}
+
+impl Eq for Time {}
+
+impl PartialOrd for Time {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Time {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (AllDay(f), AllDay(s)) => f.cmp(s),
+            (Timed(f, _), Timed(s, _)) => f.cmp(s),
+            // "AllDays" are always before timed events
+            (AllDay(date1), Timed(start2, _)) => {
+                let date2 = start2.date_naive();
+                match date1.cmp(&date2) {
+                    Ordering::Equal => Ordering::Less,
+                    ordering => ordering,
+                }
+            }
+            (Timed(start1, _), AllDay(date2)) => {
+                let date1 = start1.date_naive();
+                match date1.cmp(date2) {
+                    Ordering::Equal => Ordering::Greater,
+                    ordering => ordering,
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Event {
+    pub time: Time,
+    pub title: String,
+    // From the display options of the event's calendar
+    #[serde(default)]
+    pub prefix: Option<String>,
+    #[serde(default)]
+    pub color: Option<Color>,
+}
+
+impl Event {
+    pub fn new(time: Time, title: String) -> Self {
+        Event {
+            time,
+            title,
+            prefix: None,
+            color: None,
+        }
+    }
+}
+
+impl CalendarDisplay {
+    // Whether the hide options let it through
+    pub fn shows(&self, event: &Event) -> bool {
+        match event.time {
+            AllDay(_) => !self.hide_all_day,
+            Timed(..) => !self.hide_timed,
+        }
+    }
+
+    // The events of one calendar as they are shown
+    pub fn apply(&self, mut events: Vec<Event>) -> Vec<Event> {
+        events.retain(|e| self.shows(e));
+        events.sort_by(|f, s| f.time.cmp(&s.time));
+        events.truncate(self.max_events.unwrap_or(MAX_EVENTS));
+        for event in &mut events {
+            event.prefix = self.prefix.clone();
+            event.color = self.color;
+        }
+        events
+    }
+}
+
+enum Source {
+    // Display options are per calendar in [google]
+    Google(CalendarProvider),
+    Ics(IcsProvider, CalendarDisplay),
+    CalDav(CalDavProvider, CalendarDisplay),
+}
+
+pub struct Calendars {
+    sources: Vec<Source>,
+    // What every source returned last time, kept while it fails
+    previous: Vec<Option<Vec<Event>>>,
+    debug: bool,
+}
+
+impl Calendars {
+    pub async fn new(config: Config) -> Self {
+        let mut configured = config.calendars.clone();
+        // Configs from before there were other sources
+        if configured.is_empty() && config.google.is_some() {
+            configured.push(CalendarSourceConfig::Google);
+        }
+        let mut sources = vec![];
+        for source in configured {
+            sources.push(match source {
+                CalendarSourceConfig::Google => Source::Google(CalendarProvider::new(
+                    config
+                        .google
+                        .clone()
+                        .expect("Calendar source google needs a [google] section"),
+                )),
+                CalendarSourceConfig::Ics { source, display } => {
+                    Source::Ics(IcsProvider::new(source), display)
+                }
+                CalendarSourceConfig::Caldav {
+                    url,
+                    username,
+                    password,
+                    display,
+                } => Source::CalDav(CalDavProvider::new(url, username, password), display),
+            });
+        }
+        Calendars {
+            previous: vec![None; sources.len()],
+            sources,
+            debug: config.general.debug,
+        }
+    }
+
+    // Sources are fetched independently, one that fails keeps the events it
+    // had last time. Only fails if all of them do, preferring AuthRequired so
+    // the widget can ask for it
+    pub async fn fetch(&mut self) -> Result<Vec<Event>, ProviderError> {
+        if self.debug {
+            return Ok(vec![Event::new(
+                AllDay(Local::now().date_naive()),
+                "hehe".to_string(),
+            )]);
+        }
+
+        let from = Local::now();
+        let to = from + LOOKAHEAD;
+        let mut combined_events = vec![];
+        let mut succeeded = false;
+        let mut error = None;
+        for (source, previous) in self.sources.iter_mut().zip(self.previous.iter_mut()) {
+            let result = match source {
+                Source::Google(provider) => provider.fetch().await,
+                Source::Ics(provider, display) => {
+                    provider.fetch(from, to).await.map(|e| display.apply(e))
+                }
+                Source::CalDav(provider, display) => {
+                    provider.fetch(from, to).await.map(|e| display.apply(e))
+                }
+            };
+            match result {
+                Ok(events) => {
+                    succeeded = true;
+                    *previous = Some(events);
+                }
+                Err(e) => {
+                    warn!("Could not fetch {}: {}", source.name(), e);
+                    if !matches!(error, Some(ProviderError::AuthRequired)) {
+                        error = Some(e);
+                    }
+                }
+            }
+            combined_events.extend(previous.iter().flatten().cloned());
+        }
+        if !succeeded && let Some(error) = error {
+            return Err(error);
+        }
+        combined_events.sort_by(|f, s| f.time.cmp(&s.time));
+        Ok(combined_events)
+    }
+}
+
+impl Source {
+    // For log messages
+    fn name(&self) -> &str {
+        match self {
+            Source::Google(_) => "google calendar",
+            Source::Ics(provider, _) => provider.source(),
+            Source::CalDav(provider, _) => provider.url(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::GoogleConfig;
+    use chrono::NaiveDate;
+
+    fn google(calendars: &str) -> Result<GoogleConfig, config::ConfigError> {
+        let toml = format!(
+            r#"
+            token_path = ""
+            client_id = ""
+            client_secret = ""
+            auth_uri = "https://accounts.google.com/o/oauth2/v2/auth"
+            redirect_uri = "http://localhost:8080"
+            token_uri = "https://www.googleapis.com/oauth2/v3/token"
+            {calendars}
+            "#
+        );
+        config::Config::builder()
+            .add_source(config::File::from_str(&toml, config::FileFormat::Toml))
+            .build()?
+            .try_deserialize()
+    }
+
+    #[test]
+    fn calendar_entries() {
+        // As in config.example.toml before entries were tables
+        let old = google(r#"calender_list = ["Holidays in Germany"]"#).unwrap();
+        assert_eq!(
+            old.calendar_list[0].name.as_deref(),
+            Some("Holidays in Germany")
+        );
+
+        let new = google(
+            r#"
+            [[calendar_list]]
+            id = "en.german#holiday@group.v.calendar.google.com"
+            prefix = "*"
+            color = "accent"
+            hide_timed = true
+            [[calendar_list]]
+            name = "Work"
+            max_events = 3
+            "#,
+        )
+        .unwrap();
+        let (holidays, work) = (&new.calendar_list[0], &new.calendar_list[1]);
+        assert!(holidays.name.is_none() && holidays.display.hide_timed);
+        assert_eq!(holidays.display.prefix.as_deref(), Some("*"));
+        assert!(matches!(holidays.display.color, Some(Color::Accent)));
+        assert_eq!(work.display.max_events, Some(3));
+        assert!(!work.display.hide_all_day);
+
+        assert!(google("[[calendar_list]]\nprefix = \"*\"").is_err());
+    }
+
+    #[test]
+    fn display_options() {
+        let day = |d| NaiveDate::from_ymd_opt(2025, 3, d).unwrap();
+        let at = |d| {
+            day(d)
+                .and_hms_opt(9, 0, 0)
+                .unwrap()
+                .and_local_timezone(Local)
+                .unwrap()
+        };
+        let events = || {
+            vec![
+                Event::new(Timed(at(16), TimeDelta::hours(1)), "c".to_string()),
+                Event::new(AllDay(day(14)), "a".to_string()),
+                Event::new(Timed(at(15), TimeDelta::hours(1)), "b".to_string()),
+            ]
+        };
+        let titles = |events: Vec<Event>| events.into_iter().map(|e| e.title).collect::<Vec<_>>();
+
+        let display = CalendarDisplay {
+            prefix: Some("#".to_string()),
+            max_events: Some(2),
+            ..CalendarDisplay::default()
+        };
+        let shown = display.apply(events());
+        assert_eq!(shown[0].prefix.as_deref(), Some("#"));
+        assert_eq!(titles(shown), ["a", "b"]);
+
+        let timed_only = CalendarDisplay {
+            hide_all_day: true,
+            ..CalendarDisplay::default()
+        };
+        assert_eq!(titles(timed_only.apply(events())), ["b", "c"]);
+        let all_day_only = CalendarDisplay {
+            hide_timed: true,
+            ..CalendarDisplay::default()
+        };
+        assert_eq!(titles(all_day_only.apply(events())), ["a"]);
+    }
+
+    #[tokio::test]
+    async fn sources_fail_independently() {
+        let dir = std::env::temp_dir().join(format!("igen-calendars-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let tomorrow = Local::now().date_naive() + TimeDelta::days(1);
+        let write = |name: &str| {
+            let path = dir.join(format!("{name}.ics"));
+            std::fs::write(
+                &path,
+                format!(
+                    "BEGIN:VCALENDAR\r\nBEGIN:VEVENT\r\nSUMMARY:{name}\r\nDTSTART;VALUE=DATE:{}\r\nEND:VEVENT\r\nEND:VCALENDAR\r\n",
+                    tomorrow.format("%Y%m%d")
+                ),
+            )
+            .unwrap();
+            path
+        };
+        let ics = |path: &std::path::Path| {
+            Source::Ics(
+                IcsProvider::new(path.to_string_lossy().into_owned()),
+                CalendarDisplay::default(),
+            )
+        };
+        let (home, work) = (write("home"), write("work"));
+        let mut calendars = Calendars {
+            sources: vec![ics(&dir.join("missing.ics")), ics(&home), ics(&work)],
+            previous: vec![None, None, None],
+            debug: false,
+        };
+        let titles = |events: Vec<Event>| {
+            let mut titles: Vec<_> = events.into_iter().map(|e| e.title).collect();
+            titles.sort();
+            titles
+        };
+        assert_eq!(titles(calendars.fetch().await.unwrap()), ["home", "work"]);
+
+        // Keeps what it had while it fails
+        std::fs::remove_file(&work).unwrap();
+        assert_eq!(titles(calendars.fetch().await.unwrap()), ["home", "work"]);
+
+        std::fs::remove_file(&home).unwrap();
+        assert!(calendars.fetch().await.is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,130 +1,80 @@
-use crate::provider::google::Time::{AllDay, Timed};
-use crate::settings::Config;
-use std::cmp::Ordering;
-
-use log::{debug, warn};
-use oauth2::basic::{BasicClient, BasicTokenResponse};
-use oauth2::{
-    AuthUrl, AuthorizationCode, ClientId, ClientSecret, CsrfToken, PkceCodeChallenge, RedirectUrl,
-    RefreshToken, Scope, TokenResponse, TokenUrl, reqwest,
-};
+use crate::provider::ProviderError;
+use crate::provider::calendar::Time::{AllDay, Timed};
+use crate::provider::calendar::{Event, MAX_EVENTS};
+use crate::provider::token::TokenManager;
+use crate::settings::{CalendarDisplay, GoogleConfig};
+
+use log::warn;
+use oauth2::reqwest;
 use reqwest::Url;
-use serde::{Deserialize, Serialize};
-use std::fs;
-use std::io::{BufRead, BufReader, Write};
-use std::net::TcpListener;
-use std::ops::Add;
-use std::path::Path;
+use serde::Deserialize;
 use std::str::FromStr;
+use std::sync::Arc;
 
-#[derive(Debug)]
-pub enum Time {
-    AllDay(chrono::NaiveDate),
-    Timed(chrono::DateTime<chrono::Local>, chrono::TimeDelta),
-}
-
-impl PartialEq for Time {
-    fn eq(&self, other: &Self) -> bool {
-        match (self, other) {
-            (AllDay(f), AllDay(s)) => f == s,
-            (Timed(f, df), Timed(s, ds)) => f == s && df == ds,
-            (_, _) => false,
-        }
-    }
-}
-
-impl Eq for Time {}
-
-impl PartialOrd for Time {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.cmp(other))
-    }
-}
-
-impl Ord for Time {
-    fn cmp(&self, other: &Self) -> Ordering {
-        match (self, other) {
-            (AllDay(f), AllDay(s)) => f.cmp(s),
-            (Timed(f, _), Timed(s, _)) => f.cmp(s),
-            // "AllDays" are always before timed events
-            (AllDay(date1), Timed(start2, _)) => {
-                let date2 = start2.date_naive();
-                match date1.cmp(&date2) {
-                    Ordering::Equal => Ordering::Less,
-                    ordering => ordering,
-                }
-            }
-            (Timed(start1, _), AllDay(date2)) => {
-                let date1 = start1.date_naive();
-                match date1.cmp(date2) {
-                    Ordering::Equal => Ordering::Greater,
-                    ordering => ordering,
-                }
-            }
-        }
-    }
-}
-
-#[derive(Debug)]
-pub struct Event {
-    pub time: Time,
-    pub title: String,
-}
-
-#[derive(Serialize, Deserialize)]
-struct StoredToken {
-    access_token: String,
-    refresh_token: Option<String>,
-    expires_at: Option<chrono::DateTime<chrono::Utc>>,
-}
+const CALENDAR_API: &str = "https://www.googleapis.com/calendar/v3";
+// Events per request. Google can't leave out all-day or timed events, so
+// hidden ones are skipped and further pages fetched, up to MAX_PAGES
+const PAGE_SIZE: usize = 50;
+const MAX_PAGES: usize = 5;
 
 pub struct CalendarProvider {
-    config: Config,
+    config: GoogleConfig,
+    api: String,
     http_client: reqwest::Client,
+    tokens: Arc<TokenManager>,
     calendar_list: Option<CalendarListResponse>,
 }
 
 #[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
 struct EventsResponse {
     items: Vec<GoogleEvent>,
+    next_page_token: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
 struct GoogleEvent {
     id: String,
+    // Left out for events without a title
+    #[serde(default)]
     summary: String,
     start: GoogleEventDateTime,
     end: GoogleEventDateTime,
 }
 
-impl From<GoogleEvent> for Event {
-    fn from(value: GoogleEvent) -> Self {
+impl TryFrom<GoogleEvent> for Event {
+    type Error = ProviderError;
+
+    fn try_from(value: GoogleEvent) -> Result<Self, Self::Error> {
+        let invalid =
+            |what: &str| ProviderError::InvalidData(format!("event {}: {}", value.id, what));
+
         // if there is a date, it is an all-day event
-        let time = if let Some(start_date) = value.start.date {
-            AllDay(chrono::NaiveDate::from_str(&start_date).expect("Could not parse date"))
+        let time = if let Some(start_date) = &value.start.date {
+            AllDay(
+                chrono::NaiveDate::from_str(start_date)
+                    .map_err(|_| invalid("could not parse date"))?,
+            )
         } else {
             let st = chrono::DateTime::parse_from_rfc3339(
                 value
                     .start
                     .date_time
-                    .expect("Timed Event must have start time")
-                    .as_str(),
+                    .as_ref()
+                    .ok_or_else(|| invalid("timed event without start time"))?,
             )
-            .expect("Could not parse event start time");
+            .map_err(|_| invalid("could not parse start time"))?;
             let et = chrono::DateTime::parse_from_rfc3339(
                 value
                     .end
                     .date_time
-                    .expect("Timed Event must have end time")
-                    .as_str(),
+                    .as_ref()
+                    .ok_or_else(|| invalid("timed event without end time"))?,
             )
-            .expect("Could not parse event end time");
+            .map_err(|_| invalid("could not parse end time"))?;
             Timed(chrono::DateTime::from(st), et.signed_duration_since(st))
         };
-        Event {
-            title: value.summary,
-            time,
-        }
+        Ok(Event::new(time, value.summary))
     }
 }
 
@@ -143,267 +93,277 @@ struct CalendarListResponse {
 #[derive(Debug, Deserialize)]
 struct CalendarListEntry {
     id: String,
+    #[serde(default)]
     summary: String,
 }
 
-macro_rules! create_oauth_client {
-    ($self:expr) => {
-        BasicClient::new(ClientId::new($self.config.google.client_id.clone()))
-            .set_client_secret(ClientSecret::new($self.config.google.client_secret.clone()))
-            .set_auth_uri(
-                AuthUrl::new($self.config.google.auth_uri.clone())
-                    .expect("Could not construct auth uri"),
-            )
-            .set_redirect_uri(
-                RedirectUrl::new($self.config.google.redirect_uri.clone())
-                    .expect("Could not construct redirect uri"),
-            )
-            .set_token_uri(
-                TokenUrl::new($self.config.google.token_uri.clone())
-                    .expect("Could not construct token uri"),
-            )
-    };
-}
-
 impl CalendarProvider {
-    pub async fn new(config: Config) -> Self {
-        let cl = CalendarProvider {
+    pub fn new(config: GoogleConfig) -> Self {
+        let http_client = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .expect("HTTP client could not be constructed");
+        CalendarProvider {
+            tokens: TokenManager::new(config.clone(), http_client.clone()),
             config,
-            http_client: reqwest::Client::builder()
-                .redirect(reqwest::redirect::Policy::none())
-                .build()
-                .expect("HTTP client could not be constructed"),
+            api: CALENDAR_API.to_string(),
+            http_client,
             calendar_list: None,
-        };
-        cl.load_or_refresh_token().await;
-        cl
+        }
     }
 
-    async fn authenticate(&self) -> BasicTokenResponse {
-        let client = create_oauth_client!(self);
-
-        let (pkce_code_challenge, pkce_code_verifier) = PkceCodeChallenge::new_random_sha256();
-
-        let (authorize_url, _) = client
-            .authorize_url(CsrfToken::new_random)
-            .add_scope(Scope::new(
-                "https://www.googleapis.com/auth/calendar".to_string(),
-            ))
-            .set_pkce_challenge(pkce_code_challenge)
-            .url();
-
-        println!("Open this URL in your browser:\n{authorize_url}\n");
+    async fn retrieve_calendar_events(&mut self) -> Result<Vec<Event>, ProviderError> {
+        // Only needed to look up calendars by name
+        if self.calendar_list.is_none() && self.config.calendar_list.iter().any(|e| e.id.is_none())
+        {
+            self.fetch_calenders().await?
+        }
 
-        let (code, _) = {
-            // A very naive implementation of the redirect server.
-            let listener = TcpListener::bind("127.0.0.1:8080").unwrap();
+        let mut combined_events: Vec<Event> = vec![];
 
-            // The server will terminate itself after collecting the first code.
-            let Some(mut stream) = listener.incoming().flatten().next() else {
-                panic!("listener terminated without accepting a connection");
+        for entry in &self.config.calendar_list {
+            let id = match (&entry.id, &entry.name) {
+                (Some(id), _) => id.clone(),
+                (None, Some(name)) => {
+                    let clr = self.calendar_list.as_ref().ok_or_else(|| {
+                        ProviderError::InvalidData("calendar list missing".to_string())
+                    })?;
+                    let Some(cal) = clr.items.iter().find(|cal| cal.summary == *name) else {
+                        warn!("No calendar named {}", name);
+                        continue;
+                    };
+                    cal.id.clone()
+                }
+                (None, None) => continue,
             };
+            let events = self.fetch_events_for_calendar(&id, &entry.display).await?;
+            combined_events.append(&mut entry.display.apply(events));
+        }
 
-            let mut reader = BufReader::new(&stream);
-
-            let mut request_line = String::new();
-            reader.read_line(&mut request_line).unwrap();
-
-            let redirect_url = request_line.split_whitespace().nth(1).unwrap();
-            let url = Url::parse(&("http://localhost".to_string() + redirect_url)).unwrap();
-
-            let code = url
-                .query_pairs()
-                .find(|(key, _)| key == "code")
-                .map(|(_, code)| AuthorizationCode::new(code.into_owned()))
-                .unwrap();
-
-            let state = url
-                .query_pairs()
-                .find(|(key, _)| key == "state")
-                .map(|(_, state)| CsrfToken::new(state.into_owned()))
-                .unwrap();
-
-            let message = "Authentication successful. You can safely go back to your terminal :^)";
-            let response = format!(
-                "HTTP/1.1 200 OK\r\ncontent-length: {}\r\n\r\n{}",
-                message.len(),
-                message
-            );
-            stream.write_all(response.as_bytes()).unwrap();
-
-            (code, state)
-        };
+        combined_events.sort_by(|f, s| f.time.cmp(&s.time));
 
-        // Exchange the code with a token.
-        client
-            .exchange_code(code)
-            .set_pkce_verifier(pkce_code_verifier)
-            .request_async(&self.http_client)
-            .await
-            .expect("Could not get token response")
+        Ok(combined_events)
     }
 
-    async fn load_or_refresh_token(&self) -> String {
-        if Path::new(&self.config.google.token_path).exists() {
-            let token_str = fs::read_to_string(&self.config.google.token_path).expect("");
-            let stored_token: StoredToken = serde_json::from_str(&token_str).expect("");
-
-            let now = chrono::Utc::now();
-            let is_expired = match stored_token.expires_at {
-                Some(expiry) => now >= expiry,
-                None => true,
-            };
-
-            if is_expired {
-                if let Some(refresh_token_str) = &stored_token.refresh_token {
-                    debug!("Access token expired. Refreshing...");
-
-                    let refresh_token = RefreshToken::new(refresh_token_str.clone());
-                    let oauth_client = create_oauth_client!(self);
-                    let token_response = oauth_client
-                        .exchange_refresh_token(&refresh_token)
-                        .request_async(&self.http_client)
-                        .await
-                        .expect("Could not exchange refresh token");
-                    self.store_token(&token_response, Some(&stored_token));
-                    token_response.access_token().secret().clone()
-                } else {
-                    warn!("No refresh token in file. Authenticating...");
-                    let tok = self.authenticate().await;
-                    self.store_token(&tok, Some(&stored_token));
-                    tok.access_token().secret().clone()
+    // At least as many shown events as display allows, unless there aren't
+    async fn fetch_events_for_calendar(
+        &self,
+        cal_id: &str,
+        display: &CalendarDisplay,
+    ) -> Result<Vec<Event>, ProviderError> {
+        // Ids contain # and @, e.g. en.german#holiday@group.v.calendar.google.com
+        let mut events_url =
+            Url::parse(&format!("{}/calendars", self.api)).expect("Events url is valid");
+        events_url
+            .path_segments_mut()
+            .expect("Events url has a path")
+            .push(cal_id)
+            .push("events");
+
+        let max_events = display.max_events.unwrap_or(MAX_EVENTS);
+        let time_min = chrono::Local::now().to_utc().to_rfc3339();
+        let mut events: Vec<Event> = vec![];
+        let mut page_token = None;
+        for _ in 0..MAX_PAGES {
+            let mut query = vec![
+                ("timeMin", time_min.clone()),
+                ("singleEvents", "true".to_string()),
+                ("orderBy", "startTime".to_string()),
+                ("maxResults", PAGE_SIZE.to_string()),
+            ];
+            if let Some(token) = page_token {
+                query.push(("pageToken", token));
+            }
+            let gevents = self
+                .http_client
+                .get(events_url.clone())
+                .header(
+                    reqwest::header::AUTHORIZATION,
+                    format!("Bearer {}", self.tokens.access_token().await?),
+                )
+                .query(&query)
+                .send()
+                .await?
+                .error_for_status()?
+                .json::<EventsResponse>()
+                .await?;
+
+            for gevent in gevents.items {
+                let event = match Event::try_from(gevent) {
+                    Ok(event) => event,
+                    Err(e) => {
+                        warn!("Skipping event in {}: {}", cal_id, e);
+                        continue;
+                    }
+                };
+                if display.shows(&event) {
+                    events.push(event);
                 }
-            } else {
-                debug!("Using existing token");
-                stored_token.access_token
             }
-        } else {
-            debug!("Found no token file, authenticating");
-            let tok = self.authenticate().await;
-            self.store_token(&tok, None);
-            tok.access_token().secret().clone()
-        }
-    }
-
-    fn store_token(&self, token_response: &BasicTokenResponse, previous: Option<&StoredToken>) {
-        let refresh_token: Option<&String> = if let Some(refresh) = token_response.refresh_token() {
-            Some(refresh.secret())
-        } else if let Some(prev) = previous {
-            if let Some(refresh) = &prev.refresh_token {
-                Some(refresh)
-            } else {
-                None
+            page_token = gevents.next_page_token;
+            if events.len() >= max_events || page_token.is_none() {
+                break;
             }
-        } else {
-            None
-        };
-
-        let stored_token = StoredToken {
-            access_token: token_response.access_token().secret().to_string(),
-            refresh_token: refresh_token.cloned(),
-            expires_at: token_response
-                .expires_in()
-                .map(|d| chrono::Utc::now().add(d)),
-        };
-        fs::write(
-            Path::new(&self.config.google.token_path),
-            serde_json::to_string_pretty(&stored_token).expect("Could not prettify token"),
-        )
-        .expect("Could not store token");
-    }
-
-    async fn retrieve_calendar_events(&mut self) -> Vec<Event> {
-        if self.calendar_list.is_none() {
-            self.fetch_calenders().await
         }
-
-        let clr = self.calendar_list.as_ref().expect("Calendar list unset");
-
-        let mut combined_events: Vec<Event> = vec![];
-
-        for cal in clr
-            .items
-            .iter()
-            .filter(|cal| self.config.google.calendar_list.contains(&cal.summary))
-        {
-            let id: &String = &cal.id;
-            for event in self.fetch_events_for_calendar(id).await {
-                combined_events.push(event);
-            }
-        }
-
-        combined_events.sort_by(|f, s| f.time.cmp(&s.time));
-
-        combined_events
+        Ok(events)
     }
 
-    async fn fetch_events_for_calendar(&self, cal_id: &str) -> Vec<Event> {
-        let events_url = format!(
-            "https://www.googleapis.com/calendar/v3/calendars/{}/events",
-            cal_id
-        );
-
-        let gevents = self
+    async fn fetch_calenders(&mut self) -> Result<(), ProviderError> {
+        let calenders = self
             .http_client
-            .get(events_url)
+            .get(format!("{}/users/me/calendarList", self.api))
             .header(
                 reqwest::header::AUTHORIZATION,
-                format!("Bearer {}", self.load_or_refresh_token().await),
+                format!("Bearer {}", self.tokens.access_token().await?),
             )
-            .query(&[
-                (
-                    "timeMin",
-                    chrono::Local::now().to_utc().to_rfc3339().as_str(),
-                ),
-                ("singleEvents", "true"),
-                ("orderBy", "startTime"),
-                ("maxResults", "10"),
-            ])
             .send()
-            .await
-            .expect("Could not send list calendar request");
+            .await?
+            .error_for_status()?;
 
-        let gevents = gevents
-            .json::<EventsResponse>()
-            .await
-            .unwrap_or_else(|_| panic!("Could not deserialize events response, cal: {}", cal_id));
+        let clr = calenders.json::<CalendarListResponse>().await?;
+        self.calendar_list = Some(clr);
+        Ok(())
+    }
 
-        let mut events: Vec<Event> = vec![];
-        for gevent in gevents.items {
-            events.push(Event::from(gevent));
-        }
-        events
+    pub async fn fetch(&mut self) -> Result<Vec<Event>, ProviderError> {
+        self.retrieve_calendar_events().await
     }
+}
 
-    async fn fetch_calenders(&mut self) {
-        const LIST_CALENDARS: &str = "https://www.googleapis.com/calendar/v3/users/me/calendarList";
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::{AuthFlow, CalendarEntry};
+    use axum::Router;
+    use axum::extract::Query;
+    use axum::routing::get;
+    use serde_json::{Value, json};
+    use std::collections::HashMap;
+
+    // Every page starts with a timed event and ends with an all-day one
+    async fn events(Query(query): Query<HashMap<String, String>>) -> axum::Json<Value> {
+        let page: usize = query
+            .get("pageToken")
+            .map_or(0, |token| token.parse().unwrap());
+        let event = |n: usize, start: Value| json!({"id": n.to_string(), "summary": format!("{page}-{n}"), "start": start, "end": start});
+        let day = format!("2025-03-{:02}", page + 10);
+        axum::Json(json!({
+            "items": [
+                event(0, json!({"dateTime": format!("{day}T09:00:00Z")})),
+                event(1, json!({"date": day})),
+            ],
+            "nextPageToken": (page < 9).then(|| (page + 1).to_string()),
+        }))
+    }
 
-        let calenders = self
-            .http_client
-            .get(LIST_CALENDARS)
-            .header(
-                reqwest::header::AUTHORIZATION,
-                format!("Bearer {}", self.load_or_refresh_token().await),
-            )
-            .send()
+    // Only the last one is complete
+    async fn broken_events() -> axum::Json<Value> {
+        axum::Json(json!({
+            "items": [
+                {"id": "0", "start": {"date": "2025-03-10"}, "end": {"date": "2025-03-11"}},
+                {"id": "1", "summary": "no end", "start": {"dateTime": "2025-03-10T09:00:00Z"}, "end": {}},
+                {"id": "2", "summary": "bad date", "start": {"date": "March"}, "end": {"date": "April"}},
+                {"id": "3", "summary": "fine", "start": {"date": "2025-03-12"}, "end": {"date": "2025-03-13"}},
+            ],
+        }))
+    }
+
+    async fn provider() -> CalendarProvider {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = Router::new()
+            .route("/calendars/{id}/events", get(events))
+            .route("/calendars/broken/events", get(broken_events))
+            .route(
+                "/users/me/calendarList",
+                get(|| async {
+                    axum::Json(json!({"items": [{"id": "broken", "summary": "Holidays"}]}))
+                }),
+            );
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let dir = std::env::temp_dir().join(format!("igen-google-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let token_path = dir.join("token.json");
+        std::fs::write(
+            &token_path,
+            json!({"access_token": "t", "expires_at": "2999-01-01T00:00:00Z"}).to_string(),
+        )
+        .unwrap();
+        let config = GoogleConfig {
+            token_path: token_path.to_string_lossy().into_owned(),
+            client_id: String::new(),
+            client_secret: String::new(),
+            auth_flow: AuthFlow::Loopback,
+            auth_uri: String::new(),
+            device_auth_uri: String::new(),
+            redirect_uri: String::new(),
+            token_uri: String::new(),
+            calendar_list: vec![],
+        };
+        let mut provider = CalendarProvider::new(config);
+        provider.api = format!("http://{addr}");
+        provider
+    }
+
+    #[tokio::test]
+    async fn filters_before_truncating() {
+        let provider = provider().await;
+        let titles = |events: Vec<Event>| events.into_iter().map(|e| e.title).collect::<Vec<_>>();
+
+        // Only every other event is shown, so it takes three pages
+        let display = CalendarDisplay {
+            hide_timed: true,
+            max_events: Some(3),
+            ..CalendarDisplay::default()
+        };
+        let events = provider
+            .fetch_events_for_calendar("a#b@c", &display)
             .await
-            .expect("Could not send list calendar request");
+            .unwrap();
+        assert_eq!(titles(display.apply(events)), ["0-1", "1-1", "2-1"]);
+
+        // Gives up after MAX_PAGES
+        let display = CalendarDisplay {
+            hide_all_day: true,
+            max_events: Some(20),
+            ..CalendarDisplay::default()
+        };
+        let events = provider
+            .fetch_events_for_calendar("a#b@c", &display)
+            .await
+            .unwrap();
+        assert_eq!(events.len(), MAX_PAGES);
+    }
 
-        let clr = calenders
-            .json::<CalendarListResponse>()
+    #[tokio::test]
+    async fn skips_malformed_events() {
+        let provider = provider().await;
+        let events = provider
+            .fetch_events_for_calendar("broken", &CalendarDisplay::default())
             .await
-            .expect("Could not deserialize calendars to json");
-        self.calendar_list = Some(clr)
+            .unwrap();
+        let titles: Vec<_> = events.into_iter().map(|e| e.title).collect();
+        assert_eq!(titles, ["", "fine"]);
     }
 
-    pub async fn fetch(&mut self) -> Vec<Event> {
-        if self.config.general.debug {
-            vec![Event {
-                time: AllDay(chrono::Local::now().date_naive()),
-                title: "hehe".to_string(),
-            }]
-        } else {
-            self.retrieve_calendar_events().await
-        }
+    #[tokio::test]
+    async fn looks_up_calendars_by_name() {
+        let mut provider = provider().await;
+        provider.config.calendar_list = ["Holidays", "Missing"]
+            .map(|name| CalendarEntry {
+                id: None,
+                name: Some(name.to_string()),
+                display: CalendarDisplay::default(),
+            })
+            .to_vec();
+        let titles: Vec<_> = provider
+            .fetch()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|e| e.title)
+            .collect();
+        assert_eq!(titles, ["", "fine"]);
     }
 }
@@ -0,0 +1,595 @@
+// iCalendar (RFC 5545) as served by .ics subscriptions and CalDAV servers,
+// boiled down to the VEVENTs overlapping a time window.
+//
+// TZID has to be an IANA name (what everything but Outlook writes), VTIMEZONE
+// definitions are not read. Unknown zones fall back to local time.
+
+use crate::provider::ProviderError;
+use crate::provider::calendar::{Event, Time};
+use crate::provider::rrule::{RRule, Until};
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, NaiveTime, TimeDelta, TimeZone, Utc};
+use chrono_tz::Tz;
+use log::warn;
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Copy)]
+enum Zone {
+    Utc,
+    Named(Tz),
+    // No zone given, the same wall clock time everywhere
+    Floating,
+}
+
+impl Zone {
+    fn resolve(self, time: NaiveDateTime) -> DateTime<Local> {
+        // Times skipped by a DST change are moved past it
+        fn earliest<T: TimeZone>(tz: &T, time: NaiveDateTime) -> Option<DateTime<Local>> {
+            tz.from_local_datetime(&time)
+                .earliest()
+                .or_else(|| {
+                    tz.from_local_datetime(&(time + TimeDelta::hours(1)))
+                        .earliest()
+                })
+                .map(|t| t.with_timezone(&Local))
+        }
+        match self {
+            Zone::Utc => None,
+            Zone::Named(tz) => earliest(&tz, time),
+            Zone::Floating => earliest(&Local, time),
+        }
+        .unwrap_or_else(|| Utc.from_utc_datetime(&time).with_timezone(&Local))
+    }
+
+    fn wall(self, at: DateTime<Utc>) -> NaiveDateTime {
+        match self {
+            Zone::Utc => at.naive_utc(),
+            Zone::Named(tz) => at.with_timezone(&tz).naive_local(),
+            Zone::Floating => at.with_timezone(&Local).naive_local(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Moment {
+    Date(NaiveDate),
+    DateTime(NaiveDateTime, Zone),
+}
+
+impl Moment {
+    fn wall(self) -> NaiveDateTime {
+        match self {
+            Moment::Date(date) => date.and_time(NaiveTime::MIN),
+            Moment::DateTime(time, _) => time,
+        }
+    }
+
+    fn zone(self) -> Zone {
+        match self {
+            Moment::Date(_) => Zone::Floating,
+            Moment::DateTime(_, zone) => zone,
+        }
+    }
+
+    fn resolve(self) -> DateTime<Local> {
+        self.zone().resolve(self.wall())
+    }
+
+    // Same kind and zone, at another wall clock time
+    fn with_wall(self, time: NaiveDateTime) -> Moment {
+        match self {
+            Moment::Date(_) => Moment::Date(time.date()),
+            Moment::DateTime(_, zone) => Moment::DateTime(time, zone),
+        }
+    }
+}
+
+struct Property {
+    name: String,
+    params: Vec<(String, String)>,
+    value: String,
+}
+
+impl Property {
+    fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+fn invalid(msg: String) -> ProviderError {
+    ProviderError::InvalidData(msg)
+}
+
+// Long lines are continued on the next one, indented by a single space or tab
+fn unfold(ics: &str) -> Vec<String> {
+    let mut lines: Vec<String> = vec![];
+    for line in ics.lines() {
+        match (line.strip_prefix([' ', '\t']), lines.last_mut()) {
+            (Some(continued), Some(last)) => last.push_str(continued),
+            _ => lines.push(line.to_string()),
+        }
+    }
+    lines
+}
+
+// NAME;PARAM=a;PARAM="b;c":value
+fn parse_property(line: &str) -> Option<Property> {
+    let mut quoted = false;
+    let mut parts = vec![];
+    let mut last = 0;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ';' if !quoted => {
+                parts.push(&line[last..i]);
+                last = i + 1;
+            }
+            ':' if !quoted => {
+                parts.push(&line[last..i]);
+                let mut parts = parts.into_iter();
+                let name = parts.next()?.to_ascii_uppercase();
+                let params = parts
+                    .filter_map(|p| p.split_once('='))
+                    .map(|(key, value)| {
+                        (
+                            key.to_ascii_uppercase(),
+                            value.trim_matches('"').to_string(),
+                        )
+                    })
+                    .collect();
+                return Some(Property {
+                    name,
+                    params,
+                    value: line[i + 1..].to_string(),
+                });
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+// Newlines become spaces, titles are rendered on a single line
+fn unescape(text: &str) -> String {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match (c, c == '\\') {
+            (_, true) => match chars.next() {
+                Some('n' | 'N') => unescaped.push(' '),
+                Some(other) => unescaped.push(other),
+                None => {}
+            },
+            (c, false) => unescaped.push(c),
+        }
+    }
+    unescaped
+}
+
+fn parse_moment(value: &str, prop: &Property) -> Result<Moment, ProviderError> {
+    let value = value.trim();
+    let bad = || invalid(format!("{} has an invalid time: {}", prop.name, value));
+    if prop.param("VALUE") == Some("DATE") || value.len() == 8 {
+        return NaiveDate::parse_from_str(value, "%Y%m%d")
+            .map(Moment::Date)
+            .map_err(|_| bad());
+    }
+    let (time, utc) = match value.strip_suffix('Z') {
+        Some(time) => (time, true),
+        None => (value, false),
+    };
+    let time = NaiveDateTime::parse_from_str(time, "%Y%m%dT%H%M%S").map_err(|_| bad())?;
+    let zone = match (utc, prop.param("TZID")) {
+        (true, _) => Zone::Utc,
+        (false, Some(id)) => match id.trim_start_matches('/').parse::<Tz>() {
+            Ok(tz) => Zone::Named(tz),
+            Err(_) => {
+                warn!("Unknown time zone {}, using local time", id);
+                Zone::Floating
+            }
+        },
+        (false, None) => Zone::Floating,
+    };
+    Ok(Moment::DateTime(time, zone))
+}
+
+// e.g. P1D, PT1H30M, -P2W
+fn parse_duration(value: &str) -> Option<TimeDelta> {
+    let (sign, rest) = match value.strip_prefix('-') {
+        Some(rest) => (-1, rest),
+        None => (1, value.strip_prefix('+').unwrap_or(value)),
+    };
+    let mut total = TimeDelta::zero();
+    let mut number = String::new();
+    for c in rest.strip_prefix('P')?.chars() {
+        match c {
+            '0'..='9' => number.push(c),
+            'T' => {}
+            unit => {
+                let n: i64 = number.parse().ok()?;
+                number.clear();
+                total += match unit {
+                    'W' => TimeDelta::weeks(n),
+                    'D' => TimeDelta::days(n),
+                    'H' => TimeDelta::hours(n),
+                    'M' => TimeDelta::minutes(n),
+                    'S' => TimeDelta::seconds(n),
+                    _ => return None,
+                };
+            }
+        }
+    }
+    number.is_empty().then_some(total * sign)
+}
+
+#[derive(Default)]
+struct RawEvent {
+    uid: Option<String>,
+    summary: Option<String>,
+    start: Option<Moment>,
+    end: Option<Moment>,
+    duration: Option<TimeDelta>,
+    rrule: Option<String>,
+    exdates: Vec<Moment>,
+    // Set on single instances of a series that were moved or changed
+    recurrence_id: Option<Moment>,
+    cancelled: bool,
+    // First thing about it we couldn't parse, such events are skipped
+    error: Option<String>,
+}
+
+// Events with anything we can't parse are left out, one broken event
+// shouldn't take the rest of the calendar with it
+fn read_events(ics: &str) -> Vec<RawEvent> {
+    let mut events = vec![];
+    let mut current: Option<RawEvent> = None;
+    // Components within the current VEVENT, e.g. VALARM
+    let mut nested = 0;
+    for line in unfold(ics) {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let Some(prop) = parse_property(&line) else {
+            match current.as_mut() {
+                Some(event) => {
+                    event
+                        .error
+                        .get_or_insert_with(|| format!("malformed line: {line}"));
+                }
+                None => warn!("Skipping malformed calendar line: {}", line),
+            }
+            continue;
+        };
+        let Some(event) = current.as_mut() else {
+            if prop.name == "BEGIN" && prop.value.eq_ignore_ascii_case("VEVENT") {
+                current = Some(RawEvent::default());
+            }
+            continue;
+        };
+        let parsed = match prop.name.as_str() {
+            "BEGIN" => {
+                nested += 1;
+                Ok(())
+            }
+            "END" if nested > 0 => {
+                nested -= 1;
+                Ok(())
+            }
+            "END" => {
+                let event = current.take().unwrap();
+                match &event.error {
+                    Some(error) => warn!(
+                        "Skipping event {}: {}",
+                        event.uid.as_deref().unwrap_or_default(),
+                        error
+                    ),
+                    None => events.push(event),
+                }
+                continue;
+            }
+            _ if nested > 0 => Ok(()),
+            "UID" => {
+                event.uid = Some(prop.value);
+                Ok(())
+            }
+            "SUMMARY" => {
+                event.summary = Some(unescape(&prop.value));
+                Ok(())
+            }
+            "DTSTART" => parse_moment(&prop.value, &prop).map(|m| event.start = Some(m)),
+            "DTEND" => parse_moment(&prop.value, &prop).map(|m| event.end = Some(m)),
+            "DURATION" => parse_duration(&prop.value)
+                .map(|d| event.duration = Some(d))
+                .ok_or_else(|| invalid(format!("invalid duration: {}", prop.value))),
+            "RRULE" => {
+                event.rrule = Some(prop.value);
+                Ok(())
+            }
+            "EXDATE" => prop
+                .value
+                .split(',')
+                .map(|value| parse_moment(value, &prop))
+                .collect::<Result<Vec<_>, _>>()
+                .map(|dates| event.exdates.extend(dates)),
+            "RECURRENCE-ID" => {
+                parse_moment(&prop.value, &prop).map(|m| event.recurrence_id = Some(m))
+            }
+            "STATUS" => {
+                event.cancelled = prop.value.eq_ignore_ascii_case("CANCELLED");
+                Ok(())
+            }
+            _ => Ok(()),
+        };
+        if let Err(e) = parsed {
+            event.error.get_or_insert_with(|| e.to_string());
+        }
+    }
+    events
+}
+
+// Every event overlapping from..to, recurring ones once per occurrence
+pub fn events_between(ics: &str, from: DateTime<Local>, to: DateTime<Local>) -> Vec<Event> {
+    let raw = read_events(ics);
+
+    // Instances of a series that got a VEVENT of their own, by UID
+    let mut overridden: HashMap<&str, Vec<DateTime<Local>>> = HashMap::new();
+    for event in &raw {
+        if let (Some(uid), Some(id)) = (&event.uid, event.recurrence_id) {
+            overridden.entry(uid).or_default().push(id.resolve());
+        }
+    }
+
+    let mut events = vec![];
+    for event in raw.iter().filter(|e| !e.cancelled) {
+        let uid = event.uid.as_deref().unwrap_or_default();
+        let title = event
+            .summary
+            .clone()
+            .unwrap_or_else(|| "Untitled".to_string());
+        let Some(start) = event.start else {
+            warn!("Skipping event {} without start", uid);
+            continue;
+        };
+        let length = match (start, event.end, event.duration) {
+            (_, Some(end), _) => end.resolve() - start.resolve(),
+            (_, None, Some(duration)) => duration,
+            (Moment::Date(_), None, None) => TimeDelta::days(1),
+            (Moment::DateTime(..), None, None) => TimeDelta::zero(),
+        };
+
+        let mut skipped: Vec<DateTime<Local>> = event.exdates.iter().map(|m| m.resolve()).collect();
+        let instances = match (&event.rrule, event.recurrence_id) {
+            (Some(rule), None) => {
+                skipped.extend(overridden.get(uid).into_iter().flatten());
+                match rule.parse::<RRule>() {
+                    Ok(rule) => {
+                        let until = rule.until.and_then(|until| match until {
+                            Until::Date(date) => date.and_hms_opt(23, 59, 59),
+                            Until::Floating(time) => Some(time),
+                            Until::Utc(time) => Some(start.zone().wall(time)),
+                        });
+                        let end = start.zone().wall(to.to_utc());
+                        rule.occurrences(start.wall(), until, end)
+                            .into_iter()
+                            .map(|time| start.with_wall(time))
+                            .collect()
+                    }
+                    Err(e) => {
+                        warn!("Ignoring recurrence of {}: {}", title, e);
+                        vec![start]
+                    }
+                }
+            }
+            _ => vec![start],
+        };
+
+        for instance in instances {
+            let begin = instance.resolve();
+            if skipped.contains(&begin) || begin >= to || (begin < from && begin + length <= from) {
+                continue;
+            }
+            let time = match instance {
+                Moment::Date(date) => Time::AllDay(date),
+                Moment::DateTime(..) => Time::Timed(begin, length),
+            };
+            events.push(Event::new(time, title.clone()));
+        }
+    }
+    events
+}
+
+pub struct IcsProvider {
+    // Path or url
+    source: String,
+    http_client: reqwest::Client,
+}
+
+impl IcsProvider {
+    pub fn new(source: String) -> IcsProvider {
+        IcsProvider {
+            source,
+            http_client: reqwest::Client::new(),
+        }
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    async fn load(&self) -> Result<String, ProviderError> {
+        // webcal is plain https by convention
+        let url = match self.source.strip_prefix("webcal://") {
+            Some(rest) => format!("https://{rest}"),
+            None => self.source.clone(),
+        };
+        if url.starts_with("http://") || url.starts_with("https://") {
+            Ok(self
+                .http_client
+                .get(url)
+                .send()
+                .await?
+                .error_for_status()?
+                .text()
+                .await?)
+        } else {
+            Ok(tokio::fs::read_to_string(&self.source).await?)
+        }
+    }
+
+    pub async fn fetch(
+        &self,
+        from: DateTime<Local>,
+        to: DateTime<Local>,
+    ) -> Result<Vec<Event>, ProviderError> {
+        Ok(events_between(&self.load().await?, from, to))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::Router;
+    use axum::routing::get;
+
+    const CALENDAR: &str = "BEGIN:VCALENDAR\r
+VERSION:2.0\r
+BEGIN:VEVENT\r
+UID:standup\r
+SUMMARY:Stand\r
+  up\\, daily\r
+DTSTART;TZID=Europe/Berlin:20250303T090000\r
+DURATION:PT15M\r
+RRULE:FREQ=WEEKLY;BYDAY=MO,WE\r
+EXDATE;TZID=Europe/Berlin:20250312T090000\r
+BEGIN:VALARM\r
+TRIGGER:-PT5M\r
+DESCRIPTION:Not an event\r
+END:VALARM\r
+END:VEVENT\r
+BEGIN:VEVENT\r
+UID:standup\r
+RECURRENCE-ID;TZID=Europe/Berlin:20250317T090000\r
+SUMMARY:Moved standup\r
+DTSTART:20250317T120000Z\r
+DTEND:20250317T121500Z\r
+END:VEVENT\r
+BEGIN:VEVENT\r
+UID:trip\r
+SUMMARY:Trip\r
+DTSTART;VALUE=DATE:20250314\r
+DTEND;VALUE=DATE:20250317\r
+END:VEVENT\r
+BEGIN:VEVENT\r
+UID:gone\r
+SUMMARY:Cancelled\r
+STATUS:CANCELLED\r
+DTSTART:20250315T100000Z\r
+END:VEVENT\r
+BEGIN:VEVENT\r
+UID:no-start\r
+SUMMARY:Broken\r
+END:VEVENT\r
+BEGIN:VEVENT\r
+UID:bad-end\r
+SUMMARY:Broken\r
+DTSTART:20250315T100000Z\r
+DTEND:tomorrow\r
+END:VEVENT\r
+BEGIN:VEVENT\r
+UID:bad-exdate\r
+SUMMARY:Broken\r
+DTSTART:20250315T100000Z\r
+RRULE:FREQ=DAILY\r
+EXDATE:20250316T100000Z,soon\r
+END:VEVENT\r
+BEGIN:VEVENT\r
+UID:bad-duration\r
+SUMMARY:Broken\r
+DTSTART:20250315T100000Z\r
+DURATION:an hour\r
+END:VEVENT\r
+BEGIN:VEVENT\r
+UID:bad-line\r
+SUMMARY:Broken\r
+DTSTART:20250315T100000Z\r
+no colon here\r
+END:VEVENT\r
+END:VCALENDAR\r
+";
+
+    fn utc(d: u32, h: u32, m: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2025, 3, d, h, m, 0).unwrap()
+    }
+
+    fn window() -> (DateTime<Local>, DateTime<Local>) {
+        (utc(10, 0, 0).into(), utc(20, 0, 0).into())
+    }
+
+    fn check(events: &[Event]) {
+        let mut starts: Vec<_> = events
+            .iter()
+            .filter_map(|e| match e.time {
+                Time::Timed(start, length) => Some((start.to_utc(), length, e.title.as_str())),
+                Time::AllDay(_) => None,
+            })
+            .collect();
+        starts.sort();
+        let quarter = TimeDelta::minutes(15);
+        // CET, 12th excluded, 17th moved, the broken ones skipped
+        assert_eq!(
+            starts,
+            [
+                (utc(10, 8, 0), quarter, "Stand up, daily"),
+                (utc(17, 12, 0), quarter, "Moved standup"),
+                (utc(19, 8, 0), quarter, "Stand up, daily"),
+            ]
+        );
+        let all_day: Vec<_> = events
+            .iter()
+            .filter(|e| matches!(e.time, Time::AllDay(_)))
+            .collect();
+        assert_eq!(all_day.len(), 1);
+        assert_eq!(
+            all_day[0].time,
+            Time::AllDay(NaiveDate::from_ymd_opt(2025, 3, 14).unwrap())
+        );
+    }
+
+    #[test]
+    fn expands_series_with_exceptions() {
+        let (from, to) = window();
+        check(&events_between(CALENDAR, from, to));
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("PT1H30M"), Some(TimeDelta::minutes(90)));
+        assert_eq!(parse_duration("-P1W2D"), Some(TimeDelta::days(-9)));
+        assert_eq!(parse_duration("P1Y"), None);
+        assert_eq!(parse_duration("PT"), Some(TimeDelta::zero()));
+    }
+
+    #[tokio::test]
+    async fn loads_files_and_urls() {
+        let (from, to) = window();
+
+        let path = std::env::temp_dir().join(format!("igen-test-{}.ics", std::process::id()));
+        std::fs::write(&path, CALENDAR).unwrap();
+        let events = IcsProvider::new(path.to_string_lossy().into_owned())
+            .fetch(from, to)
+            .await;
+        std::fs::remove_file(&path).unwrap();
+        check(&events.unwrap());
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = Router::new().route("/cal.ics", get(|| async { CALENDAR }));
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        let provider = IcsProvider::new(format!("http://{addr}/cal.ics"));
+        check(&provider.fetch(from, to).await.unwrap());
+        let missing = IcsProvider::new(format!("http://{addr}/missing.ics"));
+        assert!(missing.fetch(from, to).await.is_err());
+    }
+}
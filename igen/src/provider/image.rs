@@ -1,61 +1,266 @@
+use crate::provider::ProviderError;
+use crate::provider::rotation::Rotation;
+use crate::render::dither::Dither;
+use crate::render::fit::{Fit, FocalPoint};
 use crate::settings::Config;
-use chrono::{DateTime, Duration, Utc};
 use image::DynamicImage;
-use std::collections::VecDeque;
+use log::warn;
+use serde::{Deserialize, Deserializer, Serialize};
 use std::fs;
 use std::path::Path;
 
+// An images.json entry, either just the path or an object with per-image options
+#[derive(Serialize, Debug, Clone)]
+pub struct ImageEntry {
+    pub path: String,
+    // Both fall back to their [image] counterparts
+    pub dither: Option<Dither>,
+    pub fit: Option<Fit>,
+    // Only matters when cropping
+    pub focal_point: FocalPoint,
+    // Clockwise in degrees, applied before fitting
+    pub rotate: u16,
+}
+
+impl ImageEntry {
+    pub fn from_path(path: String) -> Self {
+        ImageEntry {
+            path,
+            dither: None,
+            fit: None,
+            focal_point: FocalPoint::default(),
+            rotate: 0,
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for ImageEntry {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Path(String),
+            Detailed {
+                path: String,
+                #[serde(default)]
+                dither: Option<Dither>,
+                #[serde(default)]
+                fit: Option<Fit>,
+                #[serde(default)]
+                focal_point: FocalPoint,
+                #[serde(default)]
+                rotate: u16,
+            },
+        }
+
+        Ok(match Repr::deserialize(deserializer)? {
+            Repr::Path(path) => ImageEntry::from_path(path),
+            Repr::Detailed {
+                path,
+                dither,
+                fit,
+                focal_point,
+                rotate,
+            } => {
+                if !matches!(rotate, 0 | 90 | 180 | 270) {
+                    return Err(serde::de::Error::custom(format!(
+                        "{path}: rotate has to be 0, 90, 180 or 270, not {rotate}"
+                    )));
+                }
+                ImageEntry {
+                    path,
+                    dither,
+                    fit,
+                    focal_point,
+                    rotate,
+                }
+            }
+        })
+    }
+}
+
 pub struct ImageProvider {
     config: Config,
-    image_paths: VecDeque<String>,
-    cache: Option<(DateTime<Utc>, DynamicImage)>,
+    images: Vec<ImageEntry>,
+    rotation: Rotation,
 }
 
 impl ImageProvider {
+    fn load_list(path: &str) -> Result<Vec<ImageEntry>, ProviderError> {
+        Ok(serde_json::from_str(fs::read_to_string(path)?.as_str())?)
+    }
+
+    // Recursively, sorted so the order (and with it the rotation) is stable
+    fn scan_directory(&self, dir: &Path, found: &mut Vec<String>) {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("Could not scan image directory {:?}: {}", dir, e);
+                return;
+            }
+        };
+        let mut paths: Vec<_> = entries.filter_map(|e| e.ok()).collect();
+        paths.sort_by_key(|e| e.path());
+
+        for entry in paths {
+            let path = entry.path();
+            match entry.file_type() {
+                Ok(t) if t.is_dir() => self.scan_directory(&path, found),
+                Ok(_) => {
+                    let wanted = path
+                        .extension()
+                        .and_then(|ext| ext.to_str())
+                        .is_some_and(|ext| {
+                            self.config
+                                .image
+                                .extensions
+                                .iter()
+                                .any(|allowed| allowed.eq_ignore_ascii_case(ext))
+                        });
+                    if wanted && let Some(path) = path.to_str() {
+                        found.push(path.to_string());
+                    }
+                }
+                Err(e) => warn!("Skipping {:?}: {}", path, e),
+            }
+        }
+    }
+
     fn load_images(&mut self) {
-        let image_paths: Vec<String> = serde_json::from_str(
-            fs::read_to_string(&self.config.image.images_path)
-                .expect("Could not read image json")
-                .as_str(),
-        )
-        .expect("Could not deserialize image json");
-
-        image_paths
+        let mut images = vec![];
+        if let Some(list) = &self.config.image.images_path {
+            match Self::load_list(list) {
+                Ok(listed) => images.extend(listed),
+                Err(e) => warn!("Could not read image list {}: {}", list, e),
+            }
+        }
+        for dir in self.config.image.directories.iter() {
+            let mut found = vec![];
+            self.scan_directory(Path::new(dir), &mut found);
+            images.extend(found.into_iter().map(ImageEntry::from_path));
+        }
+
+        let (images, missing): (Vec<ImageEntry>, Vec<ImageEntry>) = images
+            .into_iter()
+            .partition(|ie| Path::new(&ie.path).exists());
+        missing
             .iter()
-            .for_each(|ip| assert!(Path::new(ip).exists(), "Image {} doesn't exist", ip));
+            .for_each(|ie| warn!("Image {} doesn't exist, skipping it", ie.path));
 
-        self.image_paths = VecDeque::from(image_paths);
+        self.images = images;
     }
 
     pub fn new(config: Config) -> Self {
         ImageProvider {
+            rotation: Rotation::open(
+                &config.cache.dir,
+                "image",
+                config.image.shuffle,
+                config.image.seed,
+            ),
             config,
-            image_paths: VecDeque::new(),
-            cache: None,
+            images: vec![],
         }
     }
 
-    pub fn get_image(&mut self) -> DynamicImage {
-        if let Some((last_refresh, last_image)) = self.cache.as_ref() {
-            if Utc::now().signed_duration_since(last_refresh) > Duration::minutes(60) {
-            } else {
-                return last_image.clone();
-            }
-        }
+    // Next image in the rotation that can actually be decoded, along with the
+    // entry it was loaded from. The list and directories are read every time,
+    // so new files show up right away.
+    pub fn get_image(&mut self) -> Result<(ImageEntry, DynamicImage), ProviderError> {
+        self.load_images();
+        let keys: Vec<&str> = self.images.iter().map(|ie| ie.path.as_str()).collect();
 
-        match self.image_paths.pop_front() {
-            None => {
-                self.load_images();
-                if self.image_paths.is_empty() {
-                    panic!("No images available");
+        let mut last_error = ProviderError::Empty("images");
+        for _ in 0..keys.len() {
+            let Some(index) = self.rotation.next(&keys) else {
+                break;
+            };
+            let entry = &self.images[index];
+            match image::open(&entry.path) {
+                Ok(img) => return Ok((entry.clone(), img)),
+                Err(e) => {
+                    warn!("Could not load image {}, skipping it: {}", entry.path, e);
+                    last_error = e.into();
                 }
-                self.get_image()
-            }
-            Some(d) => {
-                let img = image::open(d).expect("Could not load image");
-                self.cache = Some((Utc::now(), img.clone()));
-                img
             }
         }
+        Err(last_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn provider(dir: &Path) -> ImageProvider {
+        let toml = format!(
+            r#"
+            [general]
+            debug = false
+            [cache]
+            dir = "{cache}"
+            [quote]
+            quotes_path = ""
+            [image]
+            directories = ["{photos}"]
+            extensions = ["png"]
+            [weather]
+            latitude = "0"
+            longitude = "0"
+            timezone = "UTC"
+            "#,
+            cache = dir.join("cache").display(),
+            photos = dir.join("photos").display(),
+        );
+        let config = config::Config::builder()
+            .add_source(config::File::from_str(&toml, config::FileFormat::Toml))
+            .build()
+            .unwrap()
+            .try_deserialize()
+            .unwrap();
+        ImageProvider::new(config)
+    }
+
+    fn write_image(path: &Path) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        image::RgbImage::new(2, 2).save(path).unwrap();
+    }
+
+    fn next_name(provider: &mut ImageProvider) -> String {
+        let (entry, _) = provider.get_image().unwrap();
+        Path::new(&entry.path)
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    #[test]
+    fn scans_directories_on_every_fetch() {
+        let dir = std::env::temp_dir().join(format!("igen-images-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let photos = dir.join("photos");
+        write_image(&photos.join("a.png"));
+        write_image(&photos.join("nested/c.PNG"));
+        fs::write(photos.join("notes.txt"), "not an image").unwrap();
+        image::RgbImage::new(2, 2)
+            .save(photos.join("skipped.bmp"))
+            .unwrap();
+
+        let mut provider = provider(&dir);
+        assert_eq!(next_name(&mut provider), "a.png");
+
+        // Shows up within the current pass, which carries on instead of
+        // starting over with a.png
+        write_image(&photos.join("b.png"));
+        assert_eq!(next_name(&mut provider), "b.png");
+        assert_eq!(next_name(&mut provider), "c.PNG");
+        assert_eq!(next_name(&mut provider), "a.png");
+
+        fs::remove_file(photos.join("b.png")).unwrap();
+        assert_eq!(next_name(&mut provider), "c.PNG");
+        assert_eq!(next_name(&mut provider), "a.png");
+
+        fs::remove_dir_all(&dir).unwrap();
     }
 }
@@ -1,4 +1,80 @@
+use std::fmt::{Display, Formatter};
+
+pub mod cache;
+pub mod caldav;
+pub mod calendar;
 pub mod google;
+pub mod ical;
 pub mod image;
+pub mod oauth;
 pub mod quote;
+pub mod rotation;
+pub mod rrule;
+pub mod token;
 pub mod weather;
+
+#[derive(Debug)]
+pub enum ProviderError {
+    Http(reqwest::Error),
+    Io(std::io::Error),
+    Json(serde_json::Error),
+    Image(::image::ImageError),
+    Auth(String),
+    // Somebody has to go through the OAuth flow first, see the terminal
+    AuthRequired,
+    // Upstream sent something we don't understand
+    InvalidData(String),
+    // Nothing configured to rotate through
+    Empty(&'static str),
+}
+
+impl Display for ProviderError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProviderError::Http(e) => write!(f, "http request failed: {e}"),
+            ProviderError::Io(e) => write!(f, "io error: {e}"),
+            ProviderError::Json(e) => write!(f, "invalid json: {e}"),
+            ProviderError::Image(e) => write!(f, "could not load image: {e}"),
+            ProviderError::Auth(msg) => write!(f, "authentication failed: {msg}"),
+            ProviderError::AuthRequired => write!(f, "authorization required, see the terminal"),
+            ProviderError::InvalidData(msg) => write!(f, "invalid data: {msg}"),
+            ProviderError::Empty(what) => write!(f, "no {what} available"),
+        }
+    }
+}
+
+impl std::error::Error for ProviderError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ProviderError::Http(e) => Some(e),
+            ProviderError::Io(e) => Some(e),
+            ProviderError::Json(e) => Some(e),
+            ProviderError::Image(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for ProviderError {
+    fn from(value: reqwest::Error) -> Self {
+        ProviderError::Http(value)
+    }
+}
+
+impl From<std::io::Error> for ProviderError {
+    fn from(value: std::io::Error) -> Self {
+        ProviderError::Io(value)
+    }
+}
+
+impl From<serde_json::Error> for ProviderError {
+    fn from(value: serde_json::Error) -> Self {
+        ProviderError::Json(value)
+    }
+}
+
+impl From<::image::ImageError> for ProviderError {
+    fn from(value: ::image::ImageError) -> Self {
+        ProviderError::Image(value)
+    }
+}
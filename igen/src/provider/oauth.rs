@@ -0,0 +1,367 @@
+// Getting a Google token for the first time. Either a browser on this machine
+// gets redirected back to a local listener at redirect_uri (loopback), or a
+// code is entered on any other device (device authorization grant, RFC 8628),
+// which is the only sane way on a headless server.
+
+use crate::provider::ProviderError;
+use crate::settings::{AuthFlow, GoogleConfig};
+use oauth2::basic::{BasicClient, BasicTokenResponse};
+use oauth2::url::Host;
+use oauth2::{
+    AuthUrl, AuthorizationCode, ClientId, ClientSecret, CsrfToken, DeviceAuthorizationUrl,
+    EndpointNotSet, EndpointSet, PkceCodeChallenge, RedirectUrl, Scope,
+    StandardDeviceAuthorizationResponse, TokenUrl, reqwest,
+};
+use reqwest::Url;
+use std::net::{IpAddr, Ipv4Addr};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+
+const CALENDAR_SCOPE: &str = "https://www.googleapis.com/auth/calendar";
+
+pub type OAuthClient =
+    BasicClient<EndpointSet, EndpointSet, EndpointNotSet, EndpointNotSet, EndpointSet>;
+
+pub fn client(config: &GoogleConfig) -> OAuthClient {
+    BasicClient::new(ClientId::new(config.client_id.clone()))
+        .set_client_secret(ClientSecret::new(config.client_secret.clone()))
+        .set_auth_uri(AuthUrl::new(config.auth_uri.clone()).expect("Could not construct auth uri"))
+        .set_device_authorization_url(
+            DeviceAuthorizationUrl::new(config.device_auth_uri.clone())
+                .expect("Could not construct device auth uri"),
+        )
+        .set_redirect_uri(
+            RedirectUrl::new(config.redirect_uri.clone())
+                .expect("Could not construct redirect uri"),
+        )
+        .set_token_uri(
+            TokenUrl::new(config.token_uri.clone()).expect("Could not construct token uri"),
+        )
+}
+
+// Interactive, whoever set igen up has to be watching the terminal
+pub async fn authenticate(
+    config: &GoogleConfig,
+    http_client: &reqwest::Client,
+) -> Result<BasicTokenResponse, ProviderError> {
+    match config.auth_flow {
+        AuthFlow::Loopback => {
+            loopback(config, http_client, |url| {
+                println!("Open this URL in your browser:\n{url}\n")
+            })
+            .await
+        }
+        AuthFlow::Device => {
+            device(config, http_client, |uri, code| {
+                println!("Open {uri} on any device and enter the code {code}\n")
+            })
+            .await
+        }
+    }
+}
+
+async fn loopback(
+    config: &GoogleConfig,
+    http_client: &reqwest::Client,
+    show: impl FnOnce(&Url),
+) -> Result<BasicTokenResponse, ProviderError> {
+    let client = client(config);
+    let (pkce_code_challenge, pkce_code_verifier) = PkceCodeChallenge::new_random_sha256();
+    let (authorize_url, state) = client
+        .authorize_url(CsrfToken::new_random)
+        .add_scope(Scope::new(CALENDAR_SCOPE.to_string()))
+        .set_pkce_challenge(pkce_code_challenge)
+        .url();
+
+    // Listening before anyone can follow the url
+    let listener = bind_redirect(&config.redirect_uri).await?;
+    show(&authorize_url);
+    let code = receive_code(listener, &state).await?;
+
+    client
+        .exchange_code(code)
+        .set_pkce_verifier(pkce_code_verifier)
+        .request_async(http_client)
+        .await
+        .map_err(|e| ProviderError::Auth(format!("could not exchange code: {e}")))
+}
+
+// The redirect_uri has to point at this machine, the browser is sent there
+async fn bind_redirect(redirect_uri: &str) -> Result<TcpListener, ProviderError> {
+    let url = Url::parse(redirect_uri)
+        .map_err(|e| ProviderError::Auth(format!("invalid redirect uri: {e}")))?;
+    let ip: IpAddr = match url.host() {
+        Some(Host::Ipv4(ip)) => ip.into(),
+        Some(Host::Ipv6(ip)) => ip.into(),
+        Some(Host::Domain("localhost")) => Ipv4Addr::LOCALHOST.into(),
+        _ => {
+            return Err(ProviderError::Auth(format!(
+                "redirect uri {redirect_uri} is not on this machine"
+            )));
+        }
+    };
+    let port = url.port_or_known_default().unwrap_or(80);
+    Ok(TcpListener::bind((ip, port)).await?)
+}
+
+// Answers requests until the redirect shows up, which has to carry the state
+// the authorization url was sent off with
+async fn receive_code(
+    listener: TcpListener,
+    state: &CsrfToken,
+) -> Result<AuthorizationCode, ProviderError> {
+    loop {
+        let (mut stream, _) = listener.accept().await?;
+        let mut request_line = String::new();
+        BufReader::new(&mut stream)
+            .read_line(&mut request_line)
+            .await?;
+
+        let bad_redirect = || ProviderError::Auth(format!("bad redirect: {request_line}"));
+        let path = request_line
+            .split_whitespace()
+            .nth(1)
+            .ok_or_else(bad_redirect)?;
+        let url = Url::parse(&format!("http://localhost{path}")).map_err(|_| bad_redirect())?;
+        let param = |name: &str| {
+            url.query_pairs()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.into_owned())
+        };
+
+        let (status, message, result) = match (param("state"), param("code"), param("error")) {
+            (Some(received), _, _) if received != *state.secret() => (
+                "400 Bad Request",
+                "State mismatch, start over from the terminal",
+                Some(Err(ProviderError::Auth(
+                    "state of the redirect does not match".to_string(),
+                ))),
+            ),
+            (Some(_), Some(code), _) => (
+                "200 OK",
+                "Authentication successful. You can safely go back to your terminal :^)",
+                Some(Ok(AuthorizationCode::new(code))),
+            ),
+            (Some(_), None, error) => (
+                "400 Bad Request",
+                "Authentication failed, see your terminal",
+                Some(Err(ProviderError::Auth(format!(
+                    "authorization denied: {}",
+                    error.unwrap_or_default()
+                )))),
+            ),
+            // e.g. the favicon
+            (None, _, _) => ("404 Not Found", "", None),
+        };
+        let response = format!(
+            "HTTP/1.1 {status}\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{message}",
+            message.len()
+        );
+        stream.write_all(response.as_bytes()).await?;
+        if let Some(result) = result {
+            return result;
+        }
+    }
+}
+
+async fn device(
+    config: &GoogleConfig,
+    http_client: &reqwest::Client,
+    show: impl FnOnce(&str, &str),
+) -> Result<BasicTokenResponse, ProviderError> {
+    let client = client(config);
+    let details: StandardDeviceAuthorizationResponse = client
+        .exchange_device_code()
+        .add_scope(Scope::new(CALENDAR_SCOPE.to_string()))
+        .request_async(http_client)
+        .await
+        .map_err(|e| ProviderError::Auth(format!("could not request device code: {e}")))?;
+
+    show(details.verification_uri(), details.user_code().secret());
+
+    // Polls until the code was entered or it expired
+    client
+        .exchange_device_access_token(&details)
+        .request_async(http_client, tokio::time::sleep, None)
+        .await
+        .map_err(|e| ProviderError::Auth(format!("device authorization failed: {e}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::extract::State;
+    use axum::http::StatusCode;
+    use axum::routing::post;
+    use axum::{Form, Json, Router};
+    use oauth2::TokenResponse;
+    use serde_json::{Value, json};
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // Stand-in for Google's device and token endpoints
+    async fn mock_google() -> String {
+        async fn device_code() -> Json<Value> {
+            Json(json!({
+                "device_code": "dev-123",
+                "user_code": "ABCD-EFGH",
+                // Google's name for verification_uri
+                "verification_url": "https://www.google.com/device",
+                "expires_in": 1800,
+                "interval": 0,
+            }))
+        }
+
+        async fn token(
+            State(polls): State<Arc<AtomicUsize>>,
+            Form(form): Form<HashMap<String, String>>,
+        ) -> (StatusCode, Json<Value>) {
+            let granted = match form["grant_type"].as_str() {
+                "urn:ietf:params:oauth:grant-type:device_code" => {
+                    assert_eq!(form["device_code"], "dev-123");
+                    // The user takes a moment to enter the code
+                    if polls.fetch_add(1, Ordering::SeqCst) < 2 {
+                        return (
+                            StatusCode::BAD_REQUEST,
+                            Json(json!({"error": "authorization_pending"})),
+                        );
+                    }
+                    "device"
+                }
+                "authorization_code" => {
+                    assert_eq!(form["code"], "code-123");
+                    assert!(form.contains_key("code_verifier"));
+                    "loopback"
+                }
+                other => panic!("unexpected grant {other}"),
+            };
+            (
+                StatusCode::OK,
+                Json(json!({
+                    "access_token": granted,
+                    "refresh_token": "refresh",
+                    "token_type": "Bearer",
+                    "expires_in": 3599,
+                })),
+            )
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = Router::new()
+            .route("/device/code", post(device_code))
+            .route("/token", post(token))
+            .with_state(Arc::new(AtomicUsize::new(0)));
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        format!("http://{addr}")
+    }
+
+    fn config(google: &str, redirect_uri: String) -> GoogleConfig {
+        GoogleConfig {
+            token_path: String::new(),
+            client_id: "client".to_string(),
+            client_secret: "secret".to_string(),
+            auth_uri: format!("{google}/auth"),
+            device_auth_uri: format!("{google}/device/code"),
+            redirect_uri,
+            token_uri: format!("{google}/token"),
+            auth_flow: AuthFlow::Device,
+            calendar_list: vec![],
+        }
+    }
+
+    fn http_client() -> reqwest::Client {
+        reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .unwrap()
+    }
+
+    async fn free_port() -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap().port()
+    }
+
+    #[tokio::test]
+    async fn device_flow() {
+        let google = mock_google().await;
+        let config = config(&google, "http://localhost:8080".to_string());
+        let mut shown = None;
+        let token = device(&config, &http_client(), |uri, code| {
+            shown = Some((uri.to_string(), code.to_string()))
+        })
+        .await
+        .unwrap();
+        assert_eq!(token.access_token().secret(), "device");
+        assert_eq!(
+            shown.unwrap(),
+            (
+                "https://www.google.com/device".to_string(),
+                "ABCD-EFGH".to_string()
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn loopback_flow_on_configured_port() {
+        let google = mock_google().await;
+        let port = free_port().await;
+        let config = config(&google, format!("http://127.0.0.1:{port}/callback"));
+        let http_client = http_client();
+        let (tx, rx) = tokio::sync::oneshot::channel();
+
+        let flow = loopback(&config, &http_client, |url| {
+            tx.send(url.clone()).unwrap();
+        });
+        // The browser
+        let browser = async {
+            let url = rx.await.unwrap();
+            let state = url
+                .query_pairs()
+                .find(|(key, _)| key == "state")
+                .unwrap()
+                .1
+                .into_owned();
+            let redirect = format!("http://127.0.0.1:{port}/callback");
+            let favicon = http_client
+                .get(format!("http://127.0.0.1:{port}/favicon.ico"))
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(favicon.status(), StatusCode::NOT_FOUND);
+            http_client
+                .get(redirect)
+                .query(&[("code", "code-123"), ("state", &state)])
+                .send()
+                .await
+                .unwrap()
+                .status()
+        };
+        let (token, status) = tokio::join!(flow, browser);
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(token.unwrap().access_token().secret(), "loopback");
+    }
+
+    #[tokio::test]
+    async fn loopback_rejects_foreign_state() {
+        let port = free_port().await;
+        let listener = bind_redirect(&format!("http://localhost:{port}"))
+            .await
+            .unwrap();
+        let state = CsrfToken::new("expected".to_string());
+        let (code, status) = tokio::join!(receive_code(listener, &state), async {
+            http_client()
+                .get(format!(
+                    "http://127.0.0.1:{port}/?code=code-123&state=forged"
+                ))
+                .send()
+                .await
+                .unwrap()
+                .status()
+        });
+        assert!(code.is_err());
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(bind_redirect("https://example.com/callback").await.is_err());
+    }
+}
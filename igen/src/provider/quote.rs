@@ -1,10 +1,10 @@
+use crate::provider::ProviderError;
+use crate::provider::rotation::Rotation;
 use crate::settings::QuoteConfig;
-use chrono::{DateTime, Duration, Utc};
-use serde::Deserialize;
-use std::collections::VecDeque;
+use serde::{Deserialize, Serialize};
 use std::fs;
 
-#[derive(Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Quote {
     pub content: String,
     pub author: String,
@@ -13,49 +13,37 @@ pub struct Quote {
 
 pub struct QuoteProvider {
     quote_config: QuoteConfig,
-    quotes: VecDeque<Quote>,
-    cache: Option<(DateTime<Utc>, Quote)>,
+    quotes: Vec<Quote>,
+    rotation: Rotation,
 }
 
 impl QuoteProvider {
-    fn load_quotes(&mut self) {
+    fn load_quotes(&mut self) -> Result<(), ProviderError> {
         let quotes = serde_json::from_str::<Vec<Quote>>(
-            fs::read_to_string(&self.quote_config.quotes_path)
-                .expect("Could not read quotes path")
-                .as_str(),
-        )
-        .expect("Could not deserialize quotes");
-        self.quotes = VecDeque::from(quotes);
+            fs::read_to_string(&self.quote_config.quotes_path)?.as_str(),
+        )?;
+        self.quotes = quotes;
+        Ok(())
     }
 
-    pub fn get_quote(&mut self) -> Quote {
-        if let Some((last_refresh, last_quote)) = self.cache.as_ref() {
-            if Utc::now().signed_duration_since(last_refresh) > Duration::minutes(15) {
-            } else {
-                return last_quote.clone();
-            }
-        }
-
-        match self.quotes.pop_front() {
-            None => {
-                self.load_quotes();
-                if self.quotes.is_empty() {
-                    panic!("no quotes");
-                }
-                self.get_quote()
-            }
-            Some(q) => {
-                self.cache = Some((Utc::now(), q.clone()));
-                q
-            }
+    pub fn get_quote(&mut self) -> Result<Quote, ProviderError> {
+        // Edits to the quotes file are picked up with the next pass
+        if self.quotes.is_empty() || self.rotation.at_pass_start() {
+            self.load_quotes()?;
         }
+        let keys: Vec<&str> = self.quotes.iter().map(|q| q.content.as_str()).collect();
+        let index = self
+            .rotation
+            .next(&keys)
+            .ok_or(ProviderError::Empty("quotes"))?;
+        Ok(self.quotes[index].clone())
     }
 
-    pub fn new(quote_config: QuoteConfig) -> QuoteProvider {
+    pub fn new(quote_config: QuoteConfig, state_dir: &str) -> QuoteProvider {
         QuoteProvider {
+            rotation: Rotation::open(state_dir, "quote", quote_config.shuffle, quote_config.seed),
             quote_config,
-            quotes: VecDeque::new(),
-            cache: None,
+            quotes: vec![],
         }
     }
 }
@@ -0,0 +1,213 @@
+// Position in a rotation (quotes, images) that survives restarts.
+//
+// A pass visits every item exactly once, either in list order or shuffled by
+// the pass seed. Items are tracked by a key (path, text) rather than their
+// position, so items added or removed in between don't start the pass over.
+// What was handed out and the seed live in a small json file next to the
+// provider cache, so a restart continues the current pass as well.
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq)]
+#[serde(default)]
+struct RotationState {
+    // Keys of the items already handed out in this pass
+    seen: BTreeSet<String>,
+    // Seed of the current pass, unused when not shuffling
+    seed: u64,
+}
+
+pub struct Rotation {
+    path: Option<PathBuf>,
+    shuffle: bool,
+    state: RotationState,
+}
+
+impl Rotation {
+    // seed only picks the first pass, later passes derive theirs from it
+    pub fn open(dir: &str, name: &str, shuffle: bool, seed: Option<u64>) -> Self {
+        let path = Path::new(dir).join(format!("{name}-rotation.json"));
+        let state = match fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content)
+                .inspect_err(|e| warn!("Ignoring rotation state {:?}: {}", path, e))
+                .ok(),
+            Err(_) => None,
+        };
+        let state = state.unwrap_or_else(|| RotationState {
+            seed: seed.unwrap_or_else(time_seed),
+            ..RotationState::default()
+        });
+        Self::with_state(Some(path), shuffle, state)
+    }
+
+    fn with_state(path: Option<PathBuf>, shuffle: bool, state: RotationState) -> Self {
+        Rotation {
+            path,
+            shuffle,
+            state,
+        }
+    }
+
+    // True if the next item starts a new pass, a good moment to reload the list
+    pub fn at_pass_start(&self) -> bool {
+        self.state.seen.is_empty()
+    }
+
+    // Index of the next item out of the ones with these keys, None if there
+    // are none. Items sharing a key count as one
+    pub fn next<K: AsRef<str>>(&mut self, keys: &[K]) -> Option<usize> {
+        if keys.is_empty() {
+            return None;
+        }
+        // Removed items don't hold up the end of the pass
+        let present: HashSet<&str> = keys.iter().map(AsRef::as_ref).collect();
+        self.state.seen.retain(|key| present.contains(key.as_str()));
+
+        let unseen = |seen: &BTreeSet<String>| {
+            (0..keys.len())
+                .filter(|&i| !seen.contains(keys[i].as_ref()))
+                .collect::<Vec<_>>()
+        };
+        let mut candidates = unseen(&self.state.seen);
+        if candidates.is_empty() {
+            self.state.seen.clear();
+            self.state.seed = split_mix(&mut self.state.seed);
+            candidates = unseen(&self.state.seen);
+        }
+
+        // Shuffled by ranking every key with the pass seed, which doesn't
+        // depend on what else is in the list
+        let index = if self.shuffle {
+            let seed = self.state.seed;
+            *candidates
+                .iter()
+                .min_by_key(|&&i| rank(seed, keys[i].as_ref()))
+                .unwrap()
+        } else {
+            candidates[0]
+        };
+        self.state.seen.insert(keys[index].as_ref().to_string());
+        self.save();
+        Some(index)
+    }
+
+    fn save(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let result = path
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| fs::write(path, serde_json::to_vec(&self.state)?));
+        if let Err(e) = result {
+            warn!("Could not write rotation state {:?}: {}", path, e);
+        }
+    }
+}
+
+// Position of key in the pass shuffled by seed
+fn rank(seed: u64, key: &str) -> u64 {
+    // FNV-1a, mixed with the seed below
+    let hash = key.bytes().fold(0xCBF2_9CE4_8422_2325u64, |hash, b| {
+        (hash ^ b as u64).wrapping_mul(0x0000_0100_0000_01B3)
+    });
+    let mut state = seed ^ hash;
+    split_mix(&mut state)
+}
+
+// SplitMix64, small and stable across versions, which matters since the
+// seed is persisted and has to produce the same order after a restart
+fn split_mix(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+fn time_seed() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rotation(shuffle: bool, seed: u64) -> Rotation {
+        Rotation::with_state(
+            None,
+            shuffle,
+            RotationState {
+                seed,
+                ..RotationState::default()
+            },
+        )
+    }
+
+    fn keys(names: &str) -> Vec<String> {
+        names.split_whitespace().map(String::from).collect()
+    }
+
+    fn take(rotation: &mut Rotation, keys: &[String], n: usize) -> Vec<String> {
+        (0..n)
+            .map(|_| keys[rotation.next(keys).unwrap()].clone())
+            .collect()
+    }
+
+    #[test]
+    fn in_order_wraps_around() {
+        let mut r = rotation(false, 0);
+        assert_eq!(take(&mut r, &keys("a b c"), 7), keys("a b c a b c a"));
+    }
+
+    #[test]
+    fn shuffled_passes_visit_everything_once() {
+        let all = keys("0 1 2 3 4 5 6 7 8 9");
+        let mut r = rotation(true, 42);
+        let first = take(&mut r, &all, 10);
+        let second = take(&mut r, &all, 10);
+        for pass in [&first, &second] {
+            let mut sorted = pass.clone();
+            sorted.sort();
+            assert_eq!(sorted, all);
+        }
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn resumes_from_saved_state() {
+        let all = keys("a b c d e f g h");
+        let mut r = rotation(true, 7);
+        let full = take(&mut r, &all, 12);
+
+        let mut r = rotation(true, 7);
+        take(&mut r, &all, 5);
+        let saved = serde_json::to_string(&r.state).unwrap();
+        let mut resumed = Rotation::with_state(None, true, serde_json::from_str(&saved).unwrap());
+        assert_eq!(take(&mut resumed, &all, 7), full[5..]);
+    }
+
+    #[test]
+    fn changed_items_keep_the_pass() {
+        let mut r = rotation(false, 0);
+        assert_eq!(take(&mut r, &keys("a b c d"), 2), keys("a b"));
+        // c went away, e came in
+        assert_eq!(take(&mut r, &keys("a b d e"), 3), keys("d e a"));
+        assert_eq!(r.next(&Vec::<String>::new()), None);
+
+        // Shuffled, new items join the current pass without repeating old ones
+        let mut r = rotation(true, 3);
+        let first = take(&mut r, &keys("a b c d"), 2);
+        let rest = take(&mut r, &keys("a b c d e"), 3);
+        let mut pass = [first, rest].concat();
+        pass.sort();
+        assert_eq!(pass, keys("a b c d e"));
+    }
+}
@@ -0,0 +1,444 @@
+// Recurrence rules (RFC 5545 3.3.10), expanded into the start times of a series.
+//
+// Covers what calendar apps actually write: FREQ=DAILY to YEARLY with INTERVAL,
+// COUNT, UNTIL, BYDAY (with ordinals for monthly and yearly rules), BYMONTHDAY,
+// BYMONTH and WKST. Rules using anything else are rejected, the caller falls
+// back to the first occurrence instead of showing made up ones.
+//
+// Everything runs on wall clock time of the series' time zone, so a weekly
+// meeting stays at 9:00 across DST changes.
+
+use chrono::{DateTime, Datelike, NaiveDate, NaiveDateTime, TimeDelta, Utc, Weekday};
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Freq {
+    Daily,
+    Weekly,
+    Monthly,
+    Yearly,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Until {
+    Date(NaiveDate),
+    // Same time zone as DTSTART
+    Floating(NaiveDateTime),
+    Utc(DateTime<Utc>),
+}
+
+#[derive(Debug, Clone)]
+pub struct RRule {
+    freq: Freq,
+    interval: u32,
+    count: Option<u32>,
+    pub until: Option<Until>,
+    // e.g. -1FR is the last friday of the month (or year)
+    by_day: Vec<(Option<i32>, Weekday)>,
+    // Negative ones count from the end of the month
+    by_month_day: Vec<i32>,
+    by_month: Vec<u32>,
+    week_start: Weekday,
+}
+
+fn parse_weekday(s: &str) -> Result<Weekday, String> {
+    Ok(match s {
+        "MO" => Weekday::Mon,
+        "TU" => Weekday::Tue,
+        "WE" => Weekday::Wed,
+        "TH" => Weekday::Thu,
+        "FR" => Weekday::Fri,
+        "SA" => Weekday::Sat,
+        "SU" => Weekday::Sun,
+        other => return Err(format!("unknown weekday {other}")),
+    })
+}
+
+fn parse_list<T: FromStr>(value: &str, key: &str) -> Result<Vec<T>, String> {
+    value
+        .split(',')
+        .map(|v| v.parse().map_err(|_| format!("invalid {key} {v}")))
+        .collect()
+}
+
+impl FromStr for RRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut freq = None;
+        let mut rule = RRule {
+            freq: Freq::Daily,
+            interval: 1,
+            count: None,
+            until: None,
+            by_day: vec![],
+            by_month_day: vec![],
+            by_month: vec![],
+            week_start: Weekday::Mon,
+        };
+        for part in s.split(';').filter(|p| !p.is_empty()) {
+            let (key, value) = part
+                .split_once('=')
+                .ok_or_else(|| format!("invalid rule part {part}"))?;
+            match key.to_ascii_uppercase().as_str() {
+                "FREQ" => {
+                    freq = Some(match value {
+                        "DAILY" => Freq::Daily,
+                        "WEEKLY" => Freq::Weekly,
+                        "MONTHLY" => Freq::Monthly,
+                        "YEARLY" => Freq::Yearly,
+                        other => return Err(format!("unsupported frequency {other}")),
+                    })
+                }
+                "INTERVAL" => {
+                    rule.interval = value
+                        .parse()
+                        .ok()
+                        .filter(|i| *i > 0)
+                        .ok_or_else(|| format!("invalid interval {value}"))?
+                }
+                "COUNT" => {
+                    rule.count = Some(
+                        value
+                            .parse()
+                            .map_err(|_| format!("invalid count {value}"))?,
+                    )
+                }
+                "UNTIL" => rule.until = Some(parse_until(value)?),
+                "BYDAY" => {
+                    rule.by_day = value
+                        .split(',')
+                        .map(|day| {
+                            // Two letters at the end, anything else isn't a day
+                            let at = day.len().saturating_sub(2);
+                            if !day.is_char_boundary(at) {
+                                return Err(format!("invalid day {day}"));
+                            }
+                            let (ordinal, weekday) = day.split_at(at);
+                            let ordinal = match ordinal {
+                                "" => None,
+                                n => Some(
+                                    n.trim_start_matches('+')
+                                        .parse()
+                                        .ok()
+                                        .filter(|n| *n != 0)
+                                        .ok_or_else(|| format!("invalid day {day}"))?,
+                                ),
+                            };
+                            Ok((ordinal, parse_weekday(weekday)?))
+                        })
+                        .collect::<Result<_, String>>()?
+                }
+                "BYMONTHDAY" => rule.by_month_day = parse_list(value, "month day")?,
+                "BYMONTH" => rule.by_month = parse_list(value, "month")?,
+                "WKST" => rule.week_start = parse_weekday(value)?,
+                other => return Err(format!("unsupported rule part {other}")),
+            }
+        }
+        rule.freq = freq.ok_or("rule without frequency")?;
+        Ok(rule)
+    }
+}
+
+fn parse_until(value: &str) -> Result<Until, String> {
+    let invalid = || format!("invalid until {value}");
+    if value.len() == 8 {
+        return NaiveDate::parse_from_str(value, "%Y%m%d")
+            .map(Until::Date)
+            .map_err(|_| invalid());
+    }
+    let (time, utc) = match value.strip_suffix('Z') {
+        Some(time) => (time, true),
+        None => (value, false),
+    };
+    let time = NaiveDateTime::parse_from_str(time, "%Y%m%dT%H%M%S").map_err(|_| invalid())?;
+    Ok(if utc {
+        Until::Utc(time.and_utc())
+    } else {
+        Until::Floating(time)
+    })
+}
+
+fn days_of_month(year: i32, month: u32) -> Vec<NaiveDate> {
+    NaiveDate::from_ymd_opt(year, month, 1)
+        .map(|first| {
+            first
+                .iter_days()
+                .take_while(|d| d.month() == month)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn days_of_year(year: i32) -> Vec<NaiveDate> {
+    NaiveDate::from_ymd_opt(year, 1, 1)
+        .map(|first| first.iter_days().take_while(|d| d.year() == year).collect())
+        .unwrap_or_default()
+}
+
+impl RRule {
+    fn matches_weekday(&self, date: NaiveDate) -> bool {
+        self.by_day.iter().any(|(_, wd)| *wd == date.weekday())
+    }
+
+    fn matches_month_day(&self, date: NaiveDate) -> bool {
+        let len = days_of_month(date.year(), date.month()).len() as i32;
+        let day = date.day() as i32;
+        self.by_month_day
+            .iter()
+            .any(|d| *d == day || *d == day - len - 1)
+    }
+
+    // BYDAY within one month or year, ordinals count within that span
+    fn pick_weekdays(&self, span: &[NaiveDate]) -> Vec<NaiveDate> {
+        let mut days = vec![];
+        for (ordinal, weekday) in &self.by_day {
+            let matching: Vec<_> = span
+                .iter()
+                .copied()
+                .filter(|d| d.weekday() == *weekday)
+                .collect();
+            match ordinal {
+                None => days.extend(matching),
+                Some(n) if *n > 0 => days.extend(matching.get(*n as usize - 1)),
+                Some(n) => days.extend(
+                    matching
+                        .len()
+                        .checked_sub(n.unsigned_abs() as usize)
+                        .map(|i| matching[i]),
+                ),
+            }
+        }
+        days
+    }
+
+    // Candidate days of a month for MONTHLY rules and YEARLY ones with BYMONTH
+    fn days_in_month(&self, year: i32, month: u32, start: NaiveDate) -> Vec<NaiveDate> {
+        let span = days_of_month(year, month);
+        if !self.by_month_day.is_empty() {
+            span.into_iter()
+                .filter(|d| self.matches_month_day(*d))
+                .filter(|d| self.by_day.is_empty() || self.matches_weekday(*d))
+                .collect()
+        } else if !self.by_day.is_empty() {
+            self.pick_weekdays(&span)
+        } else {
+            NaiveDate::from_ymd_opt(year, month, start.day())
+                .into_iter()
+                .collect()
+        }
+    }
+
+    // First day of the nth period after the one start is in
+    fn period_start(&self, start: NaiveDate, n: u32) -> Option<NaiveDate> {
+        match self.freq {
+            Freq::Daily => start.checked_add_signed(TimeDelta::days(n as i64)),
+            Freq::Weekly => {
+                let offset = start.weekday().days_since(self.week_start);
+                start.checked_add_signed(TimeDelta::days(7 * n as i64 - offset as i64))
+            }
+            Freq::Monthly => {
+                let months = start.month0() + n;
+                NaiveDate::from_ymd_opt(
+                    start.year().checked_add((months / 12) as i32)?,
+                    months % 12 + 1,
+                    1,
+                )
+            }
+            Freq::Yearly => NaiveDate::from_ymd_opt(start.year().checked_add(n as i32)?, 1, 1),
+        }
+    }
+
+    // Candidate days of the period beginning at first, unsorted
+    fn period_days(&self, first: NaiveDate, start: NaiveDate) -> Vec<NaiveDate> {
+        let in_month =
+            |d: &NaiveDate| self.by_month.is_empty() || self.by_month.contains(&d.month());
+        match self.freq {
+            Freq::Daily => Some(first)
+                .filter(in_month)
+                .filter(|d| self.by_day.is_empty() || self.matches_weekday(*d))
+                .filter(|d| self.by_month_day.is_empty() || self.matches_month_day(*d))
+                .into_iter()
+                .collect(),
+            Freq::Weekly => first
+                .iter_days()
+                .take(7)
+                .filter(in_month)
+                .filter(|d| {
+                    if self.by_day.is_empty() {
+                        d.weekday() == start.weekday()
+                    } else {
+                        self.matches_weekday(*d)
+                    }
+                })
+                .collect(),
+            Freq::Monthly => {
+                if !in_month(&first) {
+                    return vec![];
+                }
+                self.days_in_month(first.year(), first.month(), start)
+            }
+            Freq::Yearly => {
+                let year = first.year();
+                if !self.by_month.is_empty() {
+                    self.by_month
+                        .iter()
+                        .flat_map(|m| self.days_in_month(year, *m, start))
+                        .collect()
+                } else if !self.by_month_day.is_empty() {
+                    (1..=12)
+                        .flat_map(|m| self.days_in_month(year, m, start))
+                        .collect()
+                } else if !self.by_day.is_empty() {
+                    self.pick_weekdays(&days_of_year(year))
+                } else {
+                    NaiveDate::from_ymd_opt(year, start.month(), start.day())
+                        .into_iter()
+                        .collect()
+                }
+            }
+        }
+    }
+
+    // Start times of the series beginning at start, up to and including end.
+    // until is UNTIL in the series' wall clock time, start always is the first
+    // occurrence whether it matches the rule or not.
+    pub fn occurrences(
+        &self,
+        start: NaiveDateTime,
+        until: Option<NaiveDateTime>,
+        end: NaiveDateTime,
+    ) -> Vec<NaiveDateTime> {
+        let limit = until.map_or(end, |until| until.min(end));
+        let mut found = vec![];
+        if start > limit {
+            return found;
+        }
+        found.push(start);
+        let mut count = 1;
+        for n in (0..).step_by(self.interval as usize) {
+            if self.count.is_some_and(|max| count >= max) {
+                break;
+            }
+            let Some(first) = self.period_start(start.date(), n) else {
+                break;
+            };
+            if first > limit.date() {
+                break;
+            }
+            let mut days = self.period_days(first, start.date());
+            days.sort();
+            days.dedup();
+            for day in days {
+                let time = day.and_time(start.time());
+                if time <= start {
+                    continue;
+                }
+                if time > limit || self.count.is_some_and(|max| count >= max) {
+                    return found;
+                }
+                found.push(time);
+                count += 1;
+            }
+        }
+        found
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(y: i32, m: u32, d: u32, h: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(y, m, d)
+            .unwrap()
+            .and_hms_opt(h, 0, 0)
+            .unwrap()
+    }
+
+    fn expand(rule: &str, start: NaiveDateTime, end: NaiveDateTime) -> Vec<NaiveDateTime> {
+        let rule: RRule = rule.parse().unwrap();
+        let until = match rule.until {
+            Some(Until::Floating(t)) => Some(t),
+            Some(Until::Date(d)) => d.and_hms_opt(23, 59, 59),
+            _ => None,
+        };
+        rule.occurrences(start, until, end)
+    }
+
+    #[test]
+    fn weekly_on_several_days() {
+        // Friday the 14th
+        let start = at(2025, 3, 14, 9);
+        let found = expand("FREQ=WEEKLY;BYDAY=MO,FR;COUNT=4", start, at(2026, 1, 1, 0));
+        assert_eq!(
+            found,
+            [
+                start,
+                at(2025, 3, 17, 9),
+                at(2025, 3, 21, 9),
+                at(2025, 3, 24, 9)
+            ]
+        );
+        let found = expand("FREQ=WEEKLY;INTERVAL=2", start, at(2025, 4, 12, 0));
+        assert_eq!(found, [start, at(2025, 3, 28, 9), at(2025, 4, 11, 9)]);
+    }
+
+    #[test]
+    fn monthly_by_position_and_day() {
+        let start = at(2025, 1, 31, 18);
+        // Last friday of the month
+        let found = expand(
+            "FREQ=MONTHLY;BYDAY=-1FR;UNTIL=20250430",
+            start,
+            at(2026, 1, 1, 0),
+        );
+        assert_eq!(
+            found,
+            [
+                start,
+                at(2025, 2, 28, 18),
+                at(2025, 3, 28, 18),
+                at(2025, 4, 25, 18)
+            ]
+        );
+        // Months without a 31st are skipped
+        let found = expand("FREQ=MONTHLY", start, at(2025, 6, 1, 0));
+        assert_eq!(found, [start, at(2025, 3, 31, 18), at(2025, 5, 31, 18)]);
+        let found = expand(
+            "FREQ=MONTHLY;BYMONTHDAY=-1;COUNT=3",
+            start,
+            at(2026, 1, 1, 0),
+        );
+        assert_eq!(found, [start, at(2025, 2, 28, 18), at(2025, 3, 31, 18)]);
+    }
+
+    #[test]
+    fn yearly() {
+        let start = at(2024, 2, 29, 0);
+        let found = expand("FREQ=YEARLY", start, at(2030, 1, 1, 0));
+        assert_eq!(found, [start, at(2028, 2, 29, 0)]);
+        // Thanksgiving
+        let start = at(2025, 11, 27, 12);
+        let found = expand(
+            "FREQ=YEARLY;BYMONTH=11;BYDAY=4TH",
+            start,
+            at(2027, 12, 1, 0),
+        );
+        assert_eq!(found, [start, at(2026, 11, 26, 12), at(2027, 11, 25, 12)]);
+    }
+
+    #[test]
+    fn rejects_what_it_cannot_expand() {
+        assert!(
+            "FREQ=MONTHLY;BYSETPOS=-1;BYDAY=MO,TU"
+                .parse::<RRule>()
+                .is_err()
+        );
+        assert!("FREQ=HOURLY".parse::<RRule>().is_err());
+        assert!("INTERVAL=2".parse::<RRule>().is_err());
+        for day in ["éx", "1é", "é"] {
+            assert!(format!("FREQ=WEEKLY;BYDAY={day}").parse::<RRule>().is_err());
+        }
+    }
+}
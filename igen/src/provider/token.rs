@@ -0,0 +1,330 @@
+// Google access tokens, kept in memory and refreshed shortly before they expire.
+//
+// Only one refresh runs at a time, callers arriving meanwhile wait for it and
+// take its result. When only the user can help (no token yet, no refresh token
+// or a revoked one) the interactive flow is started in the background and
+// fetches fail with AuthRequired until it went through, which the dashboard
+// shows instead of the calendar.
+
+use crate::provider::ProviderError;
+use crate::provider::oauth;
+use crate::settings::GoogleConfig;
+use chrono::{DateTime, TimeDelta, Utc};
+use log::{debug, info, warn};
+use oauth2::basic::{BasicErrorResponseType, BasicTokenResponse};
+use oauth2::{RefreshToken, RequestTokenError, TokenResponse, reqwest};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+// Tokens this close to their expiry are refreshed already
+const EXPIRY_SKEW: TimeDelta = TimeDelta::seconds(60);
+const REFRESH_ATTEMPTS: u32 = 3;
+// Doubled after every failed attempt
+const REFRESH_BACKOFF: Duration = Duration::from_secs(2);
+
+#[derive(Clone, Serialize, Deserialize)]
+struct StoredToken {
+    access_token: String,
+    refresh_token: Option<String>,
+    expires_at: Option<DateTime<Utc>>,
+}
+
+impl StoredToken {
+    // Refresh responses usually come without a refresh token, the old one stays valid
+    fn from_response(response: &BasicTokenResponse, previous: Option<&StoredToken>) -> Self {
+        StoredToken {
+            access_token: response.access_token().secret().clone(),
+            refresh_token: response
+                .refresh_token()
+                .map(|t| t.secret().clone())
+                .or_else(|| previous.and_then(|p| p.refresh_token.clone())),
+            expires_at: response.expires_in().map(|d| Utc::now() + d),
+        }
+    }
+
+    fn is_fresh(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at.is_some_and(|e| now + EXPIRY_SKEW < e)
+    }
+}
+
+pub struct TokenManager {
+    config: GoogleConfig,
+    http_client: reqwest::Client,
+    // Held during refreshes, which keeps them to one at a time
+    token: tokio::sync::Mutex<Option<StoredToken>>,
+    // Set while the interactive flow runs
+    authenticating: AtomicBool,
+    backoff: Duration,
+}
+
+fn load(path: &str) -> Option<StoredToken> {
+    let content = fs::read_to_string(path).ok()?;
+    serde_json::from_str(&content)
+        .inspect_err(|e| warn!("Ignoring token file {}: {}", path, e))
+        .ok()
+}
+
+impl TokenManager {
+    // The token file is only read here, afterwards it is only written
+    pub fn new(config: GoogleConfig, http_client: reqwest::Client) -> Arc<Self> {
+        Arc::new(TokenManager {
+            token: tokio::sync::Mutex::new(load(&config.token_path)),
+            config,
+            http_client,
+            authenticating: AtomicBool::new(false),
+            backoff: REFRESH_BACKOFF,
+        })
+    }
+
+    pub async fn access_token(self: &Arc<Self>) -> Result<String, ProviderError> {
+        let mut token = self.token.lock().await;
+        let Some(current) = token.as_ref() else {
+            debug!("No calendar token yet");
+            return Err(self.start_auth());
+        };
+        if current.is_fresh(Utc::now()) {
+            return Ok(current.access_token.clone());
+        }
+        let Some(refresh_token) = current.refresh_token.clone() else {
+            warn!("Calendar token expired and there is no refresh token");
+            return Err(self.start_auth());
+        };
+
+        debug!("Access token expired. Refreshing...");
+        let Some(response) = self.refresh(refresh_token).await? else {
+            warn!("Calendar refresh token was rejected");
+            *token = None;
+            return Err(self.start_auth());
+        };
+        let refreshed = StoredToken::from_response(&response, Some(current));
+        self.persist(&refreshed);
+        let access_token = refreshed.access_token.clone();
+        *token = Some(refreshed);
+        Ok(access_token)
+    }
+
+    // None if the refresh token is no good anymore
+    async fn refresh(
+        &self,
+        refresh_token: String,
+    ) -> Result<Option<BasicTokenResponse>, ProviderError> {
+        let client = oauth::client(&self.config);
+        let refresh_token = RefreshToken::new(refresh_token);
+        let mut backoff = self.backoff;
+        let mut attempt = 1;
+        loop {
+            match client
+                .exchange_refresh_token(&refresh_token)
+                .request_async(&self.http_client)
+                .await
+            {
+                Ok(response) => return Ok(Some(response)),
+                Err(RequestTokenError::ServerResponse(e))
+                    if *e.error() == BasicErrorResponseType::InvalidGrant =>
+                {
+                    return Ok(None);
+                }
+                // Anything else the server says won't change by asking again
+                Err(e @ RequestTokenError::ServerResponse(_)) => {
+                    return Err(ProviderError::Auth(format!("could not refresh token: {e}")));
+                }
+                Err(e) if attempt < REFRESH_ATTEMPTS => {
+                    warn!("Could not refresh token, retrying in {:?}: {}", backoff, e);
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                    attempt += 1;
+                }
+                Err(e) => {
+                    return Err(ProviderError::Auth(format!("could not refresh token: {e}")));
+                }
+            }
+        }
+    }
+
+    // Runs the interactive flow in the background unless it already does
+    fn start_auth(self: &Arc<Self>) -> ProviderError {
+        if !self.authenticating.swap(true, Ordering::SeqCst) {
+            let manager = self.clone();
+            tokio::spawn(async move {
+                match oauth::authenticate(&manager.config, &manager.http_client).await {
+                    Ok(response) => {
+                        let token = StoredToken::from_response(&response, None);
+                        manager.persist(&token);
+                        *manager.token.lock().await = Some(token);
+                        info!("Calendar authorized");
+                    }
+                    Err(e) => warn!("Calendar authorization failed: {}", e),
+                }
+                manager.authenticating.store(false, Ordering::SeqCst);
+            });
+        }
+        ProviderError::AuthRequired
+    }
+
+    // Failing to persist it only costs a refresh after a restart
+    fn persist(&self, token: &StoredToken) {
+        let path = Path::new(&self.config.token_path);
+        // Write and rename so a crash never leaves half a file behind
+        let tmp = path.with_extension("tmp");
+        let result = serde_json::to_string_pretty(token)
+            .map_err(std::io::Error::from)
+            .and_then(|json| fs::write(&tmp, json))
+            .and_then(|()| fs::rename(&tmp, path));
+        if let Err(e) = result {
+            warn!("Could not write token file {:?}: {}", path, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::AuthFlow;
+    use axum::Router;
+    use axum::extract::State;
+    use axum::http::StatusCode;
+    use axum::routing::post;
+    use serde_json::{Value, json};
+    use std::sync::atomic::AtomicUsize;
+
+    struct Mock {
+        refreshes: AtomicUsize,
+        // Answered with a 503 before the first success
+        failures: usize,
+        revoked: bool,
+    }
+
+    async fn mock_google(mock: Arc<Mock>) -> String {
+        async fn token(State(mock): State<Arc<Mock>>) -> (StatusCode, axum::Json<Value>) {
+            let n = mock.refreshes.fetch_add(1, Ordering::SeqCst);
+            if mock.revoked {
+                (
+                    StatusCode::BAD_REQUEST,
+                    axum::Json(json!({"error": "invalid_grant"})),
+                )
+            } else if n < mock.failures {
+                (StatusCode::SERVICE_UNAVAILABLE, axum::Json(json!({})))
+            } else {
+                // Some time for concurrent callers to pile up
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                (
+                    StatusCode::OK,
+                    axum::Json(json!({
+                        "access_token": format!("access-{n}"),
+                        "token_type": "Bearer",
+                        "expires_in": 3599,
+                    })),
+                )
+            }
+        }
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = Router::new().route("/token", post(token)).with_state(mock);
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        format!("http://{addr}")
+    }
+
+    // The token file lives in a directory of its own per test
+    fn manager(google: &str, name: &str, stored: &StoredToken) -> Arc<TokenManager> {
+        let dir = std::env::temp_dir().join(format!("igen-token-{}-{name}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let token_path = dir.join("token.json").to_string_lossy().into_owned();
+        fs::write(&token_path, serde_json::to_string(stored).unwrap()).unwrap();
+        let config = GoogleConfig {
+            token_path,
+            client_id: "client".to_string(),
+            client_secret: "secret".to_string(),
+            auth_flow: AuthFlow::Device,
+            auth_uri: format!("{google}/auth"),
+            // Not served, the background flow fails right away
+            device_auth_uri: format!("{google}/device/code"),
+            redirect_uri: "http://localhost:8080".to_string(),
+            token_uri: format!("{google}/token"),
+            calendar_list: vec![],
+        };
+        let mut manager = TokenManager::new(config, reqwest::Client::new());
+        Arc::get_mut(&mut manager).unwrap().backoff = Duration::from_millis(10);
+        manager
+    }
+
+    fn stored(expires_in: TimeDelta) -> StoredToken {
+        StoredToken {
+            access_token: "old".to_string(),
+            refresh_token: Some("refresh".to_string()),
+            expires_at: Some(Utc::now() + expires_in),
+        }
+    }
+
+    fn mock(failures: usize, revoked: bool) -> Arc<Mock> {
+        Arc::new(Mock {
+            refreshes: AtomicUsize::new(0),
+            failures,
+            revoked,
+        })
+    }
+
+    #[tokio::test]
+    async fn refreshes_once_for_concurrent_callers() {
+        let mock = mock(0, false);
+        let google = mock_google(mock.clone()).await;
+
+        let fresh = manager(&google, "fresh", &stored(TimeDelta::hours(1)));
+        assert_eq!(fresh.access_token().await.unwrap(), "old");
+        assert_eq!(mock.refreshes.load(Ordering::SeqCst), 0);
+
+        // Within the skew counts as expired
+        let expiring = manager(&google, "expiring", &stored(TimeDelta::seconds(30)));
+        let (a, b, c) = tokio::join!(
+            expiring.access_token(),
+            expiring.access_token(),
+            expiring.access_token()
+        );
+        assert_eq!([a.unwrap(), b.unwrap(), c.unwrap()], ["access-0"; 3]);
+        assert_eq!(mock.refreshes.load(Ordering::SeqCst), 1);
+
+        let written = load(&expiring.config.token_path).unwrap();
+        assert_eq!(written.access_token, "access-0");
+        assert_eq!(written.refresh_token.as_deref(), Some("refresh"));
+    }
+
+    #[tokio::test]
+    async fn retries_transient_failures() {
+        let mock = mock(2, false);
+        let google = mock_google(mock.clone()).await;
+        let manager = manager(&google, "retries", &stored(-TimeDelta::hours(1)));
+        assert_eq!(manager.access_token().await.unwrap(), "access-2");
+        assert_eq!(mock.refreshes.load(Ordering::SeqCst), 3);
+
+        let mock = self::mock(REFRESH_ATTEMPTS as usize, false);
+        let google = mock_google(mock.clone()).await;
+        let manager = self::manager(&google, "gives-up", &stored(-TimeDelta::hours(1)));
+        assert!(matches!(
+            manager.access_token().await,
+            Err(ProviderError::Auth(_))
+        ));
+        // Tried again on the next fetch
+        assert_eq!(manager.access_token().await.unwrap(), "access-3");
+    }
+
+    #[tokio::test]
+    async fn revoked_refresh_token_requires_auth() {
+        let mock = mock(0, true);
+        let google = mock_google(mock.clone()).await;
+        let manager = manager(&google, "revoked", &stored(-TimeDelta::hours(1)));
+        assert!(matches!(
+            manager.access_token().await,
+            Err(ProviderError::AuthRequired)
+        ));
+        assert!(matches!(
+            manager.access_token().await,
+            Err(ProviderError::AuthRequired)
+        ));
+        // Nothing left to refresh with
+        assert_eq!(mock.refreshes.load(Ordering::SeqCst), 1);
+    }
+}
@@ -1,6 +1,8 @@
+use crate::provider::ProviderError;
 use crate::settings::Config;
 use chrono::{NaiveDate, TimeDelta};
-use serde::Deserialize;
+use log::warn;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::ops::Add;
 
@@ -29,30 +31,44 @@ struct Daily {
     weather_code: Vec<usize>,
 }
 
+#[derive(Serialize, Deserialize)]
 pub struct NiceDaily {
     pub sunshine: f64,
     pub temp_min: f64,
     pub temp_max: f64,
-    pub weather_code: usize,
+    pub weather_code: WeatherCode,
 }
 
+#[derive(Serialize, Deserialize)]
 pub struct NiceCurrent {
     pub temperature: f64,
     pub humidity: f64,
-    pub weather_code: usize,
+    pub weather_code: WeatherCode,
 }
 
+#[derive(Serialize, Deserialize)]
 pub struct NiceWeatherData {
     pub current: NiceCurrent,
     pub days: HashMap<NaiveDate, NiceDaily>,
 }
 
-impl From<WeatherData> for NiceWeatherData {
-    fn from(value: WeatherData) -> Self {
+impl TryFrom<WeatherData> for NiceWeatherData {
+    type Error = ProviderError;
+
+    fn try_from(value: WeatherData) -> Result<Self, Self::Error> {
+        let days = value.daily.weather_code.len();
+        if value.daily.temperature_min.len() != days
+            || value.daily.temperature_max.len() != days
+            || value.daily.sunshine_duration.len() != days
+        {
+            return Err(ProviderError::InvalidData(
+                "daily forecast arrays differ in length".to_string(),
+            ));
+        }
         let nc = NiceCurrent {
             temperature: value.current.temperature,
             humidity: value.current.humidity,
-            weather_code: value.current.weather_code,
+            weather_code: value.current.weather_code.into(),
         };
         let mut nd: HashMap<NaiveDate, NiceDaily> = HashMap::new();
         let today = chrono::Local::now().date_naive();
@@ -63,21 +79,66 @@ impl From<WeatherData> for NiceWeatherData {
                     temp_min: value.daily.temperature_min[i],
                     temp_max: value.daily.temperature_max[i],
                     sunshine: value.daily.sunshine_duration[i],
-                    weather_code: value.daily.weather_code[i],
+                    weather_code: value.daily.weather_code[i].into(),
                 },
             );
         }
-        NiceWeatherData {
+        Ok(NiceWeatherData {
             current: nc,
             days: nd,
+        })
+    }
+}
+
+// A WMO code, codes we have no description for are kept as Unknown instead of
+// failing the whole forecast. Stored as the bare number.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(from = "usize", into = "usize")]
+pub enum WeatherCode {
+    Known(usize),
+    Unknown(usize),
+}
+
+impl From<usize> for WeatherCode {
+    fn from(code: usize) -> Self {
+        if wmo_description(code).is_some() {
+            WeatherCode::Known(code)
+        } else {
+            warn!("Unknown WMO code {}", code);
+            WeatherCode::Unknown(code)
+        }
+    }
+}
+
+impl From<WeatherCode> for usize {
+    fn from(code: WeatherCode) -> Self {
+        match code {
+            WeatherCode::Known(code) | WeatherCode::Unknown(code) => code,
+        }
+    }
+}
+
+impl WeatherCode {
+    pub fn description(self) -> &'static str {
+        match self {
+            WeatherCode::Known(code) => wmo_description(code).unwrap_or("Unknown"),
+            WeatherCode::Unknown(_) => "Unknown",
         }
     }
+
+    // Heavy precipitation, violent showers and thunderstorms, worth a highlight
+    pub fn is_severe(self) -> bool {
+        matches!(
+            self,
+            WeatherCode::Known(65 | 67 | 75 | 82 | 86 | 95 | 96 | 99)
+        )
+    }
 }
 
 // See https://open-meteo.com/en/docs -> WMO Weather interpretation codes (WW)
 // and https://www.nodc.noaa.gov/archive/arc0021/0002199/1.1/data/0-data/HTML/WMO-CODE/WMO4677.HTM
-pub fn wmo_weather_code_to_str(code: usize) -> &'static str {
-    match code {
+fn wmo_description(code: usize) -> Option<&'static str> {
+    Some(match code {
         0 => "Clear sky",
         1 => "Mainly clear",
         2 => "Partly cloudy",
@@ -106,8 +167,8 @@ pub fn wmo_weather_code_to_str(code: usize) -> &'static str {
         95 => "Slight or moderate thunderstorm",
         96 => "Thunderstorm with slight hail",
         99 => "Thunderstorm with heavy hail",
-        _ => panic!("Unknown WMO code: {}", code),
-    }
+        _ => return None,
+    })
 }
 
 pub struct WeatherProvider {
@@ -123,7 +184,7 @@ impl WeatherProvider {
         }
     }
 
-    pub async fn check_sky(&self) -> NiceWeatherData {
+    pub async fn check_sky(&self) -> Result<NiceWeatherData, ProviderError> {
         let base_url = format!(
             "https://api.open-meteo.com/v1/forecast?latitude={}&longitude={}&daily=sunshine_duration,temperature_2m_max,temperature_2m_min,\
         weather_code&models=best_match&current=temperature_2m,relative_humidity_2m,\
@@ -137,12 +198,45 @@ impl WeatherProvider {
             .http_client
             .get(base_url)
             .send()
-            .await
-            .expect("Could not fetch weather")
+            .await?
+            .error_for_status()?
             .json::<WeatherData>()
-            .await
-            .expect("Weather data was invalid JSON");
+            .await?;
 
-        weather.into()
+        weather.try_into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unknown_codes_keep_the_forecast() {
+        let data: WeatherData = serde_json::from_str(
+            r#"{
+                "current": {"temperature_2m": 12.5, "relative_humidity_2m": 80, "weather_code": 42},
+                "daily": {
+                    "sunshine_duration": [3600, 0],
+                    "temperature_2m_max": [14, 9],
+                    "temperature_2m_min": [5, 2],
+                    "weather_code": [95, 1234]
+                }
+            }"#,
+        )
+        .unwrap();
+        let weather = NiceWeatherData::try_from(data).unwrap();
+        assert_eq!(weather.current.weather_code, WeatherCode::Unknown(42));
+        assert_eq!(weather.current.weather_code.description(), "Unknown");
+
+        let mut days: Vec<_> = weather.days.into_iter().collect();
+        days.sort_by_key(|(date, _)| *date);
+        assert!(days[0].1.weather_code.is_severe());
+        assert!(!days[1].1.weather_code.is_severe());
+        // Cached as the bare code
+        assert_eq!(
+            serde_json::to_string(&days[1].1.weather_code).unwrap(),
+            "1234"
+        );
     }
 }
@@ -1,606 +1,214 @@
-use crate::provider::google::{CalendarProvider, Event, Time};
-use crate::provider::image::ImageProvider;
-use crate::provider::quote::QuoteProvider;
-use crate::provider::weather::{NiceDaily, WeatherProvider, wmo_weather_code_to_str};
-use crate::render::epd::{Area, EPD_HEIGHT, EPD_WIDTH, EpdImage, Outline, Padding};
-use crate::render::fonts::{Font, FontCollection};
+use crate::render::diff;
+use crate::render::dither::Dither;
+use crate::render::epd::{Area, EpdImage, Outline, Padding, PixelFormat};
+use crate::render::fonts::FontCollection;
 use crate::render::graphics::{Color, Rect};
-use crate::settings::Config;
-use chrono::{NaiveDate, TimeDelta};
-use fontdue::layout::{HorizontalAlign, LayoutSettings, TextStyle, VerticalAlign};
+use crate::render::history::FrameHistory;
+use crate::render::layout::LayoutNode;
+use crate::settings::{Config, DeviceConfig};
+use crate::widget::{WidgetRegistry, schedule};
+use crate::wire;
+use chrono::Local;
 use image::imageops;
 use log::{debug, info};
 use reqwest::multipart;
-use std::collections::{BTreeSet, HashMap};
 use std::fs;
-use std::ops::Add;
+use std::sync::Arc;
+use std::time::Duration;
 
 pub struct Dash {
-    previous_frame: Option<EpdImage>,
+    frames: FrameHistory,
     partial_update_counter: usize,
-    calendar_provider: CalendarProvider,
-    quote_provider: QuoteProvider,
-    image_provider: ImageProvider,
-    weather_provider: WeatherProvider,
+    widgets: Arc<WidgetRegistry>,
+    layout: LayoutNode,
     font_collection: FontCollection,
     config: Config,
+    // Previews are written to {preview_name}.png and .bin
+    preview_name: String,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Eq, PartialEq)]
 pub enum RenderAction {
     Full(Vec<u8>),
+    // 2bpp frame, the panel has no partial refresh in 4-gray mode
+    FullGray4(Vec<u8>),
+    // Both planes of a tri-color frame, no partial refresh there either
+    FullTriColor { black: Vec<u8>, red: Vec<u8> },
     // bbox and data
     Partial(Rect, Vec<u8>),
+    // disjoint rects, each with its own data
+    MultiPartial(Vec<(Rect, Vec<u8>)>),
+}
+
+pub enum Rendered {
+    // The device already shows exactly this frame, nothing to send
+    Unchanged {
+        etag: String,
+    },
+    Frame {
+        id: u32,
+        etag: String,
+        action: RenderAction,
+    },
+}
+
+// If-None-Match holds a comma separated list of (possibly weak) tags or *
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    if_none_match
+        .split(',')
+        .map(|tag| tag.trim())
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
 }
 
 impl Dash {
-    pub async fn new(config: Config) -> Self {
+    // The widgets are kept fresh elsewhere, see WidgetRegistry::spawn_refresh
+    pub fn new(config: Config, widgets: Arc<WidgetRegistry>) -> Self {
+        let panel = &config.panel;
+        let (width, height) = panel.transform().logical_size(panel.width, panel.height);
         Self {
+            layout: LayoutNode::load(config.general.layout_path.as_deref(), width, height)
+                .expect("Could not load layout"),
             config: config.clone(),
-            previous_frame: None,
+            frames: FrameHistory::default(),
             partial_update_counter: 0,
-            calendar_provider: CalendarProvider::new(config.clone()).await,
-            quote_provider: QuoteProvider::new(config.quote.clone()),
-            image_provider: ImageProvider::new(config.clone()),
-            weather_provider: WeatherProvider::new(config.clone()),
+            widgets,
             font_collection: FontCollection::new(),
+            preview_name: "output".to_string(),
         }
     }
 
-    async fn create_calendar_day_grouped(&mut self, cal: &mut Area) {
-        // This should be possible without the clone, no?
-        let date_font = self.font_collection.load_font(Font::Wellfleet);
-        let title_font = self.font_collection.load_font(Font::Dina);
-
-        let mut events_per_day: HashMap<NaiveDate, Vec<Event>> = HashMap::new();
-        let mut dates: BTreeSet<NaiveDate> = BTreeSet::new();
-        self.calendar_provider
-            .fetch()
-            .await
-            .into_iter()
-            .for_each(|e| match e.time {
-                Time::AllDay(nd) => {
-                    dates.insert(nd);
-                    if let std::collections::hash_map::Entry::Vacant(entry) =
-                        events_per_day.entry(nd)
-                    {
-                        entry.insert(vec![e]);
-                    } else {
-                        events_per_day.get_mut(&nd).unwrap().push(e);
-                    }
-                }
-                Time::Timed(dt, _) => {
-                    dates.insert(dt.date_naive());
-                    if let std::collections::hash_map::Entry::Vacant(entry) =
-                        events_per_day.entry(dt.date_naive())
-                    {
-                        entry.insert(vec![e]);
-                    } else {
-                        events_per_day.get_mut(&dt.date_naive()).unwrap().push(e);
-                    }
-                }
-            });
-
-        const DATE_EVENT_PADDING: usize = 2;
-        const DATE_HEIGHT: usize = 32;
-        const EVENT_HEIGHT: usize = 24;
-        const EVENT_PADDING: usize = 2;
-        const TITLE_MAX_LENGTH: usize = 16;
-        let fit_title = |title: &str| {
-            if title.len() > TITLE_MAX_LENGTH {
-                format!("{}>", &title[0..TITLE_MAX_LENGTH])
-            } else {
-                title.to_string()
-            }
-        };
-
-        let mut cur_y = cal.get_vstart();
-
-        for date in dates {
-            // Take until we can still fit a date + event
-            if (cur_y + DATE_HEIGHT + DATE_EVENT_PADDING + EVENT_HEIGHT)
-                >= cal.get_available_vspace()
-            {
-                break;
-            }
-
-            let mut date_area = Area::new(
-                0,
-                cur_y,
-                cal.get_available_hspace(),
-                DATE_HEIGHT,
-                Color::White,
-                Padding::full(0),
-                Outline::default(),
-            );
-            date_area.put_text(
-                &date_font,
-                LayoutSettings {
-                    x: date_area.get_hstart() as f32,
-                    y: date_area.get_vstart() as f32,
-                    max_width: Some(date_area.get_available_hspace() as f32),
-                    max_height: Some(date_area.get_available_vspace() as f32),
-                    horizontal_align: HorizontalAlign::Center,
-                    vertical_align: VerticalAlign::Middle,
-                    ..LayoutSettings::default()
-                },
-                &[TextStyle::new(
-                    date.format("%a, %-d. %b").to_string().as_str(),
-                    23.0,
-                    0,
-                )],
-                50,
-            );
-            cal.add_sub_area(date_area);
-
-            cur_y += DATE_HEIGHT + DATE_EVENT_PADDING;
-
-            for event in events_per_day.get(&date).unwrap().iter() {
-                if (cur_y + EVENT_HEIGHT) >= cal.get_available_vspace() {
-                    break;
-                }
-
-                let mut event_area = Area::new(
-                    0,
-                    cur_y,
-                    cal.get_available_hspace(),
-                    EVENT_HEIGHT,
-                    Color::White,
-                    Padding::full(0),
-                    Outline::none(),
-                );
-
-                let text = match event.time {
-                    Time::AllDay(_) => &event.title,
-                    Time::Timed(dt, _) => &format!("{} {}", dt.format("%H:%M"), event.title),
-                };
-
-                event_area.put_text(
-                    &title_font,
-                    LayoutSettings {
-                        x: event_area.get_hstart() as f32,
-                        y: event_area.get_vstart() as f32,
-                        max_width: Some(event_area.get_available_hspace() as f32),
-                        max_height: Some(event_area.get_available_vspace() as f32),
-                        horizontal_align: HorizontalAlign::Left,
-                        vertical_align: VerticalAlign::Middle,
-                        ..LayoutSettings::default()
-                    },
-                    &[TextStyle::new(fit_title(text).as_str(), 22.0, 0)],
-                    20,
-                );
-                cal.add_sub_area(event_area);
-
-                cur_y += EVENT_HEIGHT + EVENT_PADDING;
-            }
+    // Keeps its own frame history, so devices never see each other's diffs
+    pub fn for_device(
+        config: &Config,
+        device: &DeviceConfig,
+        widgets: Arc<WidgetRegistry>,
+    ) -> Self {
+        Self {
+            preview_name: format!("output-{}", device.id),
+            ..Self::new(config.for_device(device), widgets)
         }
     }
 
-    fn create_quote(&mut self, quote_area: &mut Area) {
-        let quote = self.quote_provider.get_quote();
-
-        quote_area.auto_layout_text_size(
-            &self.font_collection.load_font(Font::Wellfleet),
-            LayoutSettings {
-                x: quote_area.get_hstart() as f32,
-                y: quote_area.get_vstart() as f32,
-                max_height: Some(quote_area.get_available_vspace() as f32),
-                max_width: Some(quote_area.get_available_hspace() as f32),
-                ..LayoutSettings::default()
-            },
-            &[TextStyle::new(&quote.content, 1f32, 0)],
-            100,
-            30f32,
-        );
-    }
-
-    fn create_image(&mut self, image_area: &mut Area) {
-        let image = self.image_provider.get_image();
-        let resized = image.resize(
-            image_area.get_available_hspace() as u32,
-            image_area.get_available_vspace() as u32,
-            imageops::FilterType::Nearest,
-        );
-        let x_off = (image_area.get_available_hspace() - resized.width() as usize) / 2;
-        let y_off = (image_area.get_available_vspace() - resized.height() as usize) / 2;
-
-        image_area.load_image(x_off, y_off, &resized);
+    pub fn preview_path(&self) -> String {
+        format!("{}.png", self.preview_name)
     }
 
-    async fn create_weather(&mut self, weather_area: &mut Area) {
-        let weather = self.weather_provider.check_sky().await;
-
-        let day_font = self.font_collection.load_font(Font::Wellfleet);
-        let weather_font = self.font_collection.load_font(Font::Dina);
-        let mut y_off = 0;
-        const DAY_NAME_STEP_SIZE: usize = 28;
-        weather_area.put_text(
-            &day_font,
-            LayoutSettings {
-                x: weather_area.get_hstart() as f32,
-                y: y_off as f32,
-                max_width: Some(weather_area.get_available_hspace() as f32),
-                max_height: Some(weather_area.get_available_vspace() as f32),
-                horizontal_align: HorizontalAlign::Center,
-                vertical_align: VerticalAlign::Top,
-                ..LayoutSettings::default()
-            },
-            &[TextStyle::new("Now", 23.0, 0)],
-            100,
-        );
-        y_off += DAY_NAME_STEP_SIZE;
-
-        let mut now_area = Area::new(
-            0,
-            y_off,
-            weather_area.get_available_hspace(),
-            50,
-            Color::White,
-            Padding::full(0),
-            Outline {
-                left: 0,
-                right: 0,
-                color: Color::Black,
-                top: 1,
-                bottom: 1,
-            },
-        );
-        now_area.auto_layout_text_size(
-            &weather_font,
-            LayoutSettings {
-                x: now_area.get_hstart() as f32,
-                y: 0.0,
-                max_width: Some(now_area.get_available_hspace() as f32),
-                max_height: Some((now_area.get_available_vspace()) as f32),
-                horizontal_align: HorizontalAlign::Center,
-                ..LayoutSettings::default()
-            },
-            &[TextStyle::new(
-                wmo_weather_code_to_str(weather.current.weather_code),
-                24.0,
-                0,
-            )],
-            40,
-            24.0,
-        );
-        now_area.put_text(
-            &weather_font,
-            LayoutSettings {
-                x: now_area.get_hstart() as f32,
-                y: 28.0,
-                max_width: Some(now_area.get_available_hspace() as f32),
-                max_height: Some((now_area.get_available_vspace()) as f32),
-                horizontal_align: HorizontalAlign::Center,
-                ..LayoutSettings::default()
-            },
-            &[TextStyle::new(
-                format!(
-                    "{}°C {}%",
-                    weather.current.temperature, weather.current.humidity
-                )
-                .as_str(),
-                20.0,
-                0,
-            )],
-            40,
-        );
-
-        y_off += now_area.space.height;
-        weather_area.add_sub_area(now_area);
-
-        let tomorrow = weather
-            .days
-            .get(&chrono::Local::now().date_naive().add(TimeDelta::days(1)))
-            .expect("There is no tomorrow");
-        let day_after_tmrw = weather
-            .days
-            .get(&chrono::Local::now().date_naive().add(TimeDelta::days(2)))
-            .expect("There is no day after tomorrow");
-
-        let mut show_weather_for_day = |day: &NiceDaily, name: &str| {
-            weather_area.put_text(
-                &day_font,
-                LayoutSettings {
-                    x: weather_area.get_hstart() as f32,
-                    y: y_off as f32,
-                    max_width: Some(weather_area.get_available_hspace() as f32),
-                    max_height: Some(weather_area.get_available_vspace() as f32),
-                    horizontal_align: HorizontalAlign::Center,
-                    ..LayoutSettings::default()
-                },
-                &[TextStyle::new(name, 23.0, 0)],
-                100,
-            );
-
-            y_off += DAY_NAME_STEP_SIZE;
-
-            let mut day_area = Area::new(
-                0,
-                y_off,
-                weather_area.get_available_hspace(),
-                50,
-                Color::White,
-                Padding::full(0),
-                Outline {
-                    left: 0,
-                    right: 0,
-                    color: Color::Black,
-                    top: 1,
-                    bottom: 1,
-                },
-            );
-
-            let max_wmo_size = if wmo_weather_code_to_str(day.weather_code).len() >= 19 {
-                20.0
-            } else {
-                24.0
-            };
-
-            day_area.auto_layout_text_size(
-                &weather_font,
-                LayoutSettings {
-                    x: day_area.get_hstart() as f32,
-                    y: 0.0,
-                    max_width: Some(day_area.get_available_hspace() as f32),
-                    max_height: Some(18f32),
-                    horizontal_align: HorizontalAlign::Center,
-                    ..LayoutSettings::default()
-                },
-                &[TextStyle::new(
-                    wmo_weather_code_to_str(day.weather_code),
-                    0.0,
-                    0,
-                )],
-                40,
-                max_wmo_size,
-            );
-            day_area.put_text(
-                &weather_font,
-                LayoutSettings {
-                    x: day_area.get_hstart() as f32,
-                    y: 28.0,
-                    max_width: Some(day_area.get_available_hspace() as f32),
-                    max_height: Some((day_area.get_available_vspace()) as f32),
-                    horizontal_align: HorizontalAlign::Center,
-                    ..LayoutSettings::default()
-                },
-                &[TextStyle::new(
-                    format!(
-                        "{:05.2}°C-{:04.2}°C {:02}h",
-                        day.temp_min,
-                        day.temp_max,
-                        (day.sunshine / 3600f64).round() as usize
-                    )
-                    .as_str(),
-                    20.0,
-                    0,
-                )],
-                40,
-            );
-            y_off += day_area.space.height;
-            weather_area.add_sub_area(day_area);
-        };
-
-        show_weather_for_day(tomorrow, "Tomorrow");
-        show_weather_for_day(day_after_tmrw, "Tomorrow++");
+    // Width and height of the panel in pixels
+    pub fn size(&self) -> (usize, usize) {
+        (self.config.panel.width, self.config.panel.height)
     }
 
-    async fn create_dashboard(&mut self) -> EpdImage {
-        let mut image = EpdImage::new(EPD_WIDTH, EPD_HEIGHT);
-
-        let font = self.font_collection.load_font(Font::Wellfleet);
-
-        let mut total = Area::new(
-            0,
-            0,
-            EPD_WIDTH,
-            EPD_HEIGHT,
-            Color::White,
-            Padding::full(0),
-            Outline::none(),
-        );
-
-        let mut left_column = Area::new(
-            0,
-            0,
-            200,
-            total.get_available_vspace(),
-            Color::Black,
-            Padding::full(0),
-            Outline::none(),
-        );
-
-        let mut right_column = Area::new(
-            left_column.offset.x + left_column.space.width,
-            0,
-            total.get_available_hspace() - left_column.space.width,
-            total.get_available_vspace(),
-            Color::White,
-            Padding::full(0),
-            Outline::none(),
-        );
-
-        let mut quote_area = Area::new(
-            0,
-            right_column.get_available_vspace() - 140,
-            right_column.get_available_hspace(),
-            140,
-            Color::White,
-            Padding::full(2),
-            Outline {
-                right: 0,
-                bottom: 0,
-                left: 0, // borders left column
-                top: 1,
-                color: Color::Black,
-            },
-        );
-
-        self.create_quote(&mut quote_area);
-
-        let mut misc_column = Area::new(
-            right_column.get_available_hspace() - 100,
-            0,
-            100,
-            // right_column.get_available_vspace() - quote_area.space.height,
-            40,
-            Color::White,
-            Padding::full(0),
-            Outline {
-                color: Color::Black,
-                bottom: 1,
-                top: 0,
-                left: 1,
-                right: 0,
-            },
-        );
-
-        let mut image_area = Area::new(
-            0,
-            0,
-            // right_column.get_available_hspace() - misc_column.space.width,
-            right_column.get_available_hspace(),
-            right_column.get_available_vspace() - quote_area.space.height,
-            Color::White,
-            Padding::full(0),
-            Outline::none(),
-        );
-        self.create_image(&mut image_area);
-        right_column.add_sub_area(image_area);
-
-        let now = chrono::Local::now();
-        let now_str = now.format("%H:%M").to_string();
-        misc_column.put_text(
-            &font,
-            LayoutSettings {
-                max_width: Some(misc_column.get_available_hspace() as f32),
-                max_height: Some(misc_column.get_available_vspace() as f32),
-                horizontal_align: HorizontalAlign::Center,
-                ..LayoutSettings::default()
+    // Upright, see render::transform for how it gets onto the panel
+    fn create_dashboard(&mut self) -> EpdImage {
+        let panel = &self.config.panel;
+        let (width, height) = panel.transform().logical_size(panel.width, panel.height);
+        let mut image = EpdImage::new(width, height, panel.format);
+
+        let total = self.layout.build(
+            Rect {
+                x: 0,
+                y: 0,
+                width: image.width(),
+                height: image.height(),
             },
-            &[TextStyle::new(now_str.as_str(), 32f32, 0)],
-            110,
+            panel.format,
+            &self.widgets,
+            &mut self.font_collection,
         );
-
-        right_column.add_sub_area(quote_area);
-        right_column.add_sub_area(misc_column);
-
-        let mut calendar_area = Area::new(
-            0,
-            0,
-            left_column.get_available_hspace(),
-            left_column.get_available_vspace() / 2,
-            Color::White,
-            Padding::full(2),
-            Outline {
-                top: 0,
-                bottom: 1,
-                left: 0,
-                right: 1,
-                color: Color::Black,
-            },
-        );
-        self.create_calendar_day_grouped(&mut calendar_area).await;
-
-        let mut weather_area = Area::new(
-            0,
-            left_column.get_available_vspace() / 2,
-            left_column.get_available_hspace(),
-            left_column.get_available_vspace() / 2,
-            Color::White,
-            Padding::full(2),
-            Outline {
-                top: 1,
-                bottom: 0,
-                left: 0,
-                right: 1,
-                color: Color::Black,
-            },
-        );
-        self.create_weather(&mut weather_area).await;
-
-        left_column.add_sub_area(calendar_area);
-        left_column.add_sub_area(weather_area);
-
-        total.add_sub_area(left_column);
-        total.add_sub_area(right_column);
-
         total.draw(&mut image);
 
         image
     }
 
-    fn get_change_bbox(&self, current: &EpdImage) -> Option<Rect> {
-        fn round_to_mul(n: usize) -> usize {
-            n.div_ceil(8) * 8
-        }
+    // How long the device can sleep before the dashboard changes
+    pub fn sleep_hint(&self) -> Duration {
+        let now = Local::now();
+        let next_change = self.widgets.next_change(self.layout.widgets(), now);
+        schedule::sleep_hint(&now, next_change, &self.config.sleep)
+    }
 
-        if let Some(previous) = &self.previous_frame {
-            let mut xmin = EPD_WIDTH;
-            let mut ymin = EPD_HEIGHT;
-            let mut xmax = 0;
-            let mut ymax = 0;
+    // The device showed the frame with this id, see FrameHistory
+    pub fn acknowledge(&mut self, frame_id: u32) {
+        self.frames.acknowledge(frame_id);
+    }
 
-            let mut change = false;
-            for y in 0..EPD_HEIGHT {
-                for x in 0..EPD_WIDTH {
-                    if current.get_pixel(x, y) != previous.get_pixel(x, y) {
-                        xmin = xmin.min(x);
-                        xmax = xmax.max(x);
-                        ymin = ymin.min(y);
-                        ymax = ymin.max(y);
-                        change = true;
-                    }
-                }
-            }
+    // Only composes whatever data the widgets currently hold, never waits on the network.
+    // if_none_match is the raw If-None-Match header of the request, if any.
+    pub fn render(&mut self, force_full: bool, if_none_match: Option<&str>) -> Rendered {
+        let logical = self.create_dashboard();
+        logical.to_img_file(&self.preview_path());
 
-            if !change {
-                return None;
-            }
+        // Diffs are taken on what the panel gets, so the rects need no mapping back
+        let current = self.config.panel.transform().apply(logical);
+        current.to_file(&format!("{}.bin", self.preview_name));
 
-            Some(Rect {
-                x: round_to_mul(xmin),
-                y: round_to_mul(ymin),
-                width: round_to_mul(xmax - xmin),
-                height: round_to_mul(ymax - ymin),
-            })
-        } else {
-            None
+        let etag = format!("\"{:08x}\"", current.content_hash());
+        if !force_full && if_none_match.is_some_and(|tags| etag_matches(tags, &etag)) {
+            debug!("Frame unchanged ({})", etag);
+            return Rendered::Unchanged { etag };
         }
-    }
 
-    pub async fn render(&mut self, force_full: bool) -> RenderAction {
-        const MAX_PARTIAL_AREA_UPDATE_PX: usize = 200 * 50;
-        const MAX_PARTIAL_UPDATES: usize = 10;
-
-        let current = self.create_dashboard().await;
+        let action = self.render_action(&current, force_full);
+        Rendered::Frame {
+            id: self.frames.push(current),
+            etag,
+            action,
+        }
+    }
 
-        current.to_img_file("output.png");
-        current.to_file("output.bin");
+    fn render_action(&mut self, current: &EpdImage, force_full: bool) -> RenderAction {
+        match current.format() {
+            PixelFormat::Mono => {}
+            PixelFormat::Gray4 => return RenderAction::FullGray4(current.data()),
+            PixelFormat::TriColor => {
+                let (black, red) = current.planes();
+                return RenderAction::FullTriColor {
+                    black: black.to_vec(),
+                    red: red.to_vec(),
+                };
+            }
+        }
 
-        let raw_data = current.data().clone();
+        let dirty_rects = match self.frames.base() {
+            Some(previous) if !force_full => diff::dirty_rects(
+                previous.raw(),
+                current.raw(),
+                current.width(),
+                current.height(),
+                self.config.render.max_dirty_rects.min(wire::MAX_RECTS),
+            ),
+            _ => vec![],
+        };
 
-        let action = if !force_full && let Some(bbox) = self.get_change_bbox(&current) {
-            debug!("change bbox: {:?}", bbox);
+        let action = if !dirty_rects.is_empty() {
+            debug!("dirty rects: {:?}", dirty_rects);
 
-            if (bbox.width * bbox.height) < MAX_PARTIAL_AREA_UPDATE_PX {
-                if self.partial_update_counter < MAX_PARTIAL_UPDATES {
-                    info!("Sending partial update {:?}", bbox);
+            let dirty_area: usize = dirty_rects.iter().map(Rect::area).sum();
+            if dirty_area < self.config.render.max_partial_area {
+                if self.partial_update_counter < self.config.render.max_partial_updates {
+                    info!("Sending partial update {:?}", dirty_rects);
                     self.partial_update_counter += 1;
-                    RenderAction::Partial(bbox, current.to_partial_from_rect(bbox))
+                    if let [bbox] = dirty_rects.as_slice() {
+                        RenderAction::Partial(*bbox, current.to_partial_from_rect(*bbox))
+                    } else {
+                        RenderAction::MultiPartial(
+                            dirty_rects
+                                .into_iter()
+                                .map(|r| (r, current.to_partial_from_rect(r)))
+                                .collect(),
+                        )
+                    }
                 } else {
                     self.partial_update_counter = 0;
-                    RenderAction::Full(raw_data)
+                    RenderAction::Full(current.data())
                 }
             } else {
-                RenderAction::Full(raw_data)
+                RenderAction::Full(current.data())
             }
         } else {
-            RenderAction::Full(raw_data)
+            RenderAction::Full(current.data())
         };
 
-        self.previous_frame = Some(current);
-
         debug!("Render complete");
         // uhhhh
         action
@@ -637,17 +245,19 @@ impl Dash {
                     FRAME_HEIGHT as u32,
                     imageops::FilterType::Nearest,
                 );
-            let mut img = EpdImage::new(EPD_WIDTH, EPD_HEIGHT);
+            let (width, height) = self.size();
+            let mut img = EpdImage::new(width, height, PixelFormat::Mono);
             let mut whole = Area::new(
                 0,
                 0,
-                EPD_WIDTH,
-                EPD_HEIGHT,
+                width,
+                height,
                 Color::White,
                 Padding::full(0),
                 Outline::none(),
             );
-            whole.load_image(0, 0, &frame);
+            whole.set_format(PixelFormat::Mono);
+            whole.load_image(0, 0, &frame, Dither::Threshold);
             whole.draw(&mut img);
 
             let abc = img.to_partial(0, 0, FRAME_WIDTH, FRAME_HEIGHT);
@@ -678,3 +288,18 @@ impl Dash {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_etags() {
+        let etag = "\"0badf00d\"";
+        assert!(etag_matches("\"0badf00d\"", etag));
+        assert!(etag_matches("\"12345678\", W/\"0badf00d\"", etag));
+        assert!(etag_matches("*", etag));
+        assert!(!etag_matches("\"12345678\"", etag));
+        assert!(!etag_matches("0badf00d", etag));
+    }
+}
@@ -0,0 +1,368 @@
+// Frame diffing on packed 1bpp data (MSB first, rows padded to whole bytes).
+// Every returned rect covers all changed pixels it is responsible for and has
+// x and width aligned to 8, except where it is clipped by the right edge.
+
+use crate::render::graphics::Rect;
+
+// Rows per tile when grouping changes into rects; columns are always one byte
+const TILE_HEIGHT: usize = 8;
+
+fn stride(width: usize) -> usize {
+    width.div_ceil(8)
+}
+
+// Pixel rect for the byte columns [col_start, col_end) and rows [row_start, row_end)
+fn byte_rect(
+    col_start: usize,
+    col_end: usize,
+    row_start: usize,
+    row_end: usize,
+    width: usize,
+) -> Rect {
+    Rect {
+        x: col_start * 8,
+        y: row_start,
+        width: (col_end * 8).min(width) - col_start * 8,
+        height: row_end - row_start,
+    }
+}
+
+pub fn bounding_box(previous: &[u8], current: &[u8], width: usize, height: usize) -> Option<Rect> {
+    let stride = stride(width);
+    assert_eq!(previous.len(), stride * height);
+    assert_eq!(current.len(), stride * height);
+
+    let mut bbox: Option<(usize, usize, usize, usize)> = None;
+    for (row, (prev_row, cur_row)) in previous
+        .chunks_exact(stride)
+        .zip(current.chunks_exact(stride))
+        .enumerate()
+    {
+        let Some(first) = prev_row.iter().zip(cur_row).position(|(p, c)| p != c) else {
+            continue;
+        };
+        let last = prev_row
+            .iter()
+            .zip(cur_row)
+            .rposition(|(p, c)| p != c)
+            .unwrap();
+
+        bbox = Some(match bbox {
+            None => (first, last, row, row),
+            Some((cmin, cmax, rmin, _)) => (cmin.min(first), cmax.max(last), rmin, row),
+        });
+    }
+
+    bbox.map(|(cmin, cmax, rmin, rmax)| byte_rect(cmin, cmax + 1, rmin, rmax + 1, width))
+}
+
+// Groups changed bytes into at most max_rects disjoint rects
+pub fn dirty_rects(
+    previous: &[u8],
+    current: &[u8],
+    width: usize,
+    height: usize,
+    max_rects: usize,
+) -> Vec<Rect> {
+    let stride = stride(width);
+    assert_eq!(previous.len(), stride * height);
+    assert_eq!(current.len(), stride * height);
+
+    let cols = stride;
+    let rows = height.div_ceil(TILE_HEIGHT);
+    let mut dirty = vec![vec![false; cols]; rows];
+    for (i, (p, c)) in previous.iter().zip(current).enumerate() {
+        if p != c {
+            dirty[i / stride / TILE_HEIGHT][i % stride] = true;
+        }
+    }
+
+    // Bounding boxes of connected dirty tiles, in tile coordinates
+    let mut rects: Vec<Rect> = vec![];
+    let mut seen = vec![vec![false; cols]; rows];
+    for ty in 0..rows {
+        for tx in 0..cols {
+            if !dirty[ty][tx] || seen[ty][tx] {
+                continue;
+            }
+            let mut bbox = Rect {
+                x: tx,
+                y: ty,
+                width: 1,
+                height: 1,
+            };
+            let mut stack = vec![(tx, ty)];
+            seen[ty][tx] = true;
+            while let Some((cx, cy)) = stack.pop() {
+                bbox = bbox.union(&Rect {
+                    x: cx,
+                    y: cy,
+                    width: 1,
+                    height: 1,
+                });
+                for (nx, ny) in [
+                    (cx.wrapping_sub(1), cy),
+                    (cx + 1, cy),
+                    (cx, cy.wrapping_sub(1)),
+                    (cx, cy + 1),
+                ] {
+                    if nx < cols && ny < rows && dirty[ny][nx] && !seen[ny][nx] {
+                        seen[ny][nx] = true;
+                        stack.push((nx, ny));
+                    }
+                }
+            }
+            rects.push(bbox);
+        }
+    }
+
+    // Boxes of different components may still overlap
+    fn merge_overlapping(rects: &mut Vec<Rect>) {
+        'restart: loop {
+            for i in 0..rects.len() {
+                for j in (i + 1)..rects.len() {
+                    if rects[i].intersects(&rects[j]) {
+                        rects[i] = rects[i].union(&rects[j]);
+                        rects.swap_remove(j);
+                        continue 'restart;
+                    }
+                }
+            }
+            break;
+        }
+    }
+    merge_overlapping(&mut rects);
+
+    let max_rects = max_rects.max(1);
+    while rects.len() > max_rects {
+        // Merge the pair that wastes the least area
+        let mut best = (0, 1, usize::MAX);
+        for i in 0..rects.len() {
+            for j in (i + 1)..rects.len() {
+                let waste = rects[i].union(&rects[j]).area() - rects[i].area() - rects[j].area();
+                if waste < best.2 {
+                    best = (i, j, waste);
+                }
+            }
+        }
+        rects[best.0] = rects[best.0].union(&rects[best.1]);
+        rects.swap_remove(best.1);
+        merge_overlapping(&mut rects);
+    }
+
+    // Tiles to pixels
+    rects
+        .into_iter()
+        .map(|r| {
+            byte_rect(
+                r.x,
+                r.x + r.width,
+                r.y * TILE_HEIGHT,
+                ((r.y + r.height) * TILE_HEIGHT).min(height),
+                width,
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn frame(width: usize, height: usize) -> Vec<u8> {
+        vec![0xFF; stride(width) * height]
+    }
+
+    fn flip(data: &mut [u8], width: usize, x: usize, y: usize) {
+        data[y * stride(width) + x / 8] ^= 1 << (7 - x % 8);
+    }
+
+    fn changed_pixels(
+        previous: &[u8],
+        current: &[u8],
+        width: usize,
+        height: usize,
+    ) -> Vec<(usize, usize)> {
+        let stride = stride(width);
+        let bit = |data: &[u8], x: usize, y: usize| (data[y * stride + x / 8] >> (7 - x % 8)) & 1;
+        (0..height)
+            .flat_map(|y| (0..width).map(move |x| (x, y)))
+            .filter(|&(x, y)| bit(previous, x, y) != bit(current, x, y))
+            .collect()
+    }
+
+    fn contains(rect: &Rect, (x, y): (usize, usize)) -> bool {
+        x >= rect.x && x < rect.x + rect.width && y >= rect.y && y < rect.y + rect.height
+    }
+
+    fn assert_aligned(rect: &Rect, width: usize, height: usize) {
+        assert_eq!(rect.x % 8, 0, "{rect:?}");
+        assert!(rect.width > 0 && rect.height > 0, "{rect:?}");
+        assert!(
+            rect.x + rect.width <= width && rect.y + rect.height <= height,
+            "{rect:?}"
+        );
+        assert!(
+            (rect.x + rect.width).is_multiple_of(8) || rect.x + rect.width == width,
+            "{rect:?}"
+        );
+    }
+
+    #[test]
+    fn no_change() {
+        let f = frame(800, 480);
+        assert_eq!(bounding_box(&f, &f, 800, 480), None);
+        assert!(dirty_rects(&f, &f, 800, 480, 4).is_empty());
+    }
+
+    #[test]
+    fn bbox_rounds_start_down() {
+        let previous = frame(800, 480);
+        let mut current = previous.clone();
+        flip(&mut current, 800, 3, 10);
+        assert_eq!(
+            bounding_box(&previous, &current, 800, 480),
+            Some(Rect {
+                x: 0,
+                y: 10,
+                width: 8,
+                height: 1
+            })
+        );
+    }
+
+    #[test]
+    fn bbox_spans_all_rows_and_byte_boundaries() {
+        let previous = frame(800, 480);
+        let mut current = previous.clone();
+        // 7..=9 crosses a byte boundary, rows 20 and 300 used to collapse onto the first one
+        flip(&mut current, 800, 7, 20);
+        flip(&mut current, 800, 9, 300);
+        assert_eq!(
+            bounding_box(&previous, &current, 800, 480),
+            Some(Rect {
+                x: 0,
+                y: 20,
+                width: 16,
+                height: 281
+            })
+        );
+    }
+
+    #[test]
+    fn separate_changes_stay_separate() {
+        let previous = frame(800, 480);
+        let mut current = previous.clone();
+        // clock in the top right and a calendar line on the left
+        for x in 700..790 {
+            flip(&mut current, 800, x, 5);
+        }
+        for x in 10..150 {
+            flip(&mut current, 800, x, 100);
+        }
+
+        let mut rects = dirty_rects(&previous, &current, 800, 480, 4);
+        rects.sort_by_key(|r| r.x);
+        assert_eq!(
+            rects,
+            vec![
+                Rect {
+                    x: 8,
+                    y: 96,
+                    width: 144,
+                    height: 8
+                },
+                Rect {
+                    x: 696,
+                    y: 0,
+                    width: 96,
+                    height: 8
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn clips_to_unaligned_edges() {
+        let (width, height) = (13, 11);
+        let previous = frame(width, height);
+        let mut current = previous.clone();
+        flip(&mut current, width, 12, 10);
+
+        let expected = Rect {
+            x: 8,
+            y: 10,
+            width: 5,
+            height: 1,
+        };
+        assert_eq!(
+            bounding_box(&previous, &current, width, height),
+            Some(expected)
+        );
+        assert_eq!(
+            dirty_rects(&previous, &current, width, height, 4),
+            vec![Rect {
+                y: 8,
+                height: 3,
+                ..expected
+            }]
+        );
+    }
+
+    fn frames() -> impl Strategy<Value = (usize, usize, Vec<u8>, Vec<u8>)> {
+        (1usize..80, 1usize..50).prop_flat_map(|(width, height)| {
+            let len = stride(width) * height;
+            (
+                Just(width),
+                Just(height),
+                proptest::collection::vec(any::<u8>(), len),
+                proptest::collection::vec((0..width, 0..height), 0..20),
+            )
+                .prop_map(|(width, height, previous, flips)| {
+                    let mut current = previous.clone();
+                    for (x, y) in flips {
+                        flip(&mut current, width, x, y);
+                    }
+                    (width, height, previous, current)
+                })
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn bbox_contains_every_change((width, height, previous, current) in frames()) {
+            let changed = changed_pixels(&previous, &current, width, height);
+            match bounding_box(&previous, &current, width, height) {
+                None => prop_assert!(changed.is_empty()),
+                Some(bbox) => {
+                    assert_aligned(&bbox, width, height);
+                    for px in changed {
+                        prop_assert!(contains(&bbox, px), "{:?} not in {:?}", px, bbox);
+                    }
+                }
+            }
+        }
+
+        #[test]
+        fn dirty_rects_cover_every_change(
+            (width, height, previous, current) in frames(),
+            max_rects in 1usize..6,
+        ) {
+            let changed = changed_pixels(&previous, &current, width, height);
+            let rects = dirty_rects(&previous, &current, width, height, max_rects);
+
+            prop_assert_eq!(rects.is_empty(), changed.is_empty());
+            prop_assert!(rects.len() <= max_rects);
+            for (i, rect) in rects.iter().enumerate() {
+                assert_aligned(rect, width, height);
+                for other in &rects[i + 1..] {
+                    prop_assert!(!rect.intersects(other), "{:?} overlaps {:?}", rect, other);
+                }
+            }
+            for px in changed {
+                prop_assert!(rects.iter().any(|r| contains(r, px)), "{:?} not in {:?}", px, rects);
+            }
+        }
+    }
+}
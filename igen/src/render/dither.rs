@@ -0,0 +1,261 @@
+// Reducing photos to what the panel can show.
+//
+// Everything works on linear light: the sRGB values are decoded before taking
+// the luminance, so a 50% dither pattern ends up as bright as the mid gray it
+// replaces instead of noticeably darker.
+
+use crate::render::graphics::PixelColor;
+use image::{DynamicImage, GenericImageView};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Dither {
+    // Plain cut at perceptual mid gray, for line art and pure black and white frames
+    Threshold,
+    #[default]
+    FloydSteinberg,
+    // Only diffuses 3/4 of the error, keeps more contrast in highlights and shadows
+    Atkinson,
+    // Ordered 8x8 pattern, no crawling artifacts between similar frames
+    Bayer,
+}
+
+// Colors a panel can show, darkest first
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Palette {
+    Mono,
+    Gray4,
+}
+
+impl Palette {
+    fn colors(self) -> &'static [PixelColor] {
+        match self {
+            Palette::Mono => &[PixelColor::Black, PixelColor::White],
+            Palette::Gray4 => &[
+                PixelColor::Black,
+                PixelColor::DarkGray,
+                PixelColor::LightGray,
+                PixelColor::White,
+            ],
+        }
+    }
+}
+
+const BAYER_8X8: [[u8; 8]; 8] = [
+    [0, 32, 8, 40, 2, 34, 10, 42],
+    [48, 16, 56, 24, 50, 18, 58, 26],
+    [12, 44, 4, 36, 14, 46, 6, 38],
+    [60, 28, 52, 20, 62, 30, 54, 22],
+    [3, 35, 11, 43, 1, 33, 9, 41],
+    [51, 19, 59, 27, 49, 17, 57, 25],
+    [15, 47, 7, 39, 13, 45, 5, 37],
+    [63, 31, 55, 23, 61, 29, 53, 21],
+];
+
+fn srgb_to_linear(value: f32) -> f32 {
+    let v = value / 255.0;
+    if v <= 0.04045 {
+        v / 12.92
+    } else {
+        ((v + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+// Rec. 709 luminance in linear light, transparent pixels count as white paper
+fn luminance(image: &DynamicImage) -> Vec<f32> {
+    image
+        .pixels()
+        .map(|(_, _, px)| {
+            let [r, g, b, a] = px.0.map(|c| c as f32);
+            let y = 0.2126 * srgb_to_linear(r)
+                + 0.7152 * srgb_to_linear(g)
+                + 0.0722 * srgb_to_linear(b);
+            let alpha = a / 255.0;
+            y * alpha + (1.0 - alpha)
+        })
+        .collect()
+}
+
+// Row-major pixels of the whole image
+pub fn dither(image: &DynamicImage, method: Dither, palette: Palette) -> Vec<PixelColor> {
+    let (width, height) = (image.width() as usize, image.height() as usize);
+    let mut lum = luminance(image);
+
+    let colors = palette.colors();
+    let levels: Vec<f32> = colors
+        .iter()
+        .map(|c| srgb_to_linear(c.luma() as f32))
+        .collect();
+    // Index of the darkest level of the pair v lies between
+    let lower = |v: f32| {
+        levels[1..]
+            .iter()
+            .position(|&l| v < l)
+            .unwrap_or(levels.len() - 2)
+    };
+
+    // (dx, dy, weight) of the error handed to not yet visited neighbours
+    let kernel: &[(isize, usize, f32)] = match method {
+        Dither::Threshold => {
+            // Cut halfway between neighbouring levels as perceived, not in linear light
+            return lum
+                .iter()
+                .map(|&v| {
+                    let i = lower(v);
+                    let cut = (colors[i].luma() as f32 + colors[i + 1].luma() as f32) / 2.0;
+                    colors[if v > srgb_to_linear(cut) { i + 1 } else { i }]
+                })
+                .collect();
+        }
+        Dither::Bayer => {
+            return lum
+                .iter()
+                .enumerate()
+                .map(|(i, &v)| {
+                    let (x, y) = (i % width, i / width);
+                    let threshold = (BAYER_8X8[y % 8][x % 8] as f32 + 0.5) / 64.0;
+                    let l = lower(v);
+                    let t = (v - levels[l]) / (levels[l + 1] - levels[l]);
+                    colors[if t > threshold { l + 1 } else { l }]
+                })
+                .collect();
+        }
+        Dither::FloydSteinberg => &[
+            (1, 0, 7.0 / 16.0),
+            (-1, 1, 3.0 / 16.0),
+            (0, 1, 5.0 / 16.0),
+            (1, 1, 1.0 / 16.0),
+        ],
+        Dither::Atkinson => &[
+            (1, 0, 1.0 / 8.0),
+            (2, 0, 1.0 / 8.0),
+            (-1, 1, 1.0 / 8.0),
+            (0, 1, 1.0 / 8.0),
+            (1, 1, 1.0 / 8.0),
+            (0, 2, 1.0 / 8.0),
+        ],
+    };
+
+    let mut out = Vec::with_capacity(width * height);
+    for y in 0..height {
+        for x in 0..width {
+            let old = lum[y * width + x];
+            let l = lower(old);
+            let nearest = if old - levels[l] > levels[l + 1] - old {
+                l + 1
+            } else {
+                l
+            };
+            let error = old - levels[nearest];
+            out.push(colors[nearest]);
+
+            for &(dx, dy, weight) in kernel {
+                let (nx, ny) = (x as isize + dx, y + dy);
+                if nx >= 0 && (nx as usize) < width && ny < height {
+                    lum[ny * width + nx as usize] += error * weight;
+                }
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgba, RgbaImage};
+
+    fn solid(width: u32, height: u32, px: [u8; 4]) -> DynamicImage {
+        DynamicImage::ImageRgba8(RgbaImage::from_pixel(width, height, Rgba(px)))
+    }
+
+    fn white_share(pixels: &[PixelColor]) -> f32 {
+        pixels.iter().filter(|&&p| p == PixelColor::White).count() as f32 / pixels.len() as f32
+    }
+
+    const ALL: [Dither; 4] = [
+        Dither::Threshold,
+        Dither::FloydSteinberg,
+        Dither::Atkinson,
+        Dither::Bayer,
+    ];
+
+    #[test]
+    fn pure_colors_stay_pure() {
+        for method in ALL {
+            assert_eq!(
+                white_share(&dither(
+                    &solid(16, 16, [0, 0, 0, 255]),
+                    method,
+                    Palette::Mono
+                )),
+                0.0
+            );
+            assert_eq!(
+                white_share(&dither(
+                    &solid(16, 16, [255, 255, 255, 255]),
+                    method,
+                    Palette::Mono
+                )),
+                1.0
+            );
+            assert_eq!(
+                white_share(&dither(&solid(16, 16, [0, 0, 0, 0]), method, Palette::Mono)),
+                1.0
+            );
+        }
+    }
+
+    #[test]
+    fn mid_gray_keeps_its_brightness() {
+        // sRGB 188 is about half the light of white
+        let gray = solid(64, 64, [188, 188, 188, 255]);
+        for method in [Dither::FloydSteinberg, Dither::Atkinson, Dither::Bayer] {
+            let share = white_share(&dither(&gray, method, Palette::Mono));
+            assert!((share - 0.5).abs() < 0.05, "{method:?}: {share}");
+        }
+        assert_eq!(
+            white_share(&dither(&gray, Dither::Threshold, Palette::Mono)),
+            1.0
+        );
+    }
+
+    #[test]
+    fn gray_levels_are_hit_exactly() {
+        for color in Palette::Gray4.colors() {
+            let luma = color.luma();
+            let img = solid(16, 16, [luma, luma, luma, 255]);
+            for method in ALL {
+                assert!(
+                    dither(&img, method, Palette::Gray4)
+                        .iter()
+                        .all(|c| c == color),
+                    "{method:?} {color:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn between_gray_levels_mixes_neighbours_only() {
+        let img = solid(32, 32, [128, 128, 128, 255]);
+        for method in [Dither::FloydSteinberg, Dither::Atkinson, Dither::Bayer] {
+            let pixels = dither(&img, method, Palette::Gray4);
+            assert!(
+                pixels
+                    .iter()
+                    .all(|c| matches!(c, PixelColor::DarkGray | PixelColor::LightGray)),
+                "{method:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn parses_config_names() {
+        let parsed: Vec<Dither> =
+            serde_json::from_str(r#"["threshold", "floyd-steinberg", "atkinson", "bayer"]"#)
+                .unwrap();
+        assert_eq!(parsed, ALL);
+    }
+}
@@ -1,12 +1,11 @@
-use crate::render::graphics::{Color, PixelColor, Rect};
+use crate::render::dither::{self, Dither, Palette};
+use crate::render::graphics::{Color, Pixel, PixelColor, Rect};
 use fontdue::Font;
 use fontdue::layout::{CoordinateSystem, Layout, LayoutSettings, TextStyle};
-use image::{DynamicImage, GenericImageView, Luma, Pixel};
+use image::{DynamicImage, Rgb};
+use serde::Deserialize;
 use std::io::Write;
 
-pub const EPD_WIDTH: usize = 800;
-pub const EPD_HEIGHT: usize = 480;
-
 pub struct Padding {
     top: usize,
     bottom: usize,
@@ -83,7 +82,11 @@ pub struct Area {
     padding: Padding,
     outline: Outline,
 
-    buf: Vec<Vec<PixelColor>>,
+    buf: Vec<Vec<Pixel>>,
+    // Used by put_text, black unless changed
+    text_color: Color,
+    // Panel this is rendered for, if known. Images only need the rendition it shows
+    format: Option<PixelFormat>,
     children: Vec<Area>,
 }
 
@@ -106,55 +109,48 @@ impl Area {
         let dr = Rect {
             x: padding.left + outline.left,
             y: padding.top + outline.top,
-            width: width - padding.left - padding.right - outline.left - outline.right,
-            height: height - padding.top - padding.bottom - outline.top - outline.bottom,
+            width: width
+                .saturating_sub(padding.left + padding.right + outline.left + outline.right),
+            height: height
+                .saturating_sub(padding.top + padding.bottom + outline.top + outline.bottom),
         };
+        // Outlines wider than the area just cover all of it
+        let outline_top = outline.top.min(height);
+        let outline_left = outline.left.min(width);
 
-        let mut buf = vec![vec![PixelColor::White; width]; height];
+        let mut buf = vec![vec![Pixel::WHITE; width]; height];
 
         // Draw outline (top)
-        for y in 0..outline.top {
+        for y in 0..outline_top {
             for x in 0..space.width {
-                buf[y][x] = outline.color.into();
+                buf[y][x] = outline.color.at(x, y);
             }
         }
 
         // (bottom)
-        for y in (space.height - outline.bottom)..space.height {
+        for y in space.height.saturating_sub(outline.bottom)..space.height {
             for x in 0..space.width {
-                buf[y][x] = outline.color.into();
+                buf[y][x] = outline.color.at(x, y);
             }
         }
 
         // (left)
         for y in 0..space.height {
-            for x in 0..outline.left {
-                buf[y][x] = outline.color.into();
+            for x in 0..outline_left {
+                buf[y][x] = outline.color.at(x, y);
             }
         }
 
         // (right)
         for y in 0..space.height {
-            for x in (space.width - outline.right)..space.width {
-                buf[y][x] = outline.color.into();
+            for x in space.width.saturating_sub(outline.right)..space.width {
+                buf[y][x] = outline.color.at(x, y);
             }
         }
 
         for x in 0..dr.width {
             for y in 0..dr.height {
-                match fill {
-                    Color::White => dr.set_px(&mut buf, x, y, PixelColor::White),
-                    Color::Black => dr.set_px(&mut buf, x, y, PixelColor::Black),
-                    Color::Gray => {
-                        let should = if y % 2 == 0 { x % 2 == 0 } else { x % 2 == 1 };
-                        let color = if should {
-                            PixelColor::White
-                        } else {
-                            PixelColor::Black
-                        };
-                        dr.set_px(&mut buf, x, y, color)
-                    }
-                }
+                dr.set_px(&mut buf, x, y, fill.at(x, y));
             }
         }
 
@@ -166,10 +162,24 @@ impl Area {
             padding,
             outline,
             buf,
+            text_color: Color::Black,
+            format: None,
             children: vec![],
         }
     }
 
+    pub fn set_text_color(&mut self, color: Color) {
+        self.text_color = color;
+    }
+
+    pub fn set_format(&mut self, format: PixelFormat) {
+        self.format = Some(format);
+    }
+
+    pub fn format(&self) -> Option<PixelFormat> {
+        self.format
+    }
+
     fn is_layout_possible(
         &self,
         font: &Font,
@@ -182,6 +192,10 @@ impl Area {
             {
                 is_possible = false;
             }
+            // set_px shifts by the canvas origin again, which must stay within buf
+            if self.canvas.x + x >= self.space.width || self.canvas.y + y >= self.space.height {
+                is_possible = false;
+            }
         });
         is_possible
     }
@@ -193,7 +207,7 @@ impl Area {
         texts: &[TextStyle],
         coverage_threshold: u8,
         max_text_size: f32,
-    ) {
+    ) -> bool {
         let mut current_text_size = 1f32;
 
         let mut largest_text_styles: Vec<TextStyle> = vec![];
@@ -221,24 +235,42 @@ impl Area {
         )
     }
 
+    // Text that would not fit is left out entirely, returns whether it was drawn
     pub fn put_text(
         &mut self,
         font: &Font,
         layout_settings: LayoutSettings,
         texts: &[TextStyle],
         coverage_threshold: u8,
-    ) {
+    ) -> bool {
         if !self.is_layout_possible(font, layout_settings, texts) {
-            panic!("layouting impossible");
+            return false;
         }
+        let color = self.text_color;
         Self::layout_text(font, layout_settings, texts, |x, y, coverage| {
-            let color = if coverage > coverage_threshold {
-                PixelColor::Black
+            let ink = color.at(x, y);
+            let mono = if coverage > coverage_threshold {
+                ink.mono
             } else {
                 PixelColor::White
             };
-            self.canvas.set_px(&mut self.buf, x, y, color)
+            // Gray panels get anti-aliased edges instead of a hard cut
+            let gray = match coverage {
+                0..=42 => PixelColor::White,
+                43..=127 => PixelColor::LightGray,
+                128..=212 => PixelColor::DarkGray,
+                213.. => PixelColor::Black,
+            };
+            // Only dark text can be faded out to white
+            let gray = if ink.gray == PixelColor::Black || gray == PixelColor::White {
+                gray
+            } else {
+                ink.gray
+            };
+            self.canvas
+                .set_px(&mut self.buf, x, y, Pixel { mono, gray })
         });
+        true
     }
 
     fn layout_text<F>(
@@ -274,62 +306,67 @@ impl Area {
         }
     }
 
-    pub fn load_image(&mut self, x: usize, y: usize, image: &DynamicImage) {
-        if (image.width() > self.get_available_hspace() as u32)
-            || (image.height() > self.get_available_vspace() as u32)
-        {
+    pub fn load_image(&mut self, x: usize, y: usize, image: &DynamicImage, method: Dither) {
+        let pixels = dither_image(image, method, self.format);
+        self.put_pixels(x, y, image.width() as usize, &pixels);
+    }
+
+    // Rows of width pixels, as dither_image returns them
+    pub fn put_pixels(&mut self, x: usize, y: usize, width: usize, pixels: &[Pixel]) {
+        let height = pixels.len().checked_div(width).unwrap_or(0);
+        if x + width > self.get_available_hspace() || y + height > self.get_available_vspace() {
             panic!("Image is too large");
         }
-        let offset_x = x;
-        let offset_y = y;
-
-        for y in 0..image.height() {
-            for x in 0..image.width() {
-                let pixel = image.get_pixel(x, y);
-
-                let rgba = pixel.to_rgba();
-
-                let avg = (rgba[0] as u32 + rgba[1] as u32 + rgba[2] as u32) / 3;
-                let color = if avg > 127 || rgba[3] == 0 {
-                    PixelColor::White
-                } else {
-                    PixelColor::Black
-                };
-                self.canvas.set_px(
-                    &mut self.buf,
-                    x as usize + offset_x,
-                    y as usize + offset_y,
-                    color,
-                );
-            }
+        for (i, pixel) in pixels.iter().enumerate() {
+            self.canvas
+                .set_px(&mut self.buf, i % width + x, i / width + y, *pixel);
         }
     }
 
     pub fn add_sub_area(&mut self, mut area: Area) {
-        area.offset.x += self.offset.x + self.canvas.x;
-        area.offset.y += self.offset.y + self.canvas.y;
-        area.children.iter_mut().for_each(|area| {
-            area.offset.x += self.offset.x + self.canvas.x;
-            area.offset.y += self.offset.y + self.canvas.y;
-        });
-
+        area.shift(self.offset.x + self.canvas.x, self.offset.y + self.canvas.y);
         self.children.push(area)
     }
 
+    // Moves the area and everything below it
+    fn shift(&mut self, dx: usize, dy: usize) {
+        self.offset.x += dx;
+        self.offset.y += dy;
+        self.children.iter_mut().for_each(|c| c.shift(dx, dy));
+    }
+
     pub fn draw(&self, image: &mut EpdImage) {
-        self.render(image);
+        let bounds = Rect {
+            x: 0,
+            y: 0,
+            width: image.width(),
+            height: image.height(),
+        };
+        self.draw_within(image, bounds);
+    }
+
+    // Children only show within their parent's canvas, whatever size they were made with
+    fn draw_within(&self, image: &mut EpdImage, clip: Rect) {
+        self.render(image, clip);
 
-        self.children.iter().for_each(|c| c.draw(image));
+        let canvas = clip.intersection(&Rect {
+            x: self.offset.x + self.canvas.x,
+            y: self.offset.y + self.canvas.y,
+            width: self.canvas.width,
+            height: self.canvas.height,
+        });
+        self.children
+            .iter()
+            .for_each(|c| c.draw_within(image, canvas));
     }
 
-    fn render(&self, image: &mut EpdImage) {
+    fn render(&self, image: &mut EpdImage, clip: Rect) {
         for y in 0..self.space.height {
             for x in 0..self.space.width {
-                image.set_pixel(
-                    x + self.offset.x,
-                    y + self.offset.y,
-                    self.space.get_px(&self.buf, x, y),
-                );
+                let (image_x, image_y) = (x + self.offset.x, y + self.offset.y);
+                if clip.contains(image_x, image_y) {
+                    image.set_pixel(image_x, image_y, self.space.get_px(&self.buf, x, y));
+                }
             }
         }
     }
@@ -351,43 +388,210 @@ impl Area {
     }
 }
 
+// Both renditions of image, unless the panel is known and shows only one of them
+pub fn dither_image(
+    image: &DynamicImage,
+    method: Dither,
+    format: Option<PixelFormat>,
+) -> Vec<Pixel> {
+    let solid = |palette| {
+        dither::dither(image, method, palette)
+            .into_iter()
+            .map(Pixel::solid)
+            .collect()
+    };
+    match format {
+        Some(PixelFormat::Mono | PixelFormat::TriColor) => solid(Palette::Mono),
+        Some(PixelFormat::Gray4) => solid(Palette::Gray4),
+        None => {
+            let mono = dither::dither(image, method, Palette::Mono);
+            let gray = dither::dither(image, method, Palette::Gray4);
+            mono.into_iter()
+                .zip(gray)
+                .map(|(mono, gray)| Pixel { mono, gray })
+                .collect()
+        }
+    }
+}
+
+// How the panel is driven, picked by [panel] format
+#[derive(Deserialize, Debug, Clone, Copy, Default, Eq, PartialEq, Hash)]
+#[serde(rename_all = "kebab-case")]
+pub enum PixelFormat {
+    // 1 bit per pixel, MSB first, 1 is white
+    #[default]
+    Mono,
+    // 2 bits per pixel, MSB first, as the Waveshare 4-gray routine expects:
+    // 0b11 white, 0b10 light gray, 0b01 dark gray, 0b00 black.
+    // No partial refresh in this mode.
+    Gray4,
+    // Two 1bpp planes for the black/white/red B panels: the black plane as in
+    // Mono, followed by the red plane where 0 is red. No partial refresh either.
+    TriColor,
+}
+
+impl PixelFormat {
+    pub fn bits_per_pixel(self) -> usize {
+        match self {
+            PixelFormat::Mono | PixelFormat::TriColor => 1,
+            PixelFormat::Gray4 => 2,
+        }
+    }
+
+    pub fn planes(self) -> usize {
+        match self {
+            PixelFormat::Mono | PixelFormat::Gray4 => 1,
+            PixelFormat::TriColor => 2,
+        }
+    }
+
+    fn encode(self, color: PixelColor) -> u8 {
+        match self {
+            PixelFormat::Mono => (color.to_mono() == PixelColor::White) as u8,
+            // Red pixels stay blank on the black plane, the red plane covers them
+            PixelFormat::TriColor => {
+                (color == PixelColor::Red || color.to_mono() == PixelColor::White) as u8
+            }
+            PixelFormat::Gray4 => match color.to_gray() {
+                PixelColor::White => 0b11,
+                PixelColor::LightGray => 0b10,
+                PixelColor::DarkGray => 0b01,
+                _ => 0b00,
+            },
+        }
+    }
+
+    fn decode(self, bits: u8) -> PixelColor {
+        match (self, bits) {
+            (PixelFormat::Mono | PixelFormat::TriColor, 0b1) | (PixelFormat::Gray4, 0b11) => {
+                PixelColor::White
+            }
+            (PixelFormat::Gray4, 0b10) => PixelColor::LightGray,
+            (PixelFormat::Gray4, 0b01) => PixelColor::DarkGray,
+            _ => PixelColor::Black,
+        }
+    }
+
+    // Bytes of a single plane of w x h pixels, rows padded to whole bytes
+    pub fn plane_len(self, w: usize, h: usize) -> usize {
+        (w * self.bits_per_pixel()).div_ceil(8) * h
+    }
+
+    pub fn buffer_len(self, w: usize, h: usize) -> usize {
+        self.plane_len(w, h) * self.planes()
+    }
+}
+
+// Writes color at pixel index i (within the row) of a packed row
+fn put_bits(row: &mut [u8], i: usize, format: PixelFormat, color: PixelColor) {
+    let bpp = format.bits_per_pixel();
+    let per_byte = 8 / bpp;
+    let shift = 8 - bpp * (i % per_byte + 1);
+    let mask = ((1u16 << bpp) - 1) as u8;
+    row[i / per_byte] &= !(mask << shift);
+    row[i / per_byte] |= format.encode(color) << shift;
+}
+
+fn get_bits(row: &[u8], i: usize, format: PixelFormat) -> PixelColor {
+    let bpp = format.bits_per_pixel();
+    let per_byte = 8 / bpp;
+    let shift = 8 - bpp * (i % per_byte + 1);
+    let mask = ((1u16 << bpp) - 1) as u8;
+    format.decode((row[i / per_byte] >> shift) & mask)
+}
+
 pub struct EpdImage {
-    // 1 pixel ber bit
+    width: usize,
+    height: usize,
+    format: PixelFormat,
+    // Packed as described by format, planes back to back
     data: Vec<u8>,
 }
 
 impl EpdImage {
-    pub fn new(width: usize, height: usize) -> Self {
-        let size = (width * height).div_ceil(8);
+    pub fn new(width: usize, height: usize, format: PixelFormat) -> Self {
         EpdImage {
-            data: vec![PixelColor::White as u8; size],
+            width,
+            height,
+            format,
+            // All ones is white in every format
+            data: vec![0xFF; format.buffer_len(width, height)],
         }
     }
 
-    pub fn set_pixel(&mut self, x: usize, y: usize, color: PixelColor) {
-        let byte_index = (y * EPD_WIDTH + x) / 8;
-        let bit_index = x % 8;
-        match color {
-            PixelColor::White => self.data[byte_index] |= 1 << (7 - bit_index),
-            PixelColor::Black => self.data[byte_index] &= !(1 << (7 - bit_index)),
+    fn stride(&self) -> usize {
+        self.format.plane_len(self.width, 1)
+    }
+
+    fn row(&self, plane: usize, y: usize) -> std::ops::Range<usize> {
+        let start = plane * self.format.plane_len(self.width, self.height) + y * self.stride();
+        start..start + self.stride()
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn format(&self) -> PixelFormat {
+        self.format
+    }
+
+    pub fn set_pixel(&mut self, x: usize, y: usize, pixel: Pixel) {
+        let color = match self.format {
+            PixelFormat::Mono | PixelFormat::TriColor => pixel.mono,
+            PixelFormat::Gray4 => pixel.gray,
+        };
+        let row = self.row(0, y);
+        put_bits(&mut self.data[row], x, self.format, color);
+
+        if self.format == PixelFormat::TriColor {
+            // Same polarity as the black plane, so all ones stays blank
+            let row = self.row(1, y);
+            let red = if color == PixelColor::Red {
+                PixelColor::Black
+            } else {
+                PixelColor::White
+            };
+            put_bits(&mut self.data[row], x, PixelFormat::Mono, red);
         }
     }
 
     pub fn get_pixel(&self, x: usize, y: usize) -> PixelColor {
-        let byte_index = (y * EPD_WIDTH + x) / 8;
-        let bit_index = x % 8;
-        let clr = (self.data[byte_index] >> (7 - bit_index)) & 0x1;
-        if clr == 1 {
-            PixelColor::White
-        } else {
-            PixelColor::Black
+        if self.format == PixelFormat::TriColor
+            && get_bits(&self.data[self.row(1, y)], x, PixelFormat::Mono) == PixelColor::Black
+        {
+            return PixelColor::Red;
         }
+        get_bits(&self.data[self.row(0, y)], x, self.format)
     }
 
     pub fn data(&self) -> Vec<u8> {
         self.data.clone()
     }
 
+    // Changes whenever anything the panel would show changes
+    pub fn content_hash(&self) -> u32 {
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&(self.width as u32).to_le_bytes());
+        hasher.update(&(self.height as u32).to_le_bytes());
+        hasher.update(&[
+            self.format.bits_per_pixel() as u8,
+            self.format.planes() as u8,
+        ]);
+        hasher.update(&self.data);
+        hasher.finalize()
+    }
+
+    // The black and the red plane of a tri-color frame
+    pub fn planes(&self) -> (&[u8], &[u8]) {
+        self.data
+            .split_at(self.format.plane_len(self.width, self.height))
+    }
+
     pub fn to_file(&self, filename: &str) {
         let mut file = std::fs::File::create(filename).unwrap();
         file.write_all(&self.data).unwrap();
@@ -397,17 +601,14 @@ impl EpdImage {
         self.to_partial(rect.x, rect.y, rect.width, rect.height)
     }
 
+    // Only the first plane, partial updates are mono only anyway
     pub fn to_partial(&self, x: usize, y: usize, w: usize, h: usize) -> Vec<u8> {
-        let mut partial = vec![0u8; w.div_ceil(8) * h];
+        let stride = self.format.plane_len(w, 1);
+        let mut partial = vec![0u8; stride * h];
 
-        for row in 0..h {
+        for (row, out) in partial.chunks_exact_mut(stride).enumerate() {
             for col in 0..w {
-                let byte_index = (row * w + col) / 8;
-                let bit_index = col % 8;
-                match self.get_pixel(x + col, y + row) {
-                    PixelColor::White => partial[byte_index] |= 1 << (7 - bit_index),
-                    PixelColor::Black => partial[byte_index] &= !(1 << (7 - bit_index)),
-                }
+                put_bits(out, col, self.format, self.get_pixel(x + col, y + row));
             }
         }
         partial
@@ -420,24 +621,133 @@ impl EpdImage {
     }
 
     pub fn to_img_file(&self, filename: &str) {
-        let mut image = image::GrayImage::new(EPD_WIDTH as u32, EPD_HEIGHT as u32);
-        for y in 0..EPD_HEIGHT {
-            for x in 0..EPD_WIDTH {
-                image.put_pixel(
-                    x as u32,
-                    y as u32,
-                    if matches!(self.get_pixel(x, y), PixelColor::White) {
-                        Luma([0xFF])
-                    } else {
-                        Luma([0x00])
-                    },
-                );
+        let mut image = image::RgbImage::new(self.width as u32, self.height as u32);
+        for y in 0..self.height {
+            for x in 0..self.width {
+                image.put_pixel(x as u32, y as u32, Rgb(self.get_pixel(x, y).rgb()));
             }
         }
         image.save(filename).expect("Could not save image")
     }
 
+    // Only the first plane
     pub fn raw(&self) -> &[u8] {
-        self.data.as_slice()
+        self.planes().0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COLORS: [PixelColor; 5] = [
+        PixelColor::White,
+        PixelColor::LightGray,
+        PixelColor::DarkGray,
+        PixelColor::Black,
+        PixelColor::Red,
+    ];
+
+    #[test]
+    fn dithers_for_the_format() {
+        let gradient = DynamicImage::ImageLuma8(image::GrayImage::from_fn(64, 4, |x, _| {
+            image::Luma([(x * 4) as u8])
+        }));
+        let colors = |pixels: Vec<Pixel>, pick: fn(Pixel) -> PixelColor| {
+            let mut colors: Vec<_> = pixels.into_iter().map(pick).collect();
+            colors.sort_by_key(|c| *c as u8);
+            colors.dedup();
+            colors
+        };
+        let mono = dither_image(&gradient, Dither::FloydSteinberg, Some(PixelFormat::Mono));
+        assert_eq!(
+            colors(mono, |p| p.gray),
+            [PixelColor::White, PixelColor::Black]
+        );
+        let gray = dither_image(&gradient, Dither::FloydSteinberg, Some(PixelFormat::Gray4));
+        assert_eq!(colors(gray, |p| p.gray).len(), 4);
+        let both = dither_image(&gradient, Dither::FloydSteinberg, None);
+        assert_eq!(colors(both.clone(), |p| p.mono).len(), 2);
+        assert_eq!(colors(both, |p| p.gray).len(), 4);
+    }
+
+    #[test]
+    fn clips_children_to_their_parent() {
+        let mut parent = Area::new(
+            2,
+            2,
+            10,
+            10,
+            Color::White,
+            Padding::full(1),
+            Outline::none(),
+        );
+        // Hangs over the parent's canvas and the image on two sides
+        parent.add_sub_area(Area::new(
+            4,
+            4,
+            20,
+            20,
+            Color::Black,
+            Padding::full(0),
+            Outline::none(),
+        ));
+        let mut image = EpdImage::new(16, 16, PixelFormat::Mono);
+        parent.draw(&mut image);
+        for y in 0..16 {
+            for x in 0..16 {
+                let inside = (7..11).contains(&x) && (7..11).contains(&y);
+                let expected = if inside {
+                    PixelColor::Black
+                } else {
+                    PixelColor::White
+                };
+                assert_eq!(image.get_pixel(x, y), expected, "at {x},{y}");
+            }
+        }
+    }
+
+    #[test]
+    fn buffer_sizes() {
+        assert_eq!(
+            EpdImage::new(800, 480, PixelFormat::Mono).raw().len(),
+            48000
+        );
+        assert_eq!(
+            EpdImage::new(800, 480, PixelFormat::Gray4).data().len(),
+            96000
+        );
+        assert_eq!(
+            EpdImage::new(648, 480, PixelFormat::TriColor).data().len(),
+            2 * 81 * 480
+        );
+        // Rows are padded to whole bytes
+        assert_eq!(EpdImage::new(10, 3, PixelFormat::Mono).data().len(), 6);
+    }
+
+    #[test]
+    fn pixels_survive_packing() {
+        for format in [PixelFormat::Mono, PixelFormat::Gray4, PixelFormat::TriColor] {
+            // Odd size so the padding gets exercised too
+            let (width, height) = (13, 7);
+            let mut image = EpdImage::new(width, height, format);
+            let at = |x: usize, y: usize| COLORS[(x * 3 + y) % COLORS.len()];
+            for y in 0..height {
+                for x in 0..width {
+                    image.set_pixel(x, y, Pixel::solid(at(x, y)));
+                }
+            }
+            for y in 0..height {
+                for x in 0..width {
+                    let expected = match format {
+                        PixelFormat::Mono => at(x, y).to_mono(),
+                        PixelFormat::Gray4 => at(x, y).to_gray(),
+                        PixelFormat::TriColor if at(x, y) == PixelColor::Red => PixelColor::Red,
+                        PixelFormat::TriColor => at(x, y).to_mono(),
+                    };
+                    assert_eq!(image.get_pixel(x, y), expected, "{format:?} {x} {y}");
+                }
+            }
+        }
     }
 }
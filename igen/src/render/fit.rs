@@ -0,0 +1,139 @@
+// Getting an arbitrary photo into an area of a given size
+
+use image::{DynamicImage, imageops};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Fit {
+    // Scale to fit inside, leaves bars on one axis
+    #[default]
+    Contain,
+    // Scale to fill the area and crop what sticks out, around the focal point
+    Cover,
+    // Scale both axes independently, ignoring the aspect ratio
+    Stretch,
+    // Original size, cropped around the focal point if it is too large
+    None,
+}
+
+// Relative position in the image (0.0 to 1.0 on both axes) that should stay
+// visible when cropping
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct FocalPoint(pub f32, pub f32);
+
+impl Default for FocalPoint {
+    fn default() -> Self {
+        FocalPoint(0.5, 0.5)
+    }
+}
+
+// Start of a window of len out of total, as centered on focus as possible
+fn window_start(total: u32, len: u32, focus: f32) -> u32 {
+    let center = focus.clamp(0.0, 1.0) * total as f32;
+    (center - len as f32 / 2.0).clamp(0.0, (total - len) as f32) as u32
+}
+
+fn crop_around(image: &DynamicImage, width: u32, height: u32, focus: FocalPoint) -> DynamicImage {
+    let (width, height) = (width.min(image.width()), height.min(image.height()));
+    image.crop_imm(
+        window_start(image.width(), width, focus.0),
+        window_start(image.height(), height, focus.1),
+        width,
+        height,
+    )
+}
+
+// Result is never larger than width x height
+pub fn fit(
+    image: &DynamicImage,
+    width: u32,
+    height: u32,
+    fit: Fit,
+    focus: FocalPoint,
+) -> DynamicImage {
+    const FILTER: imageops::FilterType = imageops::FilterType::CatmullRom;
+
+    match fit {
+        Fit::Contain => image.resize(width, height, FILTER),
+        Fit::Stretch => image.resize_exact(width, height, FILTER),
+        Fit::None => crop_around(image, width, height, focus),
+        Fit::Cover => {
+            // Crop before scaling, no point in resizing what gets thrown away
+            let scale = f64::max(
+                width as f64 / image.width() as f64,
+                height as f64 / image.height() as f64,
+            );
+            let crop_w = ((width as f64 / scale).round() as u32).clamp(1, image.width());
+            let crop_h = ((height as f64 / scale).round() as u32).clamp(1, image.height());
+            crop_around(image, crop_w, crop_h, focus).resize_exact(width, height, FILTER)
+        }
+    }
+}
+
+// Clockwise, in degrees
+pub fn rotate(image: DynamicImage, degrees: u16) -> DynamicImage {
+    match degrees {
+        90 => image.rotate90(),
+        180 => image.rotate180(),
+        270 => image.rotate270(),
+        _ => image,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{GenericImageView, Rgba, RgbaImage};
+
+    // Black image with a white stripe of 4 columns starting at x, wide
+    // enough to survive being scaled down by half
+    fn marked(width: u32, height: u32, x: u32) -> DynamicImage {
+        let mut img = RgbaImage::from_pixel(width, height, Rgba([0, 0, 0, 255]));
+        for y in 0..height {
+            for x in x..(x + 4).min(width) {
+                img.put_pixel(x, y, Rgba([255, 255, 255, 255]));
+            }
+        }
+        DynamicImage::ImageRgba8(img)
+    }
+
+    #[test]
+    fn sizes() {
+        let img = marked(400, 200, 0);
+        let center = FocalPoint::default();
+        let dims = |fit_mode| fit(&img, 100, 100, fit_mode, center).dimensions();
+        assert_eq!(dims(Fit::Contain), (100, 50));
+        assert_eq!(dims(Fit::Cover), (100, 100));
+        assert_eq!(dims(Fit::Stretch), (100, 100));
+        assert_eq!(dims(Fit::None), (100, 100));
+        assert_eq!(
+            fit(&img, 800, 800, Fit::None, center).dimensions(),
+            (400, 200)
+        );
+    }
+
+    #[test]
+    fn cover_keeps_focal_point() {
+        // Mark far right, a centered crop would lose it
+        let img = marked(400, 200, 390);
+        let right = fit(&img, 100, 100, Fit::Cover, FocalPoint(0.97, 0.5));
+        let center = fit(&img, 100, 100, Fit::Cover, FocalPoint::default());
+        let brightest = |img: &DynamicImage| {
+            (0..img.width())
+                .map(|x| img.get_pixel(x, 50).0[0])
+                .max()
+                .unwrap()
+        };
+        assert!(brightest(&right) > 128);
+        assert!(brightest(&center) < 16);
+    }
+
+    #[test]
+    fn rotation() {
+        let img = marked(40, 20, 0);
+        assert_eq!(rotate(img.clone(), 90).dimensions(), (20, 40));
+        assert_eq!(rotate(img.clone(), 180).get_pixel(39, 0).0[0], 255);
+        assert_eq!(rotate(img, 0).dimensions(), (40, 20));
+    }
+}
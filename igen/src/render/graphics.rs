@@ -1,4 +1,6 @@
-#[derive(Copy, Clone, Debug)]
+use serde::{Deserialize, Serialize};
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub struct Rect {
     pub x: usize,
     pub y: usize,
@@ -7,13 +9,55 @@ pub struct Rect {
 }
 
 impl Rect {
-    pub fn set_px(&self, buf: &mut [Vec<PixelColor>], x: usize, y: usize, color: PixelColor) {
+    pub fn area(&self) -> usize {
+        self.width * self.height
+    }
+
+    pub fn intersects(&self, other: &Rect) -> bool {
+        self.x < other.x + other.width
+            && other.x < self.x + self.width
+            && self.y < other.y + other.height
+            && other.y < self.y + self.height
+    }
+
+    // Empty (at other's origin) if they don't overlap
+    pub fn intersection(&self, other: &Rect) -> Rect {
+        let x = self.x.max(other.x);
+        let y = self.y.max(other.y);
+        Rect {
+            x,
+            y,
+            width: (self.x + self.width)
+                .min(other.x + other.width)
+                .saturating_sub(x),
+            height: (self.y + self.height)
+                .min(other.y + other.height)
+                .saturating_sub(y),
+        }
+    }
+
+    pub fn contains(&self, x: usize, y: usize) -> bool {
+        (self.x..self.x + self.width).contains(&x) && (self.y..self.y + self.height).contains(&y)
+    }
+
+    pub fn union(&self, other: &Rect) -> Rect {
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+        Rect {
+            x,
+            y,
+            width: (self.x + self.width).max(other.x + other.width) - x,
+            height: (self.y + self.height).max(other.y + other.height) - y,
+        }
+    }
+
+    pub fn set_px(&self, buf: &mut [Vec<Pixel>], x: usize, y: usize, pixel: Pixel) {
         // TODO: Well, this correct looking assert fails; should be investigated someday
         // assert!(x <= self.width && y <= self.height);
-        buf[self.y + y][self.x + x] = color;
+        buf[self.y + y][self.x + x] = pixel;
     }
 
-    pub fn get_px(&self, buf: &[Vec<PixelColor>], x: usize, y: usize) -> PixelColor {
+    pub fn get_px(&self, buf: &[Vec<Pixel>], x: usize, y: usize) -> Pixel {
         buf[self.y + y][self.x + x]
     }
 }
@@ -23,21 +67,111 @@ impl From<Color> for PixelColor {
         match value {
             Color::White => PixelColor::White,
             Color::Black => PixelColor::Black,
-            Color::Gray => panic!(),
+            Color::Gray => PixelColor::LightGray,
+            Color::Accent => PixelColor::Red,
         }
     }
 }
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
-#[repr(u8)]
 pub enum PixelColor {
-    White = 0xFF,
-    Black = 0x00,
+    White,
+    // Only on panels in 4-gray mode
+    LightGray,
+    DarkGray,
+    Black,
+    // Only on tri-color panels
+    Red,
 }
 
-#[derive(Copy, Clone, Debug)]
+impl PixelColor {
+    // Value for previews and the 4-gray palette, evenly spaced in sRGB
+    pub fn luma(self) -> u8 {
+        match self {
+            PixelColor::White => 0xFF,
+            PixelColor::LightGray => 0xAA,
+            PixelColor::DarkGray => 0x55,
+            PixelColor::Black => 0x00,
+            // Rec. 601 weight of pure red
+            PixelColor::Red => 0x4C,
+        }
+    }
+
+    // Value for previews
+    pub fn rgb(self) -> [u8; 3] {
+        match self {
+            PixelColor::Red => [0xFF, 0x00, 0x00],
+            other => [other.luma(); 3],
+        }
+    }
+
+    // Closest color a panel in 4-gray mode can show
+    pub fn to_gray(self) -> PixelColor {
+        match self {
+            PixelColor::Red => PixelColor::DarkGray,
+            other => other,
+        }
+    }
+
+    // Closest color a black and white panel can show
+    pub fn to_mono(self) -> PixelColor {
+        match self {
+            PixelColor::White | PixelColor::LightGray => PixelColor::White,
+            PixelColor::DarkGray | PixelColor::Black | PixelColor::Red => PixelColor::Black,
+        }
+    }
+}
+
+// What a pixel ends up as on a black and white (or tri-color) panel and on one
+// in 4-gray mode. Everything drawn decides both up front, so areas never need
+// to know which kind of panel they are rendered for. Images are the exception,
+// dithering both is too slow when the area knows which one is shown.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Pixel {
+    // May be Red, which black and white panels show as black
+    pub mono: PixelColor,
+    pub gray: PixelColor,
+}
+
+impl Pixel {
+    pub const WHITE: Pixel = Pixel::solid(PixelColor::White);
+
+    pub const fn solid(color: PixelColor) -> Self {
+        Pixel {
+            mono: color,
+            gray: color,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum Color {
     White,
     Black,
     Gray,
+    // Red on tri-color panels, black everywhere else
+    Accent,
+}
+
+impl Color {
+    // x and y pick the spot in the checkerboard that fakes gray on black and white panels
+    pub fn at(self, x: usize, y: usize) -> Pixel {
+        match self {
+            Color::White => Pixel::solid(PixelColor::White),
+            Color::Black => Pixel::solid(PixelColor::Black),
+            Color::Gray => Pixel {
+                mono: if (x + y).is_multiple_of(2) {
+                    PixelColor::White
+                } else {
+                    PixelColor::Black
+                },
+                gray: PixelColor::LightGray,
+            },
+            Color::Accent => Pixel {
+                mono: PixelColor::Red,
+                gray: PixelColor::Black,
+            },
+        }
+    }
 }
@@ -0,0 +1,124 @@
+// Frames recently handed out to a device, by id.
+//
+// A response can get lost on the way, so the next diff has to start from the
+// frame the device says it is showing, not from the one we sent last. Devices
+// that never report anything are assumed to show whatever they got last.
+// Ids start at a random point, so an id reported after igen restarted doesn't
+// name a frame of the new history by accident.
+
+use crate::render::epd::EpdImage;
+use std::collections::VecDeque;
+use std::hash::{BuildHasher, RandomState};
+
+// Anything older can only be answered with a full refresh
+const HISTORY_LEN: usize = 8;
+
+// Never handed out, devices report it when they show nothing we sent (e.g. after clearing)
+pub const NO_FRAME: u32 = u32::MAX;
+
+pub struct FrameHistory {
+    next_id: u32,
+    frames: VecDeque<(u32, EpdImage)>,
+    // Last frame the device reported as displayed
+    displayed: Option<u32>,
+}
+
+impl Default for FrameHistory {
+    fn default() -> Self {
+        // RandomState is keyed randomly, so hashing nothing is a random number
+        let seed = RandomState::new().hash_one(()) as u32;
+        FrameHistory {
+            next_id: seed % NO_FRAME,
+            frames: VecDeque::new(),
+            displayed: None,
+        }
+    }
+}
+
+impl FrameHistory {
+    // Returns the id the frame goes out with
+    pub fn push(&mut self, frame: EpdImage) -> u32 {
+        let id = self.next_id;
+        self.next_id = (self.next_id + 1) % NO_FRAME;
+        self.frames.push_back((id, frame));
+        if self.frames.len() > HISTORY_LEN {
+            self.frames.pop_front();
+        }
+        id
+    }
+
+    pub fn acknowledge(&mut self, id: u32) {
+        self.displayed = Some(id);
+    }
+
+    // What the device is showing, None if we don't know and need a full refresh
+    pub fn base(&self) -> Option<&EpdImage> {
+        match self.displayed {
+            None => self.frames.back().map(|(_, frame)| frame),
+            Some(displayed) => self
+                .frames
+                .iter()
+                .find(|(id, _)| *id == displayed)
+                .map(|(_, frame)| frame),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::epd::PixelFormat;
+
+    // Frames are told apart by their width
+    fn frame(width: usize) -> EpdImage {
+        EpdImage::new(width, 1, PixelFormat::Mono)
+    }
+
+    #[test]
+    fn silent_devices_diff_against_last_sent() {
+        let mut history = FrameHistory::default();
+        assert!(history.base().is_none());
+        history.push(frame(8));
+        history.push(frame(16));
+        assert_eq!(history.base().unwrap().width(), 16);
+    }
+
+    #[test]
+    fn diffs_against_acknowledged_frame() {
+        let mut history = FrameHistory::default();
+        let first = history.push(frame(8));
+        history.push(frame(16));
+        // The second response got lost
+        history.acknowledge(first);
+        assert_eq!(history.base().unwrap().width(), 8);
+    }
+
+    #[test]
+    fn unknown_frames_force_full_refresh() {
+        let mut history = FrameHistory::default();
+        let first = history.push(frame(8));
+        for _ in 0..HISTORY_LEN {
+            history.push(frame(16));
+        }
+        history.acknowledge(first);
+        assert!(history.base().is_none());
+        history.acknowledge(NO_FRAME);
+        assert!(history.base().is_none());
+    }
+
+    #[test]
+    fn ids_do_not_carry_over_restarts() {
+        let mut before = FrameHistory::default();
+        let old: Vec<u32> = (0..HISTORY_LEN).map(|_| before.push(frame(8))).collect();
+
+        // A device that outlived a restart of igen reports one of the old ids
+        let mut after = FrameHistory::default();
+        for _ in 0..HISTORY_LEN {
+            after.push(frame(16));
+        }
+        for id in old {
+            after.acknowledge(id);
+            assert!(after.base().is_none());
+        }
+    }
+}
@@ -0,0 +1,578 @@
+// Declarative dashboard layouts, see layout.example.toml for the default one.
+//
+// A layout is a tree of nodes. Every node becomes an Area, children are stacked
+// along the node's direction and sized by their `size` (pixels, percent of the
+// parent's canvas or an equal share of what is left). Overlays are placed at a
+// corner of the parent instead and drawn on top of the stacked children.
+
+use crate::render::epd::{Area, Outline, Padding, PixelFormat};
+use crate::render::fonts::FontCollection;
+use crate::render::graphics::{Color, Rect};
+use crate::widget::WidgetRegistry;
+use serde::{Deserialize, Deserializer};
+
+const DEFAULT_LAYOUT: &str = include_str!("../../layout.example.toml");
+
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    #[default]
+    Row,
+    Column,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub enum Size {
+    Fixed(usize),
+    Percent(usize),
+    #[default]
+    Fill,
+}
+
+impl<'de> Deserialize<'de> for Size {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Px(usize),
+            Text(String),
+        }
+
+        match Repr::deserialize(deserializer)? {
+            Repr::Px(px) => Ok(Size::Fixed(px)),
+            Repr::Text(text) => {
+                let text = text.trim();
+                if text == "fill" {
+                    Ok(Size::Fill)
+                } else if let Some(percent) = text.strip_suffix('%') {
+                    percent
+                        .trim()
+                        .parse()
+                        .map(Size::Percent)
+                        .map_err(serde::de::Error::custom)
+                } else {
+                    text.trim_end_matches("px")
+                        .parse()
+                        .map(Size::Fixed)
+                        .map_err(|_| serde::de::Error::custom(format!("invalid size: {text}")))
+                }
+            }
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(untagged)]
+pub enum PaddingSpec {
+    Uniform(usize),
+    Sides {
+        #[serde(default)]
+        top: usize,
+        #[serde(default)]
+        bottom: usize,
+        #[serde(default)]
+        left: usize,
+        #[serde(default)]
+        right: usize,
+    },
+}
+
+impl Default for PaddingSpec {
+    fn default() -> Self {
+        PaddingSpec::Uniform(0)
+    }
+}
+
+impl PaddingSpec {
+    // (top, bottom, left, right)
+    fn sides(self) -> (usize, usize, usize, usize) {
+        match self {
+            PaddingSpec::Uniform(pad) => (pad, pad, pad, pad),
+            PaddingSpec::Sides {
+                top,
+                bottom,
+                left,
+                right,
+            } => (top, bottom, left, right),
+        }
+    }
+}
+
+impl From<PaddingSpec> for Padding {
+    fn from(value: PaddingSpec) -> Self {
+        let (top, bottom, left, right) = value.sides();
+        Padding::new(top, bottom, left, right)
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(default)]
+pub struct OutlineSpec {
+    pub top: usize,
+    pub bottom: usize,
+    pub left: usize,
+    pub right: usize,
+    pub color: Color,
+}
+
+impl Default for OutlineSpec {
+    fn default() -> Self {
+        OutlineSpec {
+            top: 0,
+            bottom: 0,
+            left: 0,
+            right: 0,
+            color: Color::Black,
+        }
+    }
+}
+
+impl From<OutlineSpec> for Outline {
+    fn from(value: OutlineSpec) -> Self {
+        Outline {
+            top: value.top,
+            bottom: value.bottom,
+            left: value.left,
+            right: value.right,
+            color: value.color,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum Anchor {
+    #[default]
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub struct Overlay {
+    #[serde(default)]
+    pub anchor: Anchor,
+    pub width: usize,
+    pub height: usize,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct LayoutNode {
+    #[serde(default)]
+    pub direction: Direction,
+    #[serde(default)]
+    pub size: Size,
+    #[serde(default)]
+    pub padding: PaddingSpec,
+    #[serde(default)]
+    pub outline: OutlineSpec,
+    #[serde(default = "default_fill")]
+    pub fill: Color,
+    pub widget: Option<String>,
+    pub overlay: Option<Overlay>,
+    #[serde(default)]
+    pub children: Vec<LayoutNode>,
+}
+
+fn default_fill() -> Color {
+    Color::White
+}
+
+impl LayoutNode {
+    // Loads a layout file (toml or json, by extension) or the built-in default
+    // and checks that it fits a width x height dashboard
+    pub fn load(
+        path: Option<&str>,
+        width: usize,
+        height: usize,
+    ) -> Result<LayoutNode, config::ConfigError> {
+        let source = match path {
+            Some(path) => config::Config::builder().add_source(config::File::with_name(path)),
+            None => config::Config::builder().add_source(config::File::from_str(
+                DEFAULT_LAYOUT,
+                config::FileFormat::Toml,
+            )),
+        };
+        let layout: LayoutNode = source.build()?.try_deserialize()?;
+        layout
+            .check(Rect {
+                x: 0,
+                y: 0,
+                width,
+                height,
+            })
+            .map_err(config::ConfigError::Message)?;
+        Ok(layout)
+    }
+
+    // Names of all widgets bound in this subtree
+    pub fn widgets(&self) -> Vec<&str> {
+        self.widget
+            .as_deref()
+            .into_iter()
+            .chain(self.children.iter().flat_map(LayoutNode::widgets))
+            .collect()
+    }
+
+    fn name(&self) -> &str {
+        self.widget.as_deref().unwrap_or("layout node")
+    }
+
+    // Percentages above 100 and children sized beyond their parent would only
+    // be cut off (or squeezed to nothing) when rendering
+    fn check(&self, rect: Rect) -> Result<(), String> {
+        let (avail_w, avail_h) = self.canvas_size(rect);
+        let main_axis = self.main_axis(avail_w, avail_h);
+
+        let mut used = 0;
+        for child in self.stacked() {
+            used += match child.size {
+                Size::Percent(p) if p > 100 => {
+                    return Err(format!("{}: size of {p}% is above 100%", child.name()));
+                }
+                Size::Percent(p) => main_axis * p / 100,
+                Size::Fixed(px) => px,
+                Size::Fill => 0,
+            };
+        }
+        if used > main_axis {
+            return Err(format!(
+                "{}: children need {used}px but only {main_axis}px are left inside it",
+                self.name()
+            ));
+        }
+
+        for (child, child_rect) in self.child_rects(avail_w, avail_h) {
+            child.check(child_rect)?;
+        }
+        Ok(())
+    }
+
+    // What is left of rect inside padding and outline
+    fn canvas_size(&self, rect: Rect) -> (usize, usize) {
+        let (top, bottom, left, right) = self.padding.sides();
+        let outline = self.outline;
+        (
+            rect.width
+                .saturating_sub(left + right + outline.left + outline.right),
+            rect.height
+                .saturating_sub(top + bottom + outline.top + outline.bottom),
+        )
+    }
+
+    fn main_axis(&self, avail_w: usize, avail_h: usize) -> usize {
+        match self.direction {
+            Direction::Row => avail_w,
+            Direction::Column => avail_h,
+        }
+    }
+
+    fn stacked(&self) -> impl Iterator<Item = &LayoutNode> {
+        self.children.iter().filter(|c| c.overlay.is_none())
+    }
+
+    // Where the children go on a avail_w x avail_h canvas, stacked ones first
+    fn child_rects(&self, avail_w: usize, avail_h: usize) -> Vec<(&LayoutNode, Rect)> {
+        let main_axis = self.main_axis(avail_w, avail_h);
+
+        let fixed_len = |size: Size| match size {
+            Size::Fixed(px) => Some(px),
+            Size::Percent(p) => Some(main_axis * p / 100),
+            Size::Fill => None,
+        };
+        let used: usize = self.stacked().filter_map(|c| fixed_len(c.size)).sum();
+        let fills = self
+            .stacked()
+            .filter(|c| matches!(c.size, Size::Fill))
+            .count();
+        let mut left_for_fills = main_axis.saturating_sub(used);
+
+        let mut rects = vec![];
+        let mut pos = 0;
+        let mut fills_seen = 0;
+        for child in self.stacked() {
+            let len = fixed_len(child.size).unwrap_or_else(|| {
+                // Equal shares, the last fill ends up with the rounding remainder
+                let share = left_for_fills / (fills - fills_seen);
+                fills_seen += 1;
+                left_for_fills -= share;
+                share
+            });
+            let len = len.min(main_axis.saturating_sub(pos));
+
+            let child_rect = match self.direction {
+                Direction::Row => Rect {
+                    x: pos,
+                    y: 0,
+                    width: len,
+                    height: avail_h,
+                },
+                Direction::Column => Rect {
+                    x: 0,
+                    y: pos,
+                    width: avail_w,
+                    height: len,
+                },
+            };
+            pos += len;
+            rects.push((child, child_rect));
+        }
+
+        for child in self.children.iter() {
+            let Some(overlay) = child.overlay else {
+                continue;
+            };
+            let width = overlay.width.min(avail_w);
+            let height = overlay.height.min(avail_h);
+            let (x, y) = match overlay.anchor {
+                Anchor::TopLeft => (0, 0),
+                Anchor::TopRight => (avail_w - width, 0),
+                Anchor::BottomLeft => (0, avail_h - height),
+                Anchor::BottomRight => (avail_w - width, avail_h - height),
+            };
+            rects.push((
+                child,
+                Rect {
+                    x,
+                    y,
+                    width,
+                    height,
+                },
+            ));
+        }
+        rects
+    }
+
+    // Builds the area tree for this node at rect (relative to the parent's canvas)
+    // and lets the bound widgets render into it
+    pub fn build(
+        &self,
+        rect: Rect,
+        format: PixelFormat,
+        widgets: &WidgetRegistry,
+        fonts: &mut FontCollection,
+    ) -> Area {
+        let mut area = Area::new(
+            rect.x,
+            rect.y,
+            rect.width,
+            rect.height,
+            self.fill,
+            self.padding.into(),
+            self.outline.into(),
+        );
+        area.set_format(format);
+        let (avail_w, avail_h) = (area.get_available_hspace(), area.get_available_vspace());
+
+        // Nothing to draw into, widgets assume at least a pixel
+        if let Some(widget) = &self.widget
+            && avail_w > 0
+            && avail_h > 0
+        {
+            widgets.render(widget, &mut area, fonts);
+        }
+
+        for (child, child_rect) in self.child_rects(avail_w, avail_h) {
+            area.add_sub_area(child.build(child_rect, format, widgets, fonts));
+        }
+
+        area
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::epd::EpdImage;
+    use crate::render::graphics::PixelColor;
+
+    fn parse(toml: &str) -> LayoutNode {
+        config::Config::builder()
+            .add_source(config::File::from_str(toml, config::FileFormat::Toml))
+            .build()
+            .unwrap()
+            .try_deserialize()
+            .unwrap()
+    }
+
+    fn rect(width: usize, height: usize) -> Rect {
+        Rect {
+            x: 0,
+            y: 0,
+            width,
+            height,
+        }
+    }
+
+    // Every default widget with something to show, read from a cache dir of its own
+    async fn populated_widgets(dir: &std::path::Path) -> WidgetRegistry {
+        use crate::provider::cache::DiskCache;
+        use crate::provider::calendar::{Event, Time};
+        use crate::provider::image::ImageEntry;
+        use crate::provider::quote::Quote;
+        use crate::provider::weather::{NiceCurrent, NiceDaily, NiceWeatherData};
+        use chrono::{Local, TimeDelta};
+
+        let cache = dir.join("cache").to_string_lossy().into_owned();
+        let today = Local::now().date_naive();
+
+        let events: Vec<Event> = (0..12)
+            .map(|i| {
+                let time = if i % 3 == 0 {
+                    Time::AllDay(today + TimeDelta::days(i / 3))
+                } else {
+                    Time::Timed(Local::now() + TimeDelta::hours(i * 5), TimeDelta::hours(1))
+                };
+                Event::new(time, format!("A rather long event title {i}"))
+            })
+            .collect();
+        DiskCache::open(&cache, "calendar").store(events);
+
+        let day = |code: usize| NiceDaily {
+            sunshine: 7200.0,
+            temp_min: -12.5,
+            temp_max: 21.25,
+            weather_code: code.into(),
+        };
+        DiskCache::open(&cache, "weather").store(NiceWeatherData {
+            current: NiceCurrent {
+                temperature: 12.5,
+                humidity: 80.0,
+                weather_code: 96.into(),
+            },
+            days: (0..3)
+                .map(|i| (today + TimeDelta::days(i), day(57)))
+                .collect(),
+        });
+
+        DiskCache::open(&cache, "quote").store(Quote {
+            content: "Rendering into whatever space is left is harder than it looks, \
+                      especially with long quotes like this one"
+                .to_string(),
+            author: "igen".to_string(),
+            tags: vec![],
+        });
+
+        let photo = dir.join("photo.png");
+        std::fs::create_dir_all(dir).unwrap();
+        image::RgbImage::from_fn(64, 48, |x, y| {
+            image::Rgb([(x * 4) as u8, (y * 5) as u8, 90])
+        })
+        .save(&photo)
+        .unwrap();
+        DiskCache::open(&cache, "image")
+            .store(ImageEntry::from_path(photo.to_string_lossy().into_owned()));
+
+        let toml = format!(
+            r#"
+            [general]
+            debug = false
+            [cache]
+            dir = "{cache}"
+            [quote]
+            quotes_path = ""
+            [image]
+            [weather]
+            latitude = "0"
+            longitude = "0"
+            timezone = "UTC"
+            "#
+        );
+        let config = config::Config::builder()
+            .add_source(config::File::from_str(&toml, config::FileFormat::Toml))
+            .build()
+            .unwrap()
+            .try_deserialize()
+            .unwrap();
+        WidgetRegistry::with_defaults(&config).await
+    }
+
+    #[tokio::test]
+    async fn default_layout_fits_supported_panels() {
+        let dir = std::env::temp_dir().join(format!("igen-layout-{}", std::process::id()));
+        let widgets = populated_widgets(&dir).await;
+        let mut fonts = FontCollection::new();
+        for (width, height) in [(800, 480), (480, 800), (400, 300), (648, 480)] {
+            let layout = LayoutNode::load(None, width, height).unwrap();
+            for format in [PixelFormat::Mono, PixelFormat::Gray4] {
+                let mut image = EpdImage::new(width, height, format);
+                layout
+                    .build(rect(width, height), format, &widgets, &mut fonts)
+                    .draw(&mut image);
+                assert!(image.data().iter().any(|b| *b != 0xFF));
+            }
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_over_full_rows() {
+        let layout = parse(
+            r#"
+            padding = 10
+            [[children]]
+            size = 500
+            [[children]]
+            size = "50%"
+            [[children]]
+            widget = "clock"
+            "#,
+        );
+        // 500 + 390 of the 780 left inside the padding
+        assert!(layout.check(rect(800, 480)).is_err());
+        layout.check(rect(1200, 480)).unwrap();
+
+        let layout = parse(
+            r#"
+            [[children]]
+            size = "150%"
+            "#,
+        );
+        assert!(layout.check(rect(800, 480)).is_err());
+    }
+
+    #[test]
+    fn builds_squeezed_areas() {
+        // Passes no check, build must still cope with it
+        let layout = parse(
+            r#"
+            direction = "row"
+            [[children]]
+            size = 150
+            widget = "clock"
+            [[children]]
+            size = 30
+            padding = 4
+            outline = { left = 5 }
+            widget = "clock"
+            [[children]]
+            size = 40
+            padding = 20
+            outline = { left = 40, right = 3 }
+            widget = "clock"
+            "#,
+        );
+        let mut widgets = WidgetRegistry::new();
+        widgets.register("clock", Box::new(crate::widget::clock::ClockWidget::new()));
+        let area = layout.build(
+            rect(200, 40),
+            PixelFormat::Mono,
+            &widgets,
+            &mut FontCollection::new(),
+        );
+        let mut image = EpdImage::new(200, 40, PixelFormat::Mono);
+        area.draw(&mut image);
+
+        let inked = |xs: std::ops::Range<usize>| {
+            xs.flat_map(|x| (0..40).map(move |y| (x, y)))
+                .filter(|&(x, y)| image.get_pixel(x, y) == PixelColor::Black)
+                .count()
+        };
+        // The clock that fits shows, the squeezed ones keep just their outline
+        assert!(inked(0..150) > 0);
+        assert_eq!(inked(150..155), 5 * 40);
+        assert_eq!(inked(155..180), 0);
+        assert_eq!(inked(180..200), 20 * 40);
+    }
+}
@@ -1,4 +1,10 @@
 pub mod dash;
-mod epd;
-mod fonts;
-mod graphics;
+mod diff;
+pub mod dither;
+pub mod epd;
+pub mod fit;
+pub mod fonts;
+pub mod graphics;
+mod history;
+pub mod layout;
+pub mod transform;
@@ -0,0 +1,150 @@
+// Mapping the dashboard as composed (logical, what you see when reading the
+// mounted panel) onto the frame as the panel expects it (physical, the
+// driver's native orientation).
+//
+// The logical frame is rotated clockwise first, then mirrored. Everything
+// after that, diffing included, only ever sees physical frames, so partial
+// update rects come out aligned to 8 on the physical x axis for free.
+
+use crate::render::epd::EpdImage;
+use crate::render::graphics::Pixel;
+use serde::Deserialize;
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, Eq, PartialEq)]
+#[serde(try_from = "u16")]
+pub enum Rotation {
+    #[default]
+    None,
+    Cw90,
+    Cw180,
+    Cw270,
+}
+
+impl TryFrom<u16> for Rotation {
+    type Error = String;
+
+    fn try_from(degrees: u16) -> Result<Self, Self::Error> {
+        match degrees {
+            0 => Ok(Rotation::None),
+            90 => Ok(Rotation::Cw90),
+            180 => Ok(Rotation::Cw180),
+            270 => Ok(Rotation::Cw270),
+            other => Err(format!("rotation has to be 0, 90, 180 or 270, not {other}")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct Transform {
+    pub rotation: Rotation,
+    // Flip left and right, after rotating
+    pub mirror_x: bool,
+    // Flip top and bottom, after rotating
+    pub mirror_y: bool,
+}
+
+impl Transform {
+    pub fn is_identity(self) -> bool {
+        self == Transform::default()
+    }
+
+    fn swaps_axes(self) -> bool {
+        matches!(self.rotation, Rotation::Cw90 | Rotation::Cw270)
+    }
+
+    // Size to compose the dashboard in for a panel of the given physical size
+    pub fn logical_size(self, width: usize, height: usize) -> (usize, usize) {
+        if self.swaps_axes() {
+            (height, width)
+        } else {
+            (width, height)
+        }
+    }
+
+    // Where logical (x, y) of a width x height logical frame ends up
+    pub fn to_physical(self, x: usize, y: usize, width: usize, height: usize) -> (usize, usize) {
+        let (x, y) = match self.rotation {
+            Rotation::None => (x, y),
+            Rotation::Cw90 => (height - 1 - y, x),
+            Rotation::Cw180 => (width - 1 - x, height - 1 - y),
+            Rotation::Cw270 => (y, width - 1 - x),
+        };
+        let (pw, ph) = self.logical_size(width, height);
+        (
+            if self.mirror_x { pw - 1 - x } else { x },
+            if self.mirror_y { ph - 1 - y } else { y },
+        )
+    }
+
+    pub fn apply(self, logical: EpdImage) -> EpdImage {
+        if self.is_identity() {
+            return logical;
+        }
+        let (width, height) = (logical.width(), logical.height());
+        let (pw, ph) = self.logical_size(width, height);
+        let mut physical = EpdImage::new(pw, ph, logical.format());
+        for y in 0..height {
+            for x in 0..width {
+                let (px, py) = self.to_physical(x, y, width, height);
+                physical.set_pixel(px, py, Pixel::solid(logical.get_pixel(x, y)));
+            }
+        }
+        physical
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::epd::PixelFormat;
+    use crate::render::graphics::PixelColor;
+
+    fn transform(degrees: u16, mirror_x: bool, mirror_y: bool) -> Transform {
+        Transform {
+            rotation: Rotation::try_from(degrees).unwrap(),
+            mirror_x,
+            mirror_y,
+        }
+    }
+
+    #[test]
+    fn corners() {
+        // Top left of a 4x2 logical frame
+        let corner = |t: Transform| t.to_physical(0, 0, 4, 2);
+        assert_eq!(corner(transform(0, false, false)), (0, 0));
+        assert_eq!(corner(transform(90, false, false)), (1, 0));
+        assert_eq!(corner(transform(180, false, false)), (3, 1));
+        assert_eq!(corner(transform(270, false, false)), (0, 3));
+        assert_eq!(corner(transform(0, true, false)), (3, 0));
+        assert_eq!(corner(transform(90, false, true)), (1, 3));
+        assert!(Rotation::try_from(45).is_err());
+    }
+
+    #[test]
+    fn every_pixel_lands_once() {
+        for degrees in [0, 90, 180, 270] {
+            for (mx, my) in [(false, false), (true, false), (false, true), (true, true)] {
+                let t = transform(degrees, mx, my);
+                let (pw, ph) = t.logical_size(5, 3);
+                let mut hit = vec![false; pw * ph];
+                for y in 0..3 {
+                    for x in 0..5 {
+                        let (px, py) = t.to_physical(x, y, 5, 3);
+                        assert!(!hit[py * pw + px]);
+                        hit[py * pw + px] = true;
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn portrait_frame() {
+        let mut logical = EpdImage::new(480, 800, PixelFormat::Mono);
+        logical.set_pixel(0, 0, Pixel::solid(PixelColor::Black));
+        let physical = transform(90, false, false).apply(logical);
+        assert_eq!((physical.width(), physical.height()), (800, 480));
+        assert_eq!(physical.get_pixel(799, 0), PixelColor::Black);
+        assert_eq!(physical.get_pixel(0, 0), PixelColor::White);
+    }
+}
@@ -1,24 +1,150 @@
-use serde::Deserialize;
+use crate::render::dither::Dither;
+use crate::render::epd::PixelFormat;
+use crate::render::fit::Fit;
+use crate::render::graphics::Color;
+use crate::render::transform::{Rotation, Transform};
+use chrono::NaiveTime;
+use serde::de::Error;
+use serde::{Deserialize, Deserializer};
+use std::time::Duration;
+
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthFlow {
+    // A browser on this machine is redirected back to redirect_uri
+    #[default]
+    Loopback,
+    // A code is entered on any other device, for headless setups
+    Device,
+}
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct GoogleConfig {
     pub token_path: String,
     pub client_id: String,
     pub client_secret: String,
+    #[serde(default)]
+    pub auth_flow: AuthFlow,
     pub auth_uri: String,
+    #[serde(default = "default_device_auth_uri")]
+    pub device_auth_uri: String,
+    // Has to point at this machine, igen listens on its port during the loopback flow
     pub redirect_uri: String,
     pub token_uri: String,
-    pub calendar_list: Vec<String>,
+    // Plain names are accepted as well, as is the old calender_list spelling
+    #[serde(alias = "calender_list", deserialize_with = "calendar_entries")]
+    pub calendar_list: Vec<CalendarEntry>,
+}
+
+// How the events of one calendar show up in the calendar widget
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct CalendarDisplay {
+    // Put in front of every event, e.g. a symbol
+    pub prefix: Option<String>,
+    // Of the event text, black if unset
+    pub color: Option<Color>,
+    // 10 if unset
+    pub max_events: Option<usize>,
+    pub hide_timed: bool,
+    pub hide_all_day: bool,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct CalendarEntry {
+    // From the calendar's settings, keeps working when the calendar is renamed
+    pub id: Option<String>,
+    // As shown in Google Calendar
+    pub name: Option<String>,
+    #[serde(flatten)]
+    pub display: CalendarDisplay,
+}
+
+fn calendar_entries<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<CalendarEntry>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Entry {
+        Name(String),
+        Table(CalendarEntry),
+    }
+
+    Vec::<Entry>::deserialize(d)?
+        .into_iter()
+        .map(|entry| match entry {
+            Entry::Name(name) => Ok(CalendarEntry {
+                id: None,
+                name: Some(name),
+                display: CalendarDisplay::default(),
+            }),
+            Entry::Table(CalendarEntry {
+                id: None,
+                name: None,
+                ..
+            }) => Err(D::Error::custom("calendar entries need an id or a name")),
+            Entry::Table(entry) => Ok(entry),
+        })
+        .collect()
+}
+
+fn default_device_auth_uri() -> String {
+    "https://oauth2.googleapis.com/device/code".to_string()
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum CalendarSourceConfig {
+    // The calendars in [google] calendar_list
+    Google,
+    // A local .ics file or an http(s)/webcal url serving one
+    Ics {
+        source: String,
+        #[serde(flatten)]
+        display: CalendarDisplay,
+    },
+    // A calendar collection, e.g. https://cloud.example.com/remote.php/dav/calendars/me/personal/
+    Caldav {
+        url: String,
+        username: Option<String>,
+        password: Option<String>,
+        #[serde(flatten)]
+        display: CalendarDisplay,
+    },
 }
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct QuoteConfig {
     pub quotes_path: String,
+    // Shuffled order without repeats until every quote was shown once
+    #[serde(default)]
+    pub shuffle: bool,
+    // Seed of the first shuffled pass, random if unset
+    pub seed: Option<u64>,
 }
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct ImageConfig {
-    pub images_path: String,
+    // json list of paths or entries with per-image options
+    pub images_path: Option<String>,
+    // Scanned recursively on every fetch
+    #[serde(default)]
+    pub directories: Vec<String>,
+    // Files in directories with other extensions are ignored
+    #[serde(default = "default_image_extensions")]
+    pub extensions: Vec<String>,
+    // Used for every image that doesn't pick its own
+    #[serde(default)]
+    pub dither: Dither,
+    #[serde(default)]
+    pub fit: Fit,
+    #[serde(default)]
+    pub shuffle: bool,
+    pub seed: Option<u64>,
+}
+
+fn default_image_extensions() -> Vec<String> {
+    ["png", "webp", "bmp", "jpg", "jpeg"]
+        .map(String::from)
+        .to_vec()
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -31,13 +157,242 @@ pub struct WeatherConfig {
 #[derive(Deserialize, Debug, Clone)]
 pub struct GeneralConfig {
     pub debug: bool,
+    // toml or json, the built-in layout (layout.example.toml) is used if unset
+    pub layout_path: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct RenderConfig {
+    // Partial updates are only sent while the summed area of all dirty rects stays below this
+    pub max_partial_area: usize,
+    // Forces a full refresh after this many partial updates to get rid of ghosting
+    pub max_partial_updates: usize,
+    // Nearby changes get merged until at most this many rects are left
+    pub max_dirty_rects: usize,
+}
+
+impl Default for RenderConfig {
+    fn default() -> Self {
+        Self {
+            max_partial_area: 200 * 50,
+            max_partial_updates: 10,
+            max_dirty_rects: 4,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct PanelConfig {
+    // In pixels, as the panel is mounted in the driver's native orientation
+    pub width: usize,
+    pub height: usize,
+    // mono, gray4 or tri-color, decides the bits per pixel
+    pub format: PixelFormat,
+    // Clockwise, for panels mounted in portrait or upside down
+    pub rotation: Rotation,
+    pub mirror_x: bool,
+    pub mirror_y: bool,
+}
+
+impl PanelConfig {
+    pub fn transform(&self) -> Transform {
+        Transform {
+            rotation: self.rotation,
+            mirror_x: self.mirror_x,
+            mirror_y: self.mirror_y,
+        }
+    }
+}
+
+impl Default for PanelConfig {
+    // Waveshare 7.5" V2
+    fn default() -> Self {
+        Self {
+            width: 800,
+            height: 480,
+            format: PixelFormat::Mono,
+            rotation: Rotation::None,
+            mirror_x: false,
+            mirror_y: false,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct SleepConfig {
+    // Bounds in seconds for how long devices are told to sleep between polls
+    #[serde(deserialize_with = "non_zero")]
+    pub min: u64,
+    pub max: u64,
+    // "HH:MM", devices sleep through these, may wrap around midnight
+    pub quiet_start: Option<NaiveTime>,
+    pub quiet_end: Option<NaiveTime>,
+}
+
+// For settings where 0 would have devices or tasks spin without pause
+fn non_zero<'de, D: Deserializer<'de>>(d: D) -> Result<u64, D::Error> {
+    match u64::deserialize(d)? {
+        0 => Err(D::Error::custom("must be at least 1")),
+        value => Ok(value),
+    }
+}
+
+impl Default for SleepConfig {
+    fn default() -> Self {
+        Self {
+            min: 30,
+            max: 60 * 60,
+            quiet_start: None,
+            quiet_end: None,
+        }
+    }
+}
+
+// Only how the dashboard is laid out and sent differs per device. Widgets and
+// their providers (calendars, image, quote, weather) are shared by all of
+// them, so anything else is rejected instead of silently ignored
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceConfig {
+    pub id: String,
+    // Each of these replaces the top-level one for this device when set
+    pub layout_path: Option<String>,
+    pub panel: Option<PanelConfig>,
+    pub render: Option<RenderConfig>,
+    pub sleep: Option<SleepConfig>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct CacheConfig {
+    // Every provider keeps its last result in here as json
+    pub dir: String,
+    // Refresh intervals in minutes
+    #[serde(deserialize_with = "non_zero")]
+    pub calendar: u64,
+    #[serde(deserialize_with = "non_zero")]
+    pub weather: u64,
+    #[serde(deserialize_with = "non_zero")]
+    pub quote: u64,
+    #[serde(deserialize_with = "non_zero")]
+    pub image: u64,
+}
+
+impl CacheConfig {
+    pub fn interval(minutes: u64) -> Duration {
+        Duration::from_secs(minutes * 60)
+    }
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            dir: "./cache".to_string(),
+            calendar: 5,
+            weather: 15,
+            quote: 15,
+            image: 60,
+        }
+    }
 }
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct Config {
     pub general: GeneralConfig,
-    pub google: GoogleConfig,
+    #[serde(default)]
+    pub render: RenderConfig,
+    #[serde(default)]
+    pub panel: PanelConfig,
+    #[serde(default)]
+    pub cache: CacheConfig,
+    #[serde(default)]
+    pub sleep: SleepConfig,
+    // Displays with a dashboard of their own, served at /device/{id}/image
+    #[serde(default)]
+    pub devices: Vec<DeviceConfig>,
+    // Merged into one calendar, just google if unset
+    #[serde(default)]
+    pub calendars: Vec<CalendarSourceConfig>,
+    // Only needed for the google calendar source
+    pub google: Option<GoogleConfig>,
     pub quote: QuoteConfig,
     pub image: ImageConfig,
     pub weather: WeatherConfig,
 }
+
+impl Config {
+    // The config as seen by the dashboard of one device
+    pub fn for_device(&self, device: &DeviceConfig) -> Config {
+        let mut config = self.clone();
+        if let Some(layout_path) = &device.layout_path {
+            config.general.layout_path = Some(layout_path.clone());
+        }
+        if let Some(panel) = &device.panel {
+            config.panel = panel.clone();
+        }
+        if let Some(render) = &device.render {
+            config.render = render.clone();
+        }
+        if let Some(sleep) = &device.sleep {
+            config.sleep = sleep.clone();
+        }
+        config
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse<T: serde::de::DeserializeOwned>(toml: &str) -> Result<T, config::ConfigError> {
+        config::Config::builder()
+            .add_source(config::File::from_str(toml, config::FileFormat::Toml))
+            .build()?
+            .try_deserialize()
+    }
+
+    #[test]
+    fn devices_only_override_the_dashboard() {
+        let hallway = parse::<DeviceConfig>(
+            r#"
+            id = "hallway"
+            [panel]
+            width = 400
+            height = 300
+            "#,
+        )
+        .unwrap();
+        assert_eq!(hallway.panel.unwrap().width, 400);
+
+        // Widgets are shared, this would have no effect
+        assert!(
+            parse::<DeviceConfig>(
+                r#"
+                id = "kitchen"
+                [image]
+                directories = ["./kitchen"]
+                "#,
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn rejects_refreshing_nonstop() {
+        let cache = parse::<CacheConfig>;
+        assert_eq!(cache("quote = 1").unwrap().quote, 1);
+        assert_eq!(cache("quote = 1").unwrap().image, 60);
+        assert!(cache("weather = 0").is_err());
+    }
+
+    #[test]
+    fn rejects_sleeping_for_nothing() {
+        let sleep = parse::<SleepConfig>;
+        assert_eq!(sleep("min = 1").unwrap().min, 1);
+        assert_eq!(sleep("max = 600").unwrap().min, 30);
+        assert!(sleep("min = 0").is_err());
+    }
+}
@@ -0,0 +1,211 @@
+use crate::provider::ProviderError;
+use crate::provider::cache::{CacheEntry, DiskCache};
+use crate::provider::calendar::{Calendars, Event, Time};
+use crate::render::epd::{Area, Outline, Padding};
+use crate::render::fonts::{Font, FontCollection};
+use crate::render::graphics::Color;
+use crate::settings::{CacheConfig, Config};
+use crate::widget::Widget;
+use async_trait::async_trait;
+use chrono::{DateTime, Local, NaiveDate};
+use fontdue::layout::{HorizontalAlign, LayoutSettings, TextStyle, VerticalAlign};
+use std::collections::{BTreeSet, HashMap};
+use std::time::Duration;
+
+pub struct CalendarWidget {
+    provider: tokio::sync::Mutex<Calendars>,
+    events: DiskCache<Vec<Event>>,
+    interval: Duration,
+}
+
+impl CalendarWidget {
+    pub async fn new(config: Config) -> Self {
+        CalendarWidget {
+            events: DiskCache::open(&config.cache.dir, "calendar"),
+            interval: CacheConfig::interval(config.cache.calendar),
+            provider: tokio::sync::Mutex::new(Calendars::new(config).await),
+        }
+    }
+}
+
+#[async_trait]
+impl Widget for CalendarWidget {
+    async fn fetch(&self) -> Result<(), ProviderError> {
+        let events = self.provider.lock().await.fetch().await?;
+        self.events.store(events);
+        Ok(())
+    }
+
+    fn refresh_interval(&self) -> Option<Duration> {
+        Some(self.interval)
+    }
+
+    fn last_fetch(&self) -> Option<DateTime<Local>> {
+        self.events.fetched_at()
+    }
+
+    fn has_data(&self) -> bool {
+        self.events.get().is_some()
+    }
+
+    // The next event start and midnight, when another day is highlighted
+    fn next_change(&self, now: DateTime<Local>) -> Option<DateTime<Local>> {
+        let midnight = now
+            .date_naive()
+            .succ_opt()
+            .and_then(|d| d.and_hms_opt(0, 0, 0))
+            .and_then(|d| d.and_local_timezone(Local).earliest());
+        let cached = self.events.get();
+        let next_start = cached.as_ref().and_then(|c| {
+            c.value
+                .iter()
+                .filter_map(|e| match e.time {
+                    Time::Timed(start, _) if start > now => Some(start),
+                    _ => None,
+                })
+                .min()
+        });
+        midnight.into_iter().chain(next_start).min()
+    }
+
+    fn render(&self, cal: &mut Area, fonts: &mut FontCollection) {
+        // This should be possible without the clone, no?
+        let date_font = fonts.load_font(Font::Wellfleet);
+        let title_font = fonts.load_font(Font::Dina);
+
+        let mut events_per_day: HashMap<NaiveDate, Vec<&Event>> = HashMap::new();
+        let mut dates: BTreeSet<NaiveDate> = BTreeSet::new();
+        let cached = self.events.get();
+        let Some(CacheEntry { value: events, .. }) = cached.as_ref() else {
+            return;
+        };
+        events.iter().for_each(|e| match e.time {
+            Time::AllDay(nd) => {
+                dates.insert(nd);
+                if let std::collections::hash_map::Entry::Vacant(entry) = events_per_day.entry(nd) {
+                    entry.insert(vec![e]);
+                } else {
+                    events_per_day.get_mut(&nd).unwrap().push(e);
+                }
+            }
+            Time::Timed(dt, _) => {
+                dates.insert(dt.date_naive());
+                if let std::collections::hash_map::Entry::Vacant(entry) =
+                    events_per_day.entry(dt.date_naive())
+                {
+                    entry.insert(vec![e]);
+                } else {
+                    events_per_day.get_mut(&dt.date_naive()).unwrap().push(e);
+                }
+            }
+        });
+
+        const DATE_EVENT_PADDING: usize = 2;
+        const DATE_HEIGHT: usize = 32;
+        const EVENT_HEIGHT: usize = 24;
+        const EVENT_PADDING: usize = 2;
+        const TITLE_MAX_LENGTH: usize = 16;
+        // By chars, prefixes tend to be symbols outside of ascii
+        let fit_title = |title: &str| {
+            if title.chars().count() > TITLE_MAX_LENGTH {
+                format!(
+                    "{}>",
+                    title.chars().take(TITLE_MAX_LENGTH).collect::<String>()
+                )
+            } else {
+                title.to_string()
+            }
+        };
+
+        let mut cur_y = cal.get_vstart();
+
+        for date in dates {
+            // Take until we can still fit a date + event
+            if (cur_y + DATE_HEIGHT + DATE_EVENT_PADDING + EVENT_HEIGHT)
+                >= cal.get_available_vspace()
+            {
+                break;
+            }
+
+            let mut date_area = Area::new(
+                0,
+                cur_y,
+                cal.get_available_hspace(),
+                DATE_HEIGHT,
+                Color::White,
+                Padding::full(0),
+                Outline::default(),
+            );
+            if date == Local::now().date_naive() {
+                date_area.set_text_color(Color::Accent);
+            }
+            date_area.put_text(
+                &date_font,
+                LayoutSettings {
+                    x: date_area.get_hstart() as f32,
+                    y: date_area.get_vstart() as f32,
+                    max_width: Some(date_area.get_available_hspace() as f32),
+                    max_height: Some(date_area.get_available_vspace() as f32),
+                    horizontal_align: HorizontalAlign::Center,
+                    vertical_align: VerticalAlign::Middle,
+                    ..LayoutSettings::default()
+                },
+                &[TextStyle::new(
+                    date.format("%a, %-d. %b").to_string().as_str(),
+                    23.0,
+                    0,
+                )],
+                50,
+            );
+            cal.add_sub_area(date_area);
+
+            cur_y += DATE_HEIGHT + DATE_EVENT_PADDING;
+
+            for event in events_per_day.get(&date).unwrap().iter() {
+                if (cur_y + EVENT_HEIGHT) >= cal.get_available_vspace() {
+                    break;
+                }
+
+                let mut event_area = Area::new(
+                    0,
+                    cur_y,
+                    cal.get_available_hspace(),
+                    EVENT_HEIGHT,
+                    Color::White,
+                    Padding::full(0),
+                    Outline::none(),
+                );
+
+                let text = match event.time {
+                    Time::AllDay(_) => event.title.clone(),
+                    Time::Timed(dt, _) => format!("{} {}", dt.format("%H:%M"), event.title),
+                };
+                let text = match &event.prefix {
+                    Some(prefix) => format!("{prefix} {text}"),
+                    None => text,
+                };
+                if let Some(color) = event.color {
+                    event_area.set_text_color(color);
+                }
+
+                event_area.put_text(
+                    &title_font,
+                    LayoutSettings {
+                        x: event_area.get_hstart() as f32,
+                        y: event_area.get_vstart() as f32,
+                        max_width: Some(event_area.get_available_hspace() as f32),
+                        max_height: Some(event_area.get_available_vspace() as f32),
+                        horizontal_align: HorizontalAlign::Left,
+                        vertical_align: VerticalAlign::Middle,
+                        ..LayoutSettings::default()
+                    },
+                    &[TextStyle::new(fit_title(&text).as_str(), 22.0, 0)],
+                    20,
+                );
+                cal.add_sub_area(event_area);
+
+                cur_y += EVENT_HEIGHT + EVENT_PADDING;
+            }
+        }
+    }
+}
@@ -0,0 +1,58 @@
+use crate::provider::ProviderError;
+use crate::render::epd::Area;
+use crate::render::fonts::{Font, FontCollection};
+use crate::widget::Widget;
+use async_trait::async_trait;
+use chrono::{DateTime, Local, TimeDelta, Timelike};
+use fontdue::layout::{HorizontalAlign, LayoutSettings, TextStyle};
+use std::time::Duration;
+
+pub struct ClockWidget;
+
+impl ClockWidget {
+    pub fn new() -> Self {
+        ClockWidget
+    }
+}
+
+#[async_trait]
+impl Widget for ClockWidget {
+    // Nothing to fetch, we always show the time of rendering
+    async fn fetch(&self) -> Result<(), ProviderError> {
+        Ok(())
+    }
+
+    fn refresh_interval(&self) -> Option<Duration> {
+        None
+    }
+
+    fn last_fetch(&self) -> Option<DateTime<Local>> {
+        None
+    }
+
+    fn has_data(&self) -> bool {
+        true
+    }
+
+    fn next_change(&self, now: DateTime<Local>) -> Option<DateTime<Local>> {
+        let minute = now.with_second(0)?.with_nanosecond(0)?;
+        Some(minute + TimeDelta::minutes(1))
+    }
+
+    fn render(&self, area: &mut Area, fonts: &mut FontCollection) {
+        let font = fonts.load_font(Font::Wellfleet);
+        let now = chrono::Local::now();
+        let now_str = now.format("%H:%M").to_string();
+        area.put_text(
+            &font,
+            LayoutSettings {
+                max_width: Some(area.get_available_hspace() as f32),
+                max_height: Some(area.get_available_vspace() as f32),
+                horizontal_align: HorizontalAlign::Center,
+                ..LayoutSettings::default()
+            },
+            &[TextStyle::new(now_str.as_str(), 32f32, 0)],
+            110,
+        );
+    }
+}
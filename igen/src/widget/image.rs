@@ -0,0 +1,126 @@
+use crate::provider::ProviderError;
+use crate::provider::cache::DiskCache;
+use crate::provider::image::{ImageEntry, ImageProvider};
+use crate::render::dither::Dither;
+use crate::render::epd::{self, Area, PixelFormat};
+use crate::render::fit::{self, Fit};
+use crate::render::fonts::FontCollection;
+use crate::render::graphics::Pixel;
+use crate::settings::{CacheConfig, Config};
+use crate::widget::Widget;
+use async_trait::async_trait;
+use chrono::{DateTime, Local};
+use image::DynamicImage;
+use log::warn;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+// Canvas width and height, and the format of the panel it is shown on
+type RenditionKey = (u32, u32, Option<PixelFormat>);
+
+struct Rendition {
+    width: usize,
+    height: usize,
+    pixels: Vec<Pixel>,
+}
+
+pub struct ImageWidget {
+    provider: Mutex<ImageProvider>,
+    // Only the entry goes to disk, the image is reopened from its path on startup
+    entry: DiskCache<ImageEntry>,
+    image: Mutex<Option<DynamicImage>>,
+    // Rotating, resizing and dithering are the slow part of rendering, so the
+    // result is kept per area and format until the next image, one for each
+    // kind of device showing it
+    renditions: Mutex<HashMap<RenditionKey, Rendition>>,
+    default_dither: Dither,
+    default_fit: Fit,
+    interval: Duration,
+}
+
+impl ImageWidget {
+    pub fn new(config: Config) -> Self {
+        let entry: DiskCache<ImageEntry> = DiskCache::open(&config.cache.dir, "image");
+        let image = entry.get().as_ref().and_then(|cached| {
+            let path = &cached.value.path;
+            image::open(path)
+                .inspect_err(|e| warn!("Could not reopen cached image {}: {}", path, e))
+                .ok()
+        });
+        ImageWidget {
+            interval: CacheConfig::interval(config.cache.image),
+            default_dither: config.image.dither,
+            default_fit: config.image.fit,
+            provider: Mutex::new(ImageProvider::new(config)),
+            entry,
+            image: Mutex::new(image),
+            renditions: Mutex::default(),
+        }
+    }
+}
+
+#[async_trait]
+impl Widget for ImageWidget {
+    async fn fetch(&self) -> Result<(), ProviderError> {
+        let (entry, image) = self.provider.lock().unwrap().get_image()?;
+        self.entry.store(entry);
+        *self.image.lock().unwrap() = Some(image);
+        self.renditions.lock().unwrap().clear();
+        Ok(())
+    }
+
+    fn refresh_interval(&self) -> Option<Duration> {
+        Some(self.interval)
+    }
+
+    fn last_fetch(&self) -> Option<DateTime<Local>> {
+        // Without the image there is nothing to keep
+        self.image
+            .lock()
+            .unwrap()
+            .as_ref()
+            .and(self.entry.fetched_at())
+    }
+
+    fn has_data(&self) -> bool {
+        self.image.lock().unwrap().is_some()
+    }
+
+    fn render(&self, image_area: &mut Area, _fonts: &mut FontCollection) {
+        let image = self.image.lock().unwrap();
+        let Some(image) = image.as_ref() else {
+            return;
+        };
+        let key = (
+            image_area.get_available_hspace() as u32,
+            image_area.get_available_vspace() as u32,
+            image_area.format(),
+        );
+        let cached = self.entry.get();
+        let entry = cached.as_ref().map(|c| &c.value);
+
+        let mut renditions = self.renditions.lock().unwrap();
+        let rendition = renditions.entry(key).or_insert_with(|| {
+            let (width, height, format) = key;
+            let rotated = fit::rotate(image.clone(), entry.map_or(0, |e| e.rotate));
+            let resized = fit::fit(
+                &rotated,
+                width,
+                height,
+                entry.and_then(|e| e.fit).unwrap_or(self.default_fit),
+                entry.map(|e| e.focal_point).unwrap_or_default(),
+            );
+            let dither = entry.and_then(|e| e.dither).unwrap_or(self.default_dither);
+            Rendition {
+                width: resized.width() as usize,
+                height: resized.height() as usize,
+                pixels: epd::dither_image(&resized, dither, format),
+            }
+        });
+
+        let x_off = (image_area.get_available_hspace() - rendition.width) / 2;
+        let y_off = (image_area.get_available_vspace() - rendition.height) / 2;
+        image_area.put_pixels(x_off, y_off, rendition.width, &rendition.pixels);
+    }
+}
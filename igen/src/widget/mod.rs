@@ -0,0 +1,276 @@
+use crate::provider::ProviderError;
+use crate::render::epd::{Area, Outline, Padding};
+use crate::render::fonts::{Font, FontCollection};
+use crate::render::graphics::Color;
+use crate::settings::Config;
+use async_trait::async_trait;
+use chrono::{DateTime, Local};
+use fontdue::layout::{HorizontalAlign, LayoutSettings, TextStyle, VerticalAlign};
+use log::{debug, warn};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+pub mod calendar;
+pub mod clock;
+pub mod image;
+pub mod quote;
+pub mod schedule;
+pub mod weather;
+
+// Failed fetches are retried after this at the latest, whatever the widget's interval
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+#[async_trait]
+pub trait Widget: Send + Sync {
+    // Pull fresh data from wherever the widget gets it from. This runs on the
+    // background refresh task, rendering must not wait for it.
+    // On failure the previously fetched data has to stay untouched.
+    async fn fetch(&self) -> Result<(), ProviderError>;
+
+    // How often fetch should run, None if there is nothing to fetch
+    fn refresh_interval(&self) -> Option<Duration>;
+
+    // When the current data was fetched, possibly by an earlier run
+    fn last_fetch(&self) -> Option<DateTime<Local>>;
+
+    // Whether there is anything to render, stale or not
+    fn has_data(&self) -> bool;
+
+    // When the output changes next on its own, without new data (a clock
+    // ticking over, an event starting). Fetches are accounted for elsewhere.
+    fn next_change(&self, _now: DateTime<Local>) -> Option<DateTime<Local>> {
+        None
+    }
+
+    // Draw the most recently fetched data into the area
+    fn render(&self, area: &mut Area, fonts: &mut FontCollection);
+}
+
+#[derive(Default)]
+struct Status {
+    last_success: Option<DateTime<Local>>,
+    // Set while the most recent fetch failed
+    last_error: Option<ProviderError>,
+}
+
+struct Entry {
+    widget: Box<dyn Widget>,
+    status: Mutex<Status>,
+}
+
+impl Entry {
+    // Returns whether the fetch went through
+    async fn refresh(&self, name: &str) -> bool {
+        let result = self.widget.fetch().await;
+        let mut status = self.status.lock().unwrap();
+        match result {
+            Ok(()) => {
+                debug!("Refreshed {}", name);
+                status.last_success = Some(Local::now());
+                status.last_error = None;
+                true
+            }
+            Err(e) => {
+                warn!("Could not fetch {}: {}", name, e);
+                status.last_error = Some(e);
+                false
+            }
+        }
+    }
+}
+
+// Built once at startup, afterwards widgets only change through their own
+// interior state, so the registry can be shared between the refresh tasks and
+// the renderer
+pub struct WidgetRegistry {
+    widgets: HashMap<String, Arc<Entry>>,
+}
+
+impl WidgetRegistry {
+    pub fn new() -> Self {
+        WidgetRegistry {
+            widgets: HashMap::new(),
+        }
+    }
+
+    // Everything we ship with; new widgets go here
+    pub async fn with_defaults(config: &Config) -> Self {
+        let mut registry = Self::new();
+        registry.register(
+            "calendar",
+            Box::new(calendar::CalendarWidget::new(config.clone()).await),
+        );
+        registry.register("clock", Box::new(clock::ClockWidget::new()));
+        registry.register("image", Box::new(image::ImageWidget::new(config.clone())));
+        registry.register(
+            "quote",
+            Box::new(quote::QuoteWidget::new(config.quote.clone(), &config.cache)),
+        );
+        registry.register(
+            "weather",
+            Box::new(weather::WeatherWidget::new(config.clone())),
+        );
+        registry
+    }
+
+    pub fn register(&mut self, name: &str, widget: Box<dyn Widget>) {
+        let entry = Arc::new(Entry {
+            widget,
+            status: Mutex::default(),
+        });
+        if self.widgets.insert(name.to_string(), entry).is_some() {
+            warn!("Widget {} registered twice, replacing it", name);
+        }
+    }
+
+    // Spawns one task per widget that fetches as soon as its data is due and
+    // then keeps refreshing on the widget's interval. Must be called from within the runtime.
+    pub fn spawn_refresh(&self) {
+        for (name, entry) in self.widgets.iter() {
+            let Some(interval) = entry.widget.refresh_interval() else {
+                continue;
+            };
+            let (name, entry) = (name.clone(), entry.clone());
+            tokio::spawn(async move {
+                // Data restored from disk is only refreshed once it is due
+                if let Some(fetched_at) = entry.widget.last_fetch() {
+                    entry.status.lock().unwrap().last_success = Some(fetched_at);
+                    let age = (Local::now() - fetched_at).to_std().unwrap_or_default();
+                    if age < interval {
+                        tokio::time::sleep(interval - age).await;
+                    }
+                }
+                loop {
+                    let wait = if entry.refresh(&name).await {
+                        interval
+                    } else {
+                        interval.min(RETRY_INTERVAL)
+                    };
+                    tokio::time::sleep(wait).await;
+                }
+            });
+        }
+    }
+
+    // Earliest point at which any of the named widgets may look different,
+    // either by itself or because its next fetch is due
+    pub fn next_change<'a>(
+        &self,
+        names: impl IntoIterator<Item = &'a str>,
+        now: DateTime<Local>,
+    ) -> Option<DateTime<Local>> {
+        names
+            .into_iter()
+            .filter_map(|name| self.widgets.get(name))
+            .flat_map(|entry| {
+                let next_fetch = entry.widget.refresh_interval().map(|interval| {
+                    let status = entry.status.lock().unwrap();
+                    match (status.last_success, &status.last_error) {
+                        (Some(last), None) => last + interval,
+                        _ => now + interval.min(RETRY_INTERVAL),
+                    }
+                });
+                [entry.widget.next_change(now), next_fetch]
+            })
+            .flatten()
+            .min()
+    }
+
+    pub fn render(&self, name: &str, area: &mut Area, fonts: &mut FontCollection) {
+        let Some(entry) = self.widgets.get(name) else {
+            warn!("No widget named {}", name);
+            return;
+        };
+        let (failing, auth_required, last_success) = {
+            let status = entry.status.lock().unwrap();
+            (
+                status.last_error.is_some(),
+                matches!(status.last_error, Some(ProviderError::AuthRequired)),
+                status.last_success,
+            )
+        };
+
+        if !entry.widget.has_data() {
+            if auth_required {
+                draw_placeholder(area, fonts, &format!("{name} auth required"));
+            } else if failing {
+                draw_placeholder(area, fonts, &format!("{name} unavailable"));
+            }
+            return;
+        }
+
+        entry.widget.render(area, fonts);
+        if auth_required {
+            draw_marker(area, fonts, "auth required");
+        } else if failing && let Some(since) = last_success {
+            draw_marker(
+                area,
+                fonts,
+                &format!("stale since {}", since.format("%H:%M")),
+            );
+        }
+    }
+}
+
+fn draw_placeholder(area: &mut Area, fonts: &mut FontCollection, message: &str) {
+    area.auto_layout_text_size(
+        &fonts.load_font(Font::Dina),
+        LayoutSettings {
+            x: area.get_hstart() as f32,
+            y: area.get_vstart() as f32,
+            max_width: Some(area.get_available_hspace() as f32),
+            max_height: Some(area.get_available_vspace() as f32),
+            horizontal_align: HorizontalAlign::Center,
+            vertical_align: VerticalAlign::Middle,
+            ..LayoutSettings::default()
+        },
+        &[TextStyle::new(message, 1.0, 0)],
+        40,
+        20.0,
+    );
+}
+
+// Small boxed note like "stale since HH:MM" in the bottom right corner of the area
+fn draw_marker(area: &mut Area, fonts: &mut FontCollection, note: &str) {
+    const MARKER_WIDTH: usize = 110;
+    const MARKER_HEIGHT: usize = 18;
+
+    let width = MARKER_WIDTH.min(area.get_available_hspace());
+    let height = MARKER_HEIGHT.min(area.get_available_vspace());
+    if width < MARKER_WIDTH / 2 || height < MARKER_HEIGHT / 2 {
+        return;
+    }
+
+    let mut marker = Area::new(
+        area.get_available_hspace() - width,
+        area.get_available_vspace() - height,
+        width,
+        height,
+        Color::White,
+        Padding::full(1),
+        Outline {
+            top: 1,
+            bottom: 1,
+            left: 1,
+            right: 1,
+            color: Color::Black,
+        },
+    );
+    marker.auto_layout_text_size(
+        &fonts.load_font(Font::Dina),
+        LayoutSettings {
+            x: marker.get_hstart() as f32,
+            y: marker.get_vstart() as f32,
+            max_width: Some(marker.get_available_hspace() as f32),
+            max_height: Some(marker.get_available_vspace() as f32),
+            horizontal_align: HorizontalAlign::Center,
+            vertical_align: VerticalAlign::Middle,
+            ..LayoutSettings::default()
+        },
+        &[TextStyle::new(note, 1.0, 0)],
+        40,
+        14.0,
+    );
+    area.add_sub_area(marker);
+}
@@ -0,0 +1,70 @@
+use crate::provider::ProviderError;
+use crate::provider::cache::{CacheEntry, DiskCache};
+use crate::provider::quote::{Quote, QuoteProvider};
+use crate::render::epd::Area;
+use crate::render::fonts::{Font, FontCollection};
+use crate::settings::{CacheConfig, QuoteConfig};
+use crate::widget::Widget;
+use async_trait::async_trait;
+use chrono::{DateTime, Local};
+use fontdue::layout::{LayoutSettings, TextStyle};
+use std::sync::Mutex;
+use std::time::Duration;
+
+pub struct QuoteWidget {
+    provider: Mutex<QuoteProvider>,
+    quote: DiskCache<Quote>,
+    interval: Duration,
+}
+
+impl QuoteWidget {
+    pub fn new(quote_config: QuoteConfig, cache_config: &CacheConfig) -> Self {
+        QuoteWidget {
+            provider: Mutex::new(QuoteProvider::new(quote_config, &cache_config.dir)),
+            quote: DiskCache::open(&cache_config.dir, "quote"),
+            interval: CacheConfig::interval(cache_config.quote),
+        }
+    }
+}
+
+#[async_trait]
+impl Widget for QuoteWidget {
+    async fn fetch(&self) -> Result<(), ProviderError> {
+        let quote = self.provider.lock().unwrap().get_quote()?;
+        self.quote.store(quote);
+        Ok(())
+    }
+
+    fn refresh_interval(&self) -> Option<Duration> {
+        Some(self.interval)
+    }
+
+    fn last_fetch(&self) -> Option<DateTime<Local>> {
+        self.quote.fetched_at()
+    }
+
+    fn has_data(&self) -> bool {
+        self.quote.get().is_some()
+    }
+
+    fn render(&self, quote_area: &mut Area, fonts: &mut FontCollection) {
+        let cached = self.quote.get();
+        let Some(CacheEntry { value: quote, .. }) = cached.as_ref() else {
+            return;
+        };
+
+        quote_area.auto_layout_text_size(
+            &fonts.load_font(Font::Wellfleet),
+            LayoutSettings {
+                x: quote_area.get_hstart() as f32,
+                y: quote_area.get_vstart() as f32,
+                max_height: Some(quote_area.get_available_vspace() as f32),
+                max_width: Some(quote_area.get_available_hspace() as f32),
+                ..LayoutSettings::default()
+            },
+            &[TextStyle::new(&quote.content, 1f32, 0)],
+            100,
+            30f32,
+        );
+    }
+}
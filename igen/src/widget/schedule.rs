@@ -0,0 +1,128 @@
+// How long a device may sleep before polling again.
+//
+// The widgets know when their output changes next (see Widget::next_change
+// and WidgetRegistry::next_change), the config bounds that and may declare
+// quiet hours in which nobody looks at the panel anyway.
+
+use crate::settings::SleepConfig;
+use chrono::{DateTime, NaiveTime, TimeDelta, TimeZone};
+use std::time::Duration;
+
+impl SleepConfig {
+    fn is_quiet(&self, time: NaiveTime) -> bool {
+        let (Some(start), Some(end)) = (self.quiet_start, self.quiet_end) else {
+            return false;
+        };
+        if start <= end {
+            start <= time && time < end
+        } else {
+            // Across midnight
+            time >= start || time < end
+        }
+    }
+
+    // First end of the quiet hours after at, if at is within them
+    fn quiet_end<Tz: TimeZone>(&self, at: &DateTime<Tz>) -> Option<DateTime<Tz>> {
+        let end = self.quiet_end?;
+        if !self.is_quiet(at.time()) {
+            return None;
+        }
+        let mut date = at.date_naive();
+        if at.time() >= end {
+            date = date.succ_opt()?;
+        }
+        date.and_time(end)
+            .and_local_timezone(at.timezone())
+            .earliest()
+    }
+}
+
+// next_change is when the rendered dashboard changes next, None if nothing is scheduled
+pub fn sleep_hint<Tz: TimeZone>(
+    now: &DateTime<Tz>,
+    next_change: Option<DateTime<Tz>>,
+    config: &SleepConfig,
+) -> Duration {
+    let earliest = now.clone() + TimeDelta::seconds(config.min as i64);
+    let latest = now.clone() + TimeDelta::seconds(config.max.max(config.min) as i64);
+    let wake = next_change
+        .unwrap_or_else(|| latest.clone())
+        .clamp(earliest, latest);
+    // Quiet hours win over max, that's the point of them
+    let wake = config.quiet_end(&wake).unwrap_or(wake);
+    // Devices sleep whole seconds, waking a bit late beats waking before the change
+    let hint = (wake - now.clone()).to_std().unwrap_or_default();
+    Duration::from_secs(hint.as_secs() + u64::from(hint.subsec_nanos() > 0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    fn at(hour: u32, minute: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2025, 3, 14, hour, minute, 0).unwrap()
+    }
+
+    fn config(quiet: Option<(&str, &str)>) -> SleepConfig {
+        SleepConfig {
+            min: 30,
+            max: 3600,
+            quiet_start: quiet.map(|(start, _)| start.parse().unwrap()),
+            quiet_end: quiet.map(|(_, end)| end.parse().unwrap()),
+        }
+    }
+
+    #[test]
+    fn follows_next_change_within_bounds() {
+        let c = config(None);
+        let now = at(12, 0);
+        let hint = |next| sleep_hint(&now, next, &c).as_secs();
+        assert_eq!(hint(Some(at(12, 5))), 300);
+        assert_eq!(hint(Some(at(11, 0))), 30);
+        assert_eq!(hint(Some(at(18, 0))), 3600);
+        assert_eq!(hint(None), 3600);
+    }
+
+    #[test]
+    fn sleeps_through_quiet_hours() {
+        let c = config(Some(("23:00", "06:30")));
+        // Next change falls into the quiet hours
+        assert_eq!(
+            sleep_hint(&at(22, 50), Some(at(23, 5)), &c).as_secs(),
+            (7 * 60 + 40) * 60
+        );
+        // Already quiet, after midnight
+        assert_eq!(
+            sleep_hint(&at(2, 0), Some(at(2, 1)), &c).as_secs(),
+            4 * 3600 + 1800
+        );
+        // Not quiet at all
+        assert_eq!(sleep_hint(&at(12, 0), Some(at(12, 1)), &c).as_secs(), 60);
+    }
+
+    #[test]
+    fn quiet_hours_within_a_day() {
+        let c = config(Some(("13:00", "14:00")));
+        assert_eq!(
+            sleep_hint(&at(12, 59), Some(at(13, 1)), &c).as_secs(),
+            61 * 60
+        );
+        assert_eq!(sleep_hint(&at(14, 0), Some(at(14, 1)), &c).as_secs(), 60);
+    }
+
+    #[test]
+    fn rounds_up_to_whole_seconds() {
+        let c = config(None);
+        let now = at(12, 0) + TimeDelta::milliseconds(300);
+        assert_eq!(
+            sleep_hint(&now, Some(at(12, 5)), &c),
+            Duration::from_secs(300)
+        );
+        let now = at(12, 0) + TimeDelta::milliseconds(999);
+        assert_eq!(
+            sleep_hint(&now, Some(at(12, 0) + TimeDelta::seconds(31)), &c),
+            Duration::from_secs(31)
+        );
+    }
+}
@@ -0,0 +1,245 @@
+use crate::provider::ProviderError;
+use crate::provider::cache::{CacheEntry, DiskCache};
+use crate::provider::weather::{NiceDaily, NiceWeatherData, WeatherProvider};
+use crate::render::epd::{Area, Outline, Padding};
+use crate::render::fonts::{Font, FontCollection};
+use crate::render::graphics::Color;
+use crate::settings::{CacheConfig, Config};
+use crate::widget::Widget;
+use async_trait::async_trait;
+use chrono::{DateTime, Local, TimeDelta};
+use fontdue::layout::{HorizontalAlign, LayoutSettings, TextStyle, VerticalAlign};
+use std::ops::Add;
+use std::time::Duration;
+
+pub struct WeatherWidget {
+    provider: WeatherProvider,
+    weather: DiskCache<NiceWeatherData>,
+    interval: Duration,
+}
+
+impl WeatherWidget {
+    pub fn new(config: Config) -> Self {
+        WeatherWidget {
+            weather: DiskCache::open(&config.cache.dir, "weather"),
+            interval: CacheConfig::interval(config.cache.weather),
+            provider: WeatherProvider::new(config),
+        }
+    }
+}
+
+#[async_trait]
+impl Widget for WeatherWidget {
+    async fn fetch(&self) -> Result<(), ProviderError> {
+        let weather = self.provider.check_sky().await?;
+        self.weather.store(weather);
+        Ok(())
+    }
+
+    fn refresh_interval(&self) -> Option<Duration> {
+        Some(self.interval)
+    }
+
+    fn last_fetch(&self) -> Option<DateTime<Local>> {
+        self.weather.fetched_at()
+    }
+
+    fn has_data(&self) -> bool {
+        self.weather.get().is_some()
+    }
+
+    fn render(&self, weather_area: &mut Area, fonts: &mut FontCollection) {
+        let cached = self.weather.get();
+        let Some(CacheEntry { value: weather, .. }) = cached.as_ref() else {
+            return;
+        };
+
+        let day_font = fonts.load_font(Font::Wellfleet);
+        let weather_font = fonts.load_font(Font::Dina);
+        let mut y_off = 0;
+        const DAY_NAME_STEP_SIZE: usize = 28;
+        const DAY_HEIGHT: usize = 50;
+        // Days are only drawn whole, whatever doesn't fit below is dropped
+        let vspace = weather_area.get_available_vspace();
+        let fits = |y_off: usize| y_off + DAY_NAME_STEP_SIZE + DAY_HEIGHT <= vspace;
+        if !fits(y_off) {
+            return;
+        }
+        weather_area.put_text(
+            &day_font,
+            LayoutSettings {
+                x: weather_area.get_hstart() as f32,
+                y: y_off as f32,
+                max_width: Some(weather_area.get_available_hspace() as f32),
+                max_height: Some(weather_area.get_available_vspace() as f32),
+                horizontal_align: HorizontalAlign::Center,
+                vertical_align: VerticalAlign::Top,
+                ..LayoutSettings::default()
+            },
+            &[TextStyle::new("Now", 23.0, 0)],
+            100,
+        );
+        y_off += DAY_NAME_STEP_SIZE;
+
+        let mut now_area = Area::new(
+            0,
+            y_off,
+            weather_area.get_available_hspace(),
+            DAY_HEIGHT,
+            Color::White,
+            Padding::full(0),
+            Outline {
+                left: 0,
+                right: 0,
+                color: Color::Black,
+                top: 1,
+                bottom: 1,
+            },
+        );
+        if weather.current.weather_code.is_severe() {
+            now_area.set_text_color(Color::Accent);
+        }
+        now_area.auto_layout_text_size(
+            &weather_font,
+            LayoutSettings {
+                x: now_area.get_hstart() as f32,
+                y: 0.0,
+                max_width: Some(now_area.get_available_hspace() as f32),
+                max_height: Some((now_area.get_available_vspace()) as f32),
+                horizontal_align: HorizontalAlign::Center,
+                ..LayoutSettings::default()
+            },
+            &[TextStyle::new(
+                weather.current.weather_code.description(),
+                24.0,
+                0,
+            )],
+            40,
+            24.0,
+        );
+        now_area.set_text_color(Color::Black);
+        now_area.put_text(
+            &weather_font,
+            LayoutSettings {
+                x: now_area.get_hstart() as f32,
+                y: 28.0,
+                max_width: Some(now_area.get_available_hspace() as f32),
+                max_height: Some((now_area.get_available_vspace()) as f32),
+                horizontal_align: HorizontalAlign::Center,
+                ..LayoutSettings::default()
+            },
+            &[TextStyle::new(
+                format!(
+                    "{}°C {}%",
+                    weather.current.temperature, weather.current.humidity
+                )
+                .as_str(),
+                20.0,
+                0,
+            )],
+            40,
+        );
+
+        y_off += now_area.space.height;
+        weather_area.add_sub_area(now_area);
+
+        // Stale data from yesterday might not reach that far
+        let tomorrow = weather
+            .days
+            .get(&chrono::Local::now().date_naive().add(TimeDelta::days(1)));
+        let day_after_tmrw = weather
+            .days
+            .get(&chrono::Local::now().date_naive().add(TimeDelta::days(2)));
+
+        let mut show_weather_for_day = |day: &NiceDaily, name: &str| {
+            if !fits(y_off) {
+                return;
+            }
+            weather_area.put_text(
+                &day_font,
+                LayoutSettings {
+                    x: weather_area.get_hstart() as f32,
+                    y: y_off as f32,
+                    max_width: Some(weather_area.get_available_hspace() as f32),
+                    max_height: Some(weather_area.get_available_vspace() as f32),
+                    horizontal_align: HorizontalAlign::Center,
+                    ..LayoutSettings::default()
+                },
+                &[TextStyle::new(name, 23.0, 0)],
+                100,
+            );
+
+            y_off += DAY_NAME_STEP_SIZE;
+
+            let mut day_area = Area::new(
+                0,
+                y_off,
+                weather_area.get_available_hspace(),
+                DAY_HEIGHT,
+                Color::White,
+                Padding::full(0),
+                Outline {
+                    left: 0,
+                    right: 0,
+                    color: Color::Black,
+                    top: 1,
+                    bottom: 1,
+                },
+            );
+
+            let wmo = day.weather_code.description();
+            let max_wmo_size = if wmo.len() >= 19 { 20.0 } else { 24.0 };
+
+            if day.weather_code.is_severe() {
+                day_area.set_text_color(Color::Accent);
+            }
+            day_area.auto_layout_text_size(
+                &weather_font,
+                LayoutSettings {
+                    x: day_area.get_hstart() as f32,
+                    y: 0.0,
+                    max_width: Some(day_area.get_available_hspace() as f32),
+                    max_height: Some(18f32),
+                    horizontal_align: HorizontalAlign::Center,
+                    ..LayoutSettings::default()
+                },
+                &[TextStyle::new(wmo, 0.0, 0)],
+                40,
+                max_wmo_size,
+            );
+            day_area.set_text_color(Color::Black);
+            day_area.put_text(
+                &weather_font,
+                LayoutSettings {
+                    x: day_area.get_hstart() as f32,
+                    y: 28.0,
+                    max_width: Some(day_area.get_available_hspace() as f32),
+                    max_height: Some((day_area.get_available_vspace()) as f32),
+                    horizontal_align: HorizontalAlign::Center,
+                    ..LayoutSettings::default()
+                },
+                &[TextStyle::new(
+                    format!(
+                        "{:05.2}°C-{:04.2}°C {:02}h",
+                        day.temp_min,
+                        day.temp_max,
+                        (day.sunshine / 3600f64).round() as usize
+                    )
+                    .as_str(),
+                    20.0,
+                    0,
+                )],
+                40,
+            );
+            y_off += day_area.space.height;
+            weather_area.add_sub_area(day_area);
+        };
+
+        if let Some(tomorrow) = tomorrow {
+            show_weather_for_day(tomorrow, "Tomorrow");
+        }
+        if let Some(day_after_tmrw) = day_after_tmrw {
+            show_weather_for_day(day_after_tmrw, "Tomorrow++");
+        }
+    }
+}
@@ -0,0 +1,589 @@
+// Framing for everything we send to the display.
+//
+// Every frame is a fixed 24 byte little-endian header followed by the payload:
+//
+//   0  magic "OWOV"
+//   4  protocol version (u8)
+//   5  command (u8)
+//   6  payload encoding (u8)
+//   7  reserved, always zero (u8)
+//   8  panel width (u16)
+//  10  panel height (u16)
+//  12  payload length as sent (u32)
+//  16  payload length after decoding (u32)
+//  20  crc32 (IEEE) of the payload as sent (u32)
+//
+// The whole payload is run through the encoding, so the rect of a partial
+// update is only readable after decoding.
+//
+// Payload per command:
+//   Full:    raw 1bpp frame
+//   Partial: x, y, width, height (u32 each), followed by the 1bpp bits of that rect
+//   MultiPartial: rect count (u32), followed by that many Partial payloads back to back.
+//                 The size of each rect's bits is (width + 7) / 8 * height, rows are
+//                 padded to whole bytes. Rects are 8 aligned except at the right edge.
+//   FullGray4: raw 2bpp frame (0b11 white, 0b10 light gray, 0b01 dark gray, 0b00 black),
+//              only sent to panels running in 4-gray mode
+//   FullTriColor: raw 1bpp black plane followed by the raw 1bpp red plane (0 is red),
+//                 both width / 8 * height
+//
+// Outside of the frame, the HTTP response carries a Frame-Id header. Devices
+// report it back via ?displayed= once the frame is on the panel (see main.rs).
+//
+// Keep in sync with esp/src/fetcher.cpp
+
+use crate::render::dash::RenderAction;
+use crate::render::graphics::Rect;
+use std::fmt::{Display, Formatter};
+
+pub mod packbits;
+
+pub const MAGIC: [u8; 4] = *b"OWOV";
+pub const PROTOCOL_VERSION: u8 = 2;
+pub const HEADER_LEN: usize = 24;
+
+const RECT_LEN: usize = 16;
+// Devices size their buffers for no more rects than this
+pub const MAX_RECTS: usize = 64;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[repr(u8)]
+pub enum Command {
+    Full = 0x00,
+    Partial = 0x01,
+    MultiPartial = 0x02,
+    FullGray4 = 0x03,
+    FullTriColor = 0x04,
+}
+
+impl TryFrom<u8> for Command {
+    type Error = WireError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x00 => Ok(Command::Full),
+            0x01 => Ok(Command::Partial),
+            0x02 => Ok(Command::MultiPartial),
+            0x03 => Ok(Command::FullGray4),
+            0x04 => Ok(Command::FullTriColor),
+            other => Err(WireError::UnknownCommand(other)),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+#[repr(u8)]
+pub enum Encoding {
+    #[default]
+    Raw = 0x00,
+    PackBits = 0x01,
+}
+
+impl TryFrom<u8> for Encoding {
+    type Error = WireError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x00 => Ok(Encoding::Raw),
+            0x01 => Ok(Encoding::PackBits),
+            other => Err(WireError::UnknownEncoding(other)),
+        }
+    }
+}
+
+impl Encoding {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Encoding::Raw => "raw",
+            Encoding::PackBits => "packbits",
+        }
+    }
+
+    // Picks the first encoding we support from a comma separated list like
+    // "packbits, raw" (q-values are ignored); falls back to raw
+    pub fn negotiate(accepted: &str) -> Encoding {
+        accepted
+            .split(',')
+            .filter_map(|e| e.split(';').next())
+            .map(|e| e.trim())
+            .find_map(|e| {
+                [Encoding::PackBits, Encoding::Raw]
+                    .into_iter()
+                    .find(|enc| enc.name().eq_ignore_ascii_case(e))
+            })
+            .unwrap_or_default()
+    }
+
+    fn encode(&self, payload: Vec<u8>) -> Vec<u8> {
+        match self {
+            Encoding::Raw => payload,
+            Encoding::PackBits => packbits::compress(&payload),
+        }
+    }
+
+    fn decode(&self, payload: &[u8], raw_len: usize) -> Result<Vec<u8>, WireError> {
+        match self {
+            Encoding::Raw => Ok(payload.to_vec()),
+            Encoding::PackBits => {
+                packbits::decompress(payload, raw_len).ok_or(WireError::MalformedPayload)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum WireError {
+    Truncated { expected: usize, actual: usize },
+    BadMagic([u8; 4]),
+    UnsupportedVersion(u8),
+    UnknownCommand(u8),
+    UnknownEncoding(u8),
+    MalformedPayload,
+    ChecksumMismatch { expected: u32, actual: u32 },
+}
+
+impl Display for WireError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WireError::Truncated { expected, actual } => {
+                write!(
+                    f,
+                    "frame truncated: expected {expected} bytes, got {actual}"
+                )
+            }
+            WireError::BadMagic(magic) => write!(f, "bad magic: {magic:02x?}"),
+            WireError::UnsupportedVersion(v) => write!(f, "unsupported protocol version {v}"),
+            WireError::UnknownCommand(c) => write!(f, "unknown command {c:#04x}"),
+            WireError::UnknownEncoding(e) => write!(f, "unknown encoding {e:#04x}"),
+            WireError::MalformedPayload => write!(f, "payload could not be decoded"),
+            WireError::ChecksumMismatch { expected, actual } => {
+                write!(
+                    f,
+                    "crc mismatch: expected {expected:#010x}, got {actual:#010x}"
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for WireError {}
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct Frame {
+    pub width: usize,
+    pub height: usize,
+    pub encoding: Encoding,
+    pub action: RenderAction,
+}
+
+fn put_rect(buf: &mut Vec<u8>, rect: &Rect) {
+    for v in [rect.x, rect.y, rect.width, rect.height] {
+        buf.extend_from_slice(&(v as u32).to_le_bytes());
+    }
+}
+
+fn read_u16(buf: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([buf[at], buf[at + 1]])
+}
+
+fn read_u32(buf: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([buf[at], buf[at + 1], buf[at + 2], buf[at + 3]])
+}
+
+fn read_rect(buf: &[u8]) -> Rect {
+    Rect {
+        x: read_u32(buf, 0) as usize,
+        y: read_u32(buf, 4) as usize,
+        width: read_u32(buf, 8) as usize,
+        height: read_u32(buf, 12) as usize,
+    }
+}
+
+pub fn encode(action: &RenderAction, width: usize, height: usize, encoding: Encoding) -> Vec<u8> {
+    let (command, payload) = match action {
+        RenderAction::Full(data) => (Command::Full, data.clone()),
+        RenderAction::FullGray4(data) => (Command::FullGray4, data.clone()),
+        RenderAction::FullTriColor { black, red } => {
+            (Command::FullTriColor, [&black[..], red].concat())
+        }
+        RenderAction::Partial(rect, data) => {
+            let mut payload = Vec::with_capacity(RECT_LEN + data.len());
+            put_rect(&mut payload, rect);
+            payload.extend_from_slice(data);
+            (Command::Partial, payload)
+        }
+        RenderAction::MultiPartial(parts) => {
+            let mut payload = (parts.len() as u32).to_le_bytes().to_vec();
+            for (rect, data) in parts {
+                put_rect(&mut payload, rect);
+                payload.extend_from_slice(data);
+            }
+            (Command::MultiPartial, payload)
+        }
+    };
+
+    let raw_len = payload.len();
+    let payload = encoding.encode(payload);
+
+    let mut frame = Vec::with_capacity(HEADER_LEN + payload.len());
+    frame.extend_from_slice(&MAGIC);
+    frame.push(PROTOCOL_VERSION);
+    frame.push(command as u8);
+    frame.push(encoding as u8);
+    frame.push(0);
+    frame.extend_from_slice(&(width as u16).to_le_bytes());
+    frame.extend_from_slice(&(height as u16).to_le_bytes());
+    frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    frame.extend_from_slice(&(raw_len as u32).to_le_bytes());
+    frame.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+    frame.extend(payload);
+    frame
+}
+
+pub fn decode(buf: &[u8]) -> Result<Frame, WireError> {
+    if buf.len() < HEADER_LEN {
+        return Err(WireError::Truncated {
+            expected: HEADER_LEN,
+            actual: buf.len(),
+        });
+    }
+
+    let magic = [buf[0], buf[1], buf[2], buf[3]];
+    if magic != MAGIC {
+        return Err(WireError::BadMagic(magic));
+    }
+    if buf[4] != PROTOCOL_VERSION {
+        return Err(WireError::UnsupportedVersion(buf[4]));
+    }
+    let command = Command::try_from(buf[5])?;
+    let encoding = Encoding::try_from(buf[6])?;
+    let width = read_u16(buf, 8) as usize;
+    let height = read_u16(buf, 10) as usize;
+    let payload_len = read_u32(buf, 12) as usize;
+    let raw_len = read_u32(buf, 16) as usize;
+    let crc = read_u32(buf, 20);
+
+    let payload = &buf[HEADER_LEN..];
+    if payload.len() != payload_len {
+        return Err(WireError::Truncated {
+            expected: HEADER_LEN + payload_len,
+            actual: buf.len(),
+        });
+    }
+    let actual_crc = crc32fast::hash(payload);
+    if actual_crc != crc {
+        return Err(WireError::ChecksumMismatch {
+            expected: crc,
+            actual: actual_crc,
+        });
+    }
+    let payload = encoding.decode(payload, raw_len)?;
+
+    let action = match command {
+        Command::Full => RenderAction::Full(payload),
+        Command::FullGray4 => RenderAction::FullGray4(payload),
+        Command::FullTriColor => {
+            if payload.len() % 2 != 0 {
+                return Err(WireError::MalformedPayload);
+            }
+            let (black, red) = payload.split_at(payload.len() / 2);
+            RenderAction::FullTriColor {
+                black: black.to_vec(),
+                red: red.to_vec(),
+            }
+        }
+        Command::Partial => {
+            if payload.len() < RECT_LEN {
+                return Err(WireError::MalformedPayload);
+            }
+            RenderAction::Partial(read_rect(&payload), payload[RECT_LEN..].to_vec())
+        }
+        Command::MultiPartial => {
+            let count = payload
+                .get(0..4)
+                .map(|_| read_u32(&payload, 0))
+                .ok_or(WireError::MalformedPayload)?;
+            let mut parts = vec![];
+            let mut at = 4;
+            for _ in 0..count {
+                let rect = payload
+                    .get(at..at + RECT_LEN)
+                    .map(read_rect)
+                    .ok_or(WireError::MalformedPayload)?;
+                at += RECT_LEN;
+                let len = rect.width.div_ceil(8) * rect.height;
+                let data = payload
+                    .get(at..at + len)
+                    .ok_or(WireError::MalformedPayload)?;
+                at += len;
+                parts.push((rect, data.to_vec()));
+            }
+            if at != payload.len() {
+                return Err(WireError::MalformedPayload);
+            }
+            RenderAction::MultiPartial(parts)
+        }
+    };
+
+    Ok(Frame {
+        width,
+        height,
+        encoding,
+        action,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_bits(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 31 % 251) as u8).collect()
+    }
+
+    #[test]
+    fn full_round_trip() {
+        let action = RenderAction::Full(sample_bits(800 / 8 * 480));
+        let encoded = encode(&action, 800, 480, Encoding::Raw);
+        assert_eq!(&encoded[0..4], b"OWOV");
+        assert_eq!(encoded.len(), HEADER_LEN + 800 / 8 * 480);
+
+        let frame = decode(&encoded).unwrap();
+        assert_eq!(frame.width, 800);
+        assert_eq!(frame.height, 480);
+        assert_eq!(frame.action, action);
+    }
+
+    #[test]
+    fn gray4_round_trip() {
+        let action = RenderAction::FullGray4(sample_bits(800 / 4 * 480));
+        let encoded = encode(&action, 800, 480, Encoding::PackBits);
+        assert_eq!(encoded[5], Command::FullGray4 as u8);
+
+        let frame = decode(&encoded).unwrap();
+        assert_eq!(frame.action, action);
+    }
+
+    #[test]
+    fn tri_color_round_trip() {
+        let action = RenderAction::FullTriColor {
+            black: sample_bits(800 / 8 * 480),
+            red: vec![0xFF; 800 / 8 * 480],
+        };
+        let encoded = encode(&action, 800, 480, Encoding::Raw);
+        assert_eq!(encoded[5], Command::FullTriColor as u8);
+        assert_eq!(encoded.len(), HEADER_LEN + 2 * 800 / 8 * 480);
+
+        let frame = decode(&encoded).unwrap();
+        assert_eq!(frame.action, action);
+    }
+
+    #[test]
+    fn partial_round_trip() {
+        let rect = Rect {
+            x: 600,
+            y: 8,
+            width: 96,
+            height: 40,
+        };
+        let action = RenderAction::Partial(rect, sample_bits(96 / 8 * 40));
+        let encoded = encode(&action, 800, 480, Encoding::Raw);
+        assert_eq!(encoded[5], Command::Partial as u8);
+
+        let frame = decode(&encoded).unwrap();
+        assert_eq!(frame.action, action);
+    }
+
+    fn dashboard_like_frame() -> Vec<u8> {
+        // a solid black column and mostly white space with some noise, roughly the dashboard
+        (0..480)
+            .flat_map(|y| {
+                (0..100).map(move |x| match x {
+                    0..25 => 0x00,
+                    _ if (x + y) % 13 == 0 => (x * y) as u8,
+                    _ => 0xFF,
+                })
+            })
+            .collect()
+    }
+
+    #[test]
+    fn packbits_round_trip() {
+        for data in [
+            vec![],
+            vec![0x42],
+            vec![0x00; 1000],
+            vec![1, 2, 2, 3, 3, 3, 4, 4, 4, 4, 5],
+            sample_bits(300),
+            dashboard_like_frame(),
+        ] {
+            let compressed = packbits::compress(&data);
+            assert_eq!(packbits::decompress(&compressed, data.len()), Some(data));
+        }
+    }
+
+    #[test]
+    fn packbits_frame_round_trip() {
+        let action = RenderAction::Partial(
+            Rect {
+                x: 0,
+                y: 0,
+                width: 800,
+                height: 480,
+            },
+            dashboard_like_frame(),
+        );
+        let encoded = encode(&action, 800, 480, Encoding::PackBits);
+        assert!(encoded.len() < HEADER_LEN + RECT_LEN + dashboard_like_frame().len() / 2);
+
+        let frame = decode(&encoded).unwrap();
+        assert_eq!(frame.encoding, Encoding::PackBits);
+        assert_eq!(frame.action, action);
+    }
+
+    #[test]
+    fn rejects_malformed_packbits() {
+        // literal run claims 4 bytes but only 2 follow
+        assert_eq!(packbits::decompress(&[0x03, 0x01, 0x02], 4), None);
+        // decodes to more than announced
+        assert_eq!(packbits::decompress(&[0xFD, 0x00], 2), None);
+    }
+
+    #[test]
+    fn negotiates_encoding() {
+        assert_eq!(Encoding::negotiate("packbits"), Encoding::PackBits);
+        assert_eq!(
+            Encoding::negotiate("gzip, PackBits;q=0.5"),
+            Encoding::PackBits
+        );
+        assert_eq!(Encoding::negotiate("gzip, br"), Encoding::Raw);
+        assert_eq!(Encoding::negotiate(""), Encoding::Raw);
+    }
+
+    #[test]
+    fn multi_partial_round_trip() {
+        let clock = Rect {
+            x: 696,
+            y: 0,
+            width: 104,
+            height: 40,
+        };
+        let calendar = Rect {
+            x: 0,
+            y: 64,
+            width: 200,
+            height: 24,
+        };
+        let action = RenderAction::MultiPartial(vec![
+            (clock, sample_bits(104 / 8 * 40)),
+            (calendar, sample_bits(200 / 8 * 24)),
+        ]);
+
+        for encoding in [Encoding::Raw, Encoding::PackBits] {
+            let encoded = encode(&action, 800, 480, encoding);
+            assert_eq!(encoded[5], Command::MultiPartial as u8);
+            assert_eq!(decode(&encoded).unwrap().action, action);
+        }
+    }
+
+    #[test]
+    fn multi_partial_round_trip_at_the_edge() {
+        use crate::render::epd::{EpdImage, PixelFormat};
+        use crate::render::graphics::{Pixel, PixelColor};
+
+        let (width, height) = (21, 16);
+        let mut current = EpdImage::new(width, height, PixelFormat::Mono);
+        current.set_pixel(20, 12, Pixel::solid(PixelColor::Black));
+        // As the diff clips it at the right edge, 5 wide but still one byte per row
+        let edge = Rect {
+            x: 16,
+            y: 8,
+            width: 5,
+            height: 8,
+        };
+        let inner = Rect {
+            x: 0,
+            y: 0,
+            width: 8,
+            height: 8,
+        };
+        let action = RenderAction::MultiPartial(
+            [edge, inner]
+                .into_iter()
+                .map(|r| (r, current.to_partial_from_rect(r)))
+                .collect(),
+        );
+        assert_eq!(current.to_partial_from_rect(edge).len(), 8);
+
+        for encoding in [Encoding::Raw, Encoding::PackBits] {
+            let encoded = encode(&action, width, height, encoding);
+            assert_eq!(decode(&encoded).unwrap().action, action);
+        }
+    }
+
+    #[test]
+    fn rejects_short_multi_partial() {
+        let action = RenderAction::MultiPartial(vec![(
+            Rect {
+                x: 0,
+                y: 0,
+                width: 16,
+                height: 2,
+            },
+            sample_bits(3),
+        )]);
+        assert_eq!(
+            decode(&encode(&action, 800, 480, Encoding::Raw)),
+            Err(WireError::MalformedPayload)
+        );
+    }
+
+    #[test]
+    fn rejects_truncated_frame() {
+        let encoded = encode(
+            &RenderAction::Full(sample_bits(64)),
+            800,
+            480,
+            Encoding::Raw,
+        );
+        assert_eq!(
+            decode(&encoded[..encoded.len() - 1]),
+            Err(WireError::Truncated {
+                expected: encoded.len(),
+                actual: encoded.len() - 1
+            })
+        );
+        assert!(matches!(
+            decode(&encoded[..10]),
+            Err(WireError::Truncated { .. })
+        ));
+    }
+
+    #[test]
+    fn rejects_corrupted_payload() {
+        let mut encoded = encode(
+            &RenderAction::Full(sample_bits(64)),
+            800,
+            480,
+            Encoding::Raw,
+        );
+        encoded[HEADER_LEN + 3] ^= 0x10;
+        assert!(matches!(
+            decode(&encoded),
+            Err(WireError::ChecksumMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn rejects_foreign_header() {
+        let mut encoded = encode(&RenderAction::Full(sample_bits(8)), 800, 480, Encoding::Raw);
+        encoded[4] = PROTOCOL_VERSION + 1;
+        assert_eq!(
+            decode(&encoded),
+            Err(WireError::UnsupportedVersion(PROTOCOL_VERSION + 1))
+        );
+
+        encoded[0] = b'X';
+        assert!(matches!(decode(&encoded), Err(WireError::BadMagic(_))));
+    }
+}
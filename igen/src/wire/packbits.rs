@@ -0,0 +1,67 @@
+// PackBits as in Apple TN1023: a signed header byte n is followed by either
+// n + 1 literal bytes (0..=127) or a single byte repeated 1 - n times (-127..=-1).
+// -128 is a no-op and never emitted.
+
+const MAX_RUN: usize = 128;
+
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() / 4);
+    let mut literal_start = 0;
+    let mut i = 0;
+
+    let flush_literals = |out: &mut Vec<u8>, literals: &[u8]| {
+        for chunk in literals.chunks(MAX_RUN) {
+            out.push((chunk.len() - 1) as u8);
+            out.extend_from_slice(chunk);
+        }
+    };
+
+    while i < data.len() {
+        let mut run = 1;
+        while i + run < data.len() && data[i + run] == data[i] && run < MAX_RUN {
+            run += 1;
+        }
+
+        // A run of two in the middle of literals doesn't pay off
+        if run >= 3 || (run == 2 && literal_start == i) {
+            flush_literals(&mut out, &data[literal_start..i]);
+            out.push((1 - run as i16) as u8);
+            out.push(data[i]);
+            i += run;
+            literal_start = i;
+        } else {
+            i += run;
+        }
+    }
+    flush_literals(&mut out, &data[literal_start..]);
+
+    out
+}
+
+pub fn decompress(data: &[u8], expected_len: usize) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(expected_len);
+    let mut i = 0;
+
+    while i < data.len() {
+        let n = data[i] as i8;
+        i += 1;
+        match n {
+            0..=127 => {
+                let len = n as usize + 1;
+                out.extend_from_slice(data.get(i..i + len)?);
+                i += len;
+            }
+            -127..=-1 => {
+                let byte = *data.get(i)?;
+                out.extend(std::iter::repeat_n(byte, 1 + n.unsigned_abs() as usize));
+                i += 1;
+            }
+            -128 => {}
+        }
+        if out.len() > expected_len {
+            return None;
+        }
+    }
+
+    (out.len() == expected_len).then_some(out)
+}